@@ -18,10 +18,33 @@ pub struct User {
     #[sqlx(default)]
     #[serde(skip)]
     pub password_hash: Option<String>,
+    // set once the email was confirmed
+    #[sqlx(default)]
+    #[serde(default)]
+    pub verified_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+/// What users share about themselves with their workspace
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct UserProfile {
+    pub id: i64,
+    pub ws_id: i64,
+    pub fullname: String,
+    pub email: String,
+    // shown instead of the fullname if set
+    pub display_name: Option<String>,
+    pub title: Option<String>,
+    // IANA time zone, e.g. `Asia/Shanghai`
+    pub timezone: Option<String>,
+    // BCP 47 language tag, e.g. `zh-CN`
+    pub locale: Option<String>,
+    // url of an uploaded image
+    pub avatar_url: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
 pub struct Workspace {
     pub id: i64,
@@ -62,6 +85,10 @@ pub struct Message {
     pub sender_id: i64,
     pub content: Option<String>,
     pub files: Option<Vec<String>>,
+    // delivered to members in do not disturb too
+    #[sqlx(default)]
+    #[serde(default)]
+    pub urgent: bool,
     pub created_at: DateTime<Utc>,
 }
 
@@ -73,8 +100,13 @@ impl User {
             fullname,
             email,
             password_hash: None,
+            verified_at: None,
             created_at: Utc::now(),
             updated_at: Utc::now(),
         }
     }
+
+    pub fn is_verified(&self) -> bool {
+        self.verified_at.is_some()
+    }
 }
@@ -44,18 +44,26 @@ where
                 }
             }
         };
-    dbg!(format!("auth token={:?}", &token));
 
-    let Ok(user) = state.verify(&token) else {
-        let msg = format!("token verification failed: {}", token);
-        warn!("{}", msg);
-        return (StatusCode::FORBIDDEN, msg).into_response();
+    let user = match state.verify(&token).await {
+        Ok(claims) => state.user(&claims).await.map(|user| (user, claims)),
+        Err(e) => Err(e),
+    };
+    let (user, claims) = match user {
+        Ok(ret) => ret,
+        Err(e) => {
+            let msg = format!("token verification failed: {:?}", e);
+            warn!("{}", msg);
+            return (StatusCode::FORBIDDEN, msg).into_response();
+        }
     };
-
-    dbg!(format!("auth user:{:?}", user.clone()));
 
     let mut req = Request::from_parts(parts, body);
     req.extensions_mut().insert(user);
+    if let Some(session_id) = claims.session_id {
+        req.extensions_mut().insert(session_id);
+    }
+    req.extensions_mut().insert(claims);
 
     next.run(req).await
 }
@@ -66,7 +74,7 @@ mod tests {
     use std::sync::Arc;
 
     use super::*;
-    use crate::{DecodingKey, EncodingKey, User};
+    use crate::{DecodingKey, EncodingKey, TokenClaims, User};
     use anyhow::Result;
     use axum::{
         Extension, Router, body::Body, http::HeaderValue, middleware::from_fn_with_state,
@@ -85,9 +93,18 @@ mod tests {
 
     impl TokenVerify for AppState {
         type Error = ();
-        fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        async fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error> {
             self.0.pk.verify(token).map_err(|_| ())
         }
+
+        async fn user(&self, claims: &TokenClaims) -> Result<User, Self::Error> {
+            Ok(User::new(
+                claims.user_id,
+                claims.ws_id,
+                "test".to_string(),
+                "test@test.com".to_string(),
+            ))
+        }
     }
 
     async fn handler(Extension(user): Extension<User>, _req: Request) -> impl IntoResponse {
@@ -143,8 +160,7 @@ mod tests {
             pk: DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?,
         }));
 
-        let user = User::new(1, 1, "test".to_string(), "test@test.com".to_string());
-        let token = state.0.sk.sign(user)?;
+        let token = state.0.sk.sign(&TokenClaims::new(1, 1, None), 60)?;
 
         let app = Router::new().route(
             "/api",
@@ -12,16 +12,20 @@ use tracing::Level;
 
 mod auth;
 mod request_id;
+mod scope;
 mod server_time;
 
 pub trait TokenVerify {
     type Error: fmt::Debug;
-    fn verify(&self, token: &str) -> Result<User, Self::Error>;
+    fn verify(&self, token: &str) -> impl Future<Output = Result<TokenClaims, Self::Error>> + Send;
+    /// Resolve the user a verified token was issued to
+    fn user(&self, claims: &TokenClaims) -> impl Future<Output = Result<User, Self::Error>> + Send;
 }
 
 pub use auth::verify_token;
+pub use scope::require_scope;
 
-use crate::User;
+use crate::{TokenClaims, User};
 const REQUEST_ID_HEADER: &str = "x-request-id";
 const SERVER_TIME_HEADER: &str = "x-server-time";
 
@@ -0,0 +1,55 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+
+use crate::{Scope, TokenClaims};
+
+/// Reject requests whose token wasn't granted `scope`, layer it inside
+/// `verify_token`:
+///
+/// `.route_layer(from_fn_with_state(Scope::ChatsWrite, require_scope))`
+pub async fn require_scope(State(scope): State<Scope>, req: Request, next: Next) -> Response {
+    let granted = req
+        .extensions()
+        .get::<TokenClaims>()
+        .is_some_and(|claims| claims.has_scope(scope));
+    if !granted {
+        let msg = format!("token lacks scope {}", scope);
+        warn!("{}", msg);
+        return (StatusCode::FORBIDDEN, msg).into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{Extension, Router, body::Body, middleware::from_fn_with_state, routing::get};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn require_scope_should_check_claims() -> Result<()> {
+        let app = |scopes: Vec<Scope>| {
+            let claims = TokenClaims {
+                scopes,
+                ..TokenClaims::new(1, 1, None)
+            };
+            Router::new()
+                .route("/api", get(|| async { "ok" }))
+                .route_layer(from_fn_with_state(Scope::ChatsWrite, require_scope))
+                .layer(Extension(claims))
+        };
+        let req = || Request::builder().uri("/api").body(Body::empty());
+
+        let res = app(vec![Scope::ChatsRead]).oneshot(req()?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = app(Scope::ALL.to_vec()).oneshot(req()?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+}
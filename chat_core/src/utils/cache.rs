@@ -0,0 +1,45 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// drop expired entries once the cache grows past this
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A small in-process cache whose entries expire after `ttl`
+#[derive(Debug, Clone)]
+pub(crate) struct TtlCache<K, V> {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<K, (V, Instant)>>>,
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().expect("cache poisoned");
+        match entries.get(key) {
+            Some((value, at)) if at.elapsed() < self.ttl => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.lock().expect("cache poisoned");
+        if entries.len() >= PRUNE_THRESHOLD {
+            entries.retain(|_, (_, at)| at.elapsed() < self.ttl);
+        }
+        entries.insert(key, (value, Instant::now()));
+    }
+
+    pub(crate) fn remove(&self, key: &K) {
+        self.entries.lock().expect("cache poisoned").remove(key);
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::anyhow;
+use jwt_simple::prelude::*;
+use tracing::warn;
+
+use crate::DecodingKey;
+
+/// A JSON Web Key Set, as served at `/.well-known/jwks.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+/// An Ed25519 public key in JWK form (RFC 8037)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(rename = "use", default, skip_serializing_if = "Option::is_none")]
+    pub key_use: Option<String>,
+    // base64url encoded public key
+    pub x: String,
+}
+
+impl DecodingKey {
+    pub fn jwks(&self) -> Jwks {
+        let keys = self
+            .0
+            .iter()
+            .map(|key| Jwk {
+                kty: "OKP".to_string(),
+                crv: "Ed25519".to_string(),
+                kid: key.key_id().clone(),
+                alg: Some("EdDSA".to_string()),
+                key_use: Some("sig".to_string()),
+                x: Base64UrlSafeNoPadding::encode_to_string(key.to_bytes())
+                    .expect("encode public key"),
+            })
+            .collect();
+        Jwks { keys }
+    }
+
+    /// Load the Ed25519 signing keys of a key set, other keys are ignored and
+    /// malformed ones skipped so one bad key doesn't stop the others
+    pub fn from_jwks(jwks: &Jwks) -> Result<Self, jwt_simple::Error> {
+        let keys: Vec<_> = jwks
+            .keys
+            .iter()
+            .filter(|k| k.kty == "OKP" && k.crv == "Ed25519")
+            .filter(|k| k.key_use.as_deref().is_none_or(|u| u == "sig"))
+            .filter_map(|k| match k.public_key() {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    warn!("skipping key {:?}: {}", k.kid, e);
+                    None
+                }
+            })
+            .collect();
+        if keys.is_empty() {
+            return Err(anyhow!("no usable key in key set"));
+        }
+        Ok(Self(keys))
+    }
+}
+
+impl Jwk {
+    fn public_key(&self) -> Result<Ed25519PublicKey, jwt_simple::Error> {
+        let raw = Base64UrlSafeNoPadding::decode_to_vec(&self.x, None)
+            .map_err(|_| anyhow!("invalid base64"))?;
+        let key = Ed25519PublicKey::from_bytes(&raw)?;
+        Ok(match &self.kid {
+            Some(kid) => key.with_key_id(kid),
+            None => key,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncodingKey, KeyConfig, TokenClaims};
+    use anyhow::Result;
+
+    #[test]
+    fn jwks_should_round_trip() -> Result<()> {
+        let keys = vec![KeyConfig {
+            kid: "k1".to_string(),
+            sk: Some(include_str!("../../fixtures/encoding.pem").to_string()),
+            pk: None,
+            active_from: None,
+        }];
+        let encoding_key = EncodingKey::from_config(&keys)?;
+        let jwks = encoding_key.decoding_key().jwks();
+        assert_eq!(jwks.keys.len(), 1);
+        assert_eq!(jwks.keys[0].kid.as_deref(), Some("k1"));
+
+        let json = serde_json::to_string(&jwks)?;
+        assert!(json.contains(r#""use":"sig""#));
+        let decoding_key = DecodingKey::from_jwks(&serde_json::from_str(&json)?)?;
+        assert!(decoding_key.has_key("k1"));
+        let token = encoding_key.sign(&TokenClaims::new(1, 1, None), 60)?;
+        assert_eq!(decoding_key.verify(&token)?.user_id, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn from_jwks_should_skip_bad_keys() -> Result<()> {
+        let keys = vec![KeyConfig {
+            kid: "k1".to_string(),
+            sk: Some(include_str!("../../fixtures/encoding.pem").to_string()),
+            pk: None,
+            active_from: None,
+        }];
+        let mut jwks = EncodingKey::from_config(&keys)?.decoding_key().jwks();
+        let good = jwks.keys[0].clone();
+        jwks.keys.insert(
+            0,
+            Jwk {
+                kid: Some("bad".to_string()),
+                x: "not base64!".to_string(),
+                ..good.clone()
+            },
+        );
+        jwks.keys.push(Jwk {
+            kid: Some("short".to_string()),
+            x: Base64UrlSafeNoPadding::encode_to_string([1u8; 8])?,
+            ..good
+        });
+
+        let decoding_key = DecodingKey::from_jwks(&jwks)?;
+        assert!(decoding_key.has_key("k1"));
+        assert!(!decoding_key.has_key("bad"));
+        assert!(!decoding_key.has_key("short"));
+
+        // a set without any usable key is an error
+        jwks.keys.remove(1);
+        assert!(DecodingKey::from_jwks(&jwks).is_err());
+        Ok(())
+    }
+}
@@ -1,51 +1,315 @@
-use jwt_simple::prelude::*;
+use std::{fmt, str::FromStr};
 
-use crate::User;
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use jwt_simple::prelude::*;
+use uuid::Uuid;
 
-const JWT_DURATION: u64 = 60 * 60 * 24 * 7; // 7 days
 const JWT_ISSUER: &str = "chat_server";
 const JWT_AUDIENCE: &str = "chat_web";
 
+/// Signing keys, each identified by the `kid` header of the tokens it signs.
+/// The newest key whose `active_from` has passed signs new tokens.
 #[derive(Clone)]
-pub struct EncodingKey(Ed25519KeyPair);
+pub struct EncodingKey(Vec<ScheduledKey>);
 
+/// Keys accepted when verifying a token
 #[derive(Clone)]
-pub struct DecodingKey(Ed25519PublicKey);
+pub struct DecodingKey(pub(crate) Vec<Ed25519PublicKey>);
+
+#[derive(Clone)]
+struct ScheduledKey {
+    active_from: Option<DateTime<Utc>>,
+    key: Ed25519KeyPair,
+}
+
+/// A key in the `auth.keys` config. To rotate, add a key with `active_from`
+/// in the future so verifiers learn it before it signs anything, then drop
+/// `sk` from the old key once it's no longer active and remove it after its
+/// last token expired.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyConfig {
+    pub kid: String,
+    // PEM private key, omit it for keys that only verify
+    #[serde(default)]
+    pub sk: Option<String>,
+    // PEM public key, derived from `sk` if omitted
+    #[serde(default)]
+    pub pk: Option<String>,
+    // when the key starts signing, keys without it are active from the start
+    #[serde(default)]
+    pub active_from: Option<DateTime<Utc>>,
+}
+
+/// Id of the sign in session a token was issued to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionId(pub Uuid);
+
+/// What a token is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "chats:read")]
+    ChatsRead,
+    #[serde(rename = "chats:write")]
+    ChatsWrite,
+    #[serde(rename = "files:read")]
+    FilesRead,
+    #[serde(rename = "files:write")]
+    FilesWrite,
+    // sessions, workspace settings and other account management
+    #[serde(rename = "account")]
+    Account,
+}
+
+/// The claims of a token. Only ids are carried, the user is looked up when
+/// the token is verified so profile changes apply right away.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenClaims {
+    pub user_id: i64,
+    pub ws_id: i64,
+    // tokens signed without a session can't be revoked
+    pub session_id: Option<SessionId>,
+    pub scopes: Vec<Scope>,
+    // chats a personal access token is limited to, `None` for every chat
+    pub chat_ids: Option<Vec<i64>>,
+}
+
+// `sub`, `exp` and the other registered claims are set by jwt-simple
+#[derive(Debug, Serialize, Deserialize)]
+struct CustomClaims {
+    ws: i64,
+    // the session is the `jti` claim, briefly it was signed as `sid`
+    #[serde(default, skip_serializing)]
+    sid: Option<SessionId>,
+    #[serde(default)]
+    scopes: Vec<Scope>,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::ChatsRead,
+        Scope::ChatsWrite,
+        Scope::FilesRead,
+        Scope::FilesWrite,
+        Scope::Account,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ChatsRead => "chats:read",
+            Scope::ChatsWrite => "chats:write",
+            Scope::FilesRead => "files:read",
+            Scope::FilesWrite => "files:write",
+            Scope::Account => "account",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| format!("unknown scope {}", s))
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl TokenClaims {
+    /// Claims of a full sign in, granting every scope
+    pub fn new(user_id: i64, ws_id: i64, session_id: Option<SessionId>) -> Self {
+        Self {
+            user_id,
+            ws_id,
+            session_id,
+            scopes: Scope::ALL.to_vec(),
+            chat_ids: None,
+        }
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    pub fn allows_chat(&self, chat_id: i64) -> bool {
+        self.chat_ids
+            .as_ref()
+            .is_none_or(|chat_ids| chat_ids.contains(&chat_id))
+    }
+}
 
 impl EncodingKey {
     // 从 PEM 格式的字符串加载密钥
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
         let key = Ed25519KeyPair::from_pem(pem)?; // pem 是 PEM 格式的私钥字符串
-        Ok(Self(key))
+        Ok(Self(vec![ScheduledKey {
+            active_from: None,
+            key,
+        }]))
+    }
+
+    /// Load the private keys of the config, keys without `sk` are skipped
+    pub fn from_config(keys: &[KeyConfig]) -> Result<Self, jwt_simple::Error> {
+        let mut scheduled = keys
+            .iter()
+            .filter_map(|k| {
+                let sk = k.sk.as_ref()?;
+                let key = Ed25519KeyPair::from_pem(sk).map(|key| ScheduledKey {
+                    active_from: k.active_from,
+                    key: key.with_key_id(&k.kid),
+                });
+                Some(key)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if scheduled.is_empty() {
+            return Err(anyhow!("no signing key configured"));
+        }
+        // newest first, keys without a schedule last
+        scheduled.sort_by_key(|k| std::cmp::Reverse(k.active_from));
+        Ok(Self(scheduled))
+    }
+
+    /// The key signing new tokens: the newest active one, or the one that
+    /// activates first if none is active yet
+    fn current(&self) -> &Ed25519KeyPair {
+        let now = Utc::now();
+        let key = self
+            .0
+            .iter()
+            .find(|k| k.active_from.is_none_or(|at| at <= now))
+            .or(self.0.last())
+            .expect("encoding key has at least one key");
+        &key.key
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.current().key_id().as_deref()
     }
 
-    pub fn sign(&self, user: impl Into<User>) -> Result<String, jwt_simple::Error> {
-        let claims = Claims::with_custom_claims(user.into(), Duration::from(JWT_DURATION))
+    /// The public keys of all signing keys, including scheduled ones
+    pub fn decoding_key(&self) -> DecodingKey {
+        DecodingKey(self.0.iter().map(|k| public_key(&k.key)).collect())
+    }
+
+    pub fn sign(&self, claims: &TokenClaims, ttl_secs: u64) -> Result<String, jwt_simple::Error> {
+        let custom = CustomClaims {
+            ws: claims.ws_id,
+            sid: None,
+            scopes: claims.scopes.clone(),
+        };
+        let mut jwt = Claims::with_custom_claims(custom, Duration::from_secs(ttl_secs))
             .with_issuer(JWT_ISSUER)
-            .with_audience(JWT_AUDIENCE);
-        let token = self.0.sign(claims)?;
+            .with_audience(JWT_AUDIENCE)
+            .with_subject(claims.user_id);
+        if let Some(session_id) = claims.session_id {
+            jwt = jwt.with_jwt_id(session_id.0.to_string());
+        }
+        let token = self.current().sign(jwt)?;
         Ok(token)
     }
 }
 
+fn public_key(key: &Ed25519KeyPair) -> Ed25519PublicKey {
+    let pk = key.public_key();
+    match key.key_id() {
+        Some(kid) => pk.with_key_id(kid),
+        None => pk,
+    }
+}
+
 impl DecodingKey {
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
         let key = Ed25519PublicKey::from_pem(pem)?;
-        Ok(Self(key))
+        Ok(Self(vec![key]))
+    }
+
+    /// Load every key of the config, retired keys without `sk` included
+    pub fn from_config(keys: &[KeyConfig]) -> Result<Self, jwt_simple::Error> {
+        let keys = keys
+            .iter()
+            .map(|k| {
+                let key = match (&k.pk, &k.sk) {
+                    (Some(pk), _) => Ed25519PublicKey::from_pem(pk)?,
+                    (None, Some(sk)) => Ed25519KeyPair::from_pem(sk)?.public_key(),
+                    (None, None) => return Err(anyhow!("key {} has neither sk nor pk", k.kid)),
+                };
+                Ok(key.with_key_id(&k.kid))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self(keys))
+    }
+
+    pub fn has_key(&self, kid: &str) -> bool {
+        self.0.iter().any(|k| k.key_id().as_deref() == Some(kid))
     }
 
     #[allow(unused)]
-    pub fn verify(&self, token: &str) -> Result<User, jwt_simple::Error> {
-        let mut opts = VerificationOptions {
-            allowed_audiences: Some(HashSet::from_strings(&[JWT_AUDIENCE])),
-            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISSUER])),
-            ..Default::default()
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, jwt_simple::Error> {
+        let claims = match token_key_id(token) {
+            Some(kid) => {
+                let key = self
+                    .0
+                    .iter()
+                    .find(|k| k.key_id().as_deref() == Some(kid.as_str()))
+                    .ok_or_else(|| anyhow!("unknown key id {}", kid))?;
+                verify_with(key, token)?
+            }
+            // signed before keys had ids, any of the keys may match
+            None => {
+                let mut ret = Err(anyhow!("no verification key"));
+                for key in &self.0 {
+                    ret = verify_with(key, token);
+                    if ret.is_ok() {
+                        break;
+                    }
+                }
+                ret?
+            }
         };
-        let claims = self.0.verify_token::<User>(token, Some(opts))?;
-        Ok(claims.custom)
+        let user_id = claims
+            .subject
+            .and_then(|sub| sub.parse().ok())
+            .ok_or_else(|| anyhow!("token has no valid subject"))?;
+        Ok(TokenClaims {
+            user_id,
+            ws_id: claims.custom.ws,
+            session_id: claims
+                .jwt_id
+                .and_then(|id| id.parse().ok())
+                .map(SessionId)
+                .or(claims.custom.sid),
+            scopes: claims.custom.scopes,
+            chat_ids: None,
+        })
     }
 }
 
+fn verify_with(
+    key: &Ed25519PublicKey,
+    token: &str,
+) -> Result<JWTClaims<CustomClaims>, jwt_simple::Error> {
+    let opts = VerificationOptions {
+        allowed_audiences: Some(HashSet::from_strings(&[JWT_AUDIENCE])),
+        allowed_issuers: Some(HashSet::from_strings(&[JWT_ISSUER])),
+        ..Default::default()
+    };
+    key.verify_token::<CustomClaims>(token, Some(opts))
+}
+
+/// The `kid` header of a token, without verifying it
+pub fn token_key_id(token: &str) -> Option<String> {
+    Token::decode_metadata(token)
+        .ok()
+        .and_then(|m| m.key_id().map(|kid| kid.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,11 +323,101 @@ mod tests {
         let encoding_key = EncodingKey::load(encoding_pem)?;
         let decoding_key = DecodingKey::load(decoding_pem)?;
 
-        let user = User::new(1, 1, "test".to_string(), "test@test.com".to_string());
-        let token = encoding_key.sign(user.clone())?;
+        let claims = TokenClaims::new(1, 1, None);
+        let token = encoding_key.sign(&claims, 60)?;
+
+        let verified = decoding_key.verify(&token)?;
+        assert_eq!(claims, verified);
+        assert_eq!(verified.session_id, None);
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_session_should_be_the_jti_claim() -> Result<()> {
+        let encoding_key = EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?;
+        let decoding_key = DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?;
+        let session_id = SessionId(Uuid::now_v7());
+
+        let token = encoding_key.sign(&TokenClaims::new(1, 1, Some(session_id)), 60)?;
+        let jwt = verify_with(&decoding_key.0[0], &token)?;
+        assert_eq!(jwt.jwt_id, Some(session_id.0.to_string()));
+        assert_eq!(decoding_key.verify(&token)?.session_id, Some(session_id));
+
+        // tokens signed with a `sid` claim are still accepted
+        let custom = serde_json::json!({ "ws": 1, "sid": session_id });
+        let claims = Claims::with_custom_claims(custom, Duration::from_secs(60))
+            .with_issuer(JWT_ISSUER)
+            .with_audience(JWT_AUDIENCE)
+            .with_subject(1);
+        let token = encoding_key.current().sign(claims)?;
+        assert_eq!(decoding_key.verify(&token)?.session_id, Some(session_id));
+        Ok(())
+    }
+
+    fn key_config(
+        kid: &str,
+        key: &Ed25519KeyPair,
+        active_from: Option<DateTime<Utc>>,
+    ) -> KeyConfig {
+        KeyConfig {
+            kid: kid.to_string(),
+            sk: Some(key.to_pem()),
+            pk: None,
+            active_from,
+        }
+    }
+
+    #[test]
+    fn jwt_should_sign_with_the_newest_active_key() -> Result<()> {
+        let old = Ed25519KeyPair::generate();
+        let new = Ed25519KeyPair::generate();
+        let next = Ed25519KeyPair::generate();
+        let keys = vec![
+            key_config("old", &old, None),
+            key_config("new", &new, Some(Utc::now() - chrono::Duration::hours(1))),
+            key_config("next", &next, Some(Utc::now() + chrono::Duration::hours(1))),
+        ];
+        let encoding_key = EncodingKey::from_config(&keys)?;
+        assert_eq!(encoding_key.key_id(), Some("new"));
+
+        let token = encoding_key.sign(&TokenClaims::new(1, 1, None), 60)?;
+        assert_eq!(token_key_id(&token).as_deref(), Some("new"));
+        let decoding_key = DecodingKey::from_config(&keys)?;
+        assert_eq!(decoding_key.verify(&token)?.user_id, 1);
+
+        // tokens of a key that was dropped from the config are rejected
+        let decoding_key = DecodingKey::from_config(&keys[..1])?;
+        assert!(!decoding_key.has_key("new"));
+        assert!(decoding_key.verify(&token).is_err());
+
+        // tokens signed before keys had ids still verify
+        let legacy = EncodingKey::load(&old.to_pem())?;
+        let token = legacy.sign(&TokenClaims::new(2, 1, None), 60)?;
+        assert_eq!(token_key_id(&token), None);
+        let decoding_key = DecodingKey::from_config(&keys)?;
+        assert_eq!(decoding_key.verify(&token)?.user_id, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_should_carry_session_and_scopes() -> Result<()> {
+        let encoding_key = EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?;
+        let decoding_key = DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?;
+
+        let session_id = SessionId(Uuid::now_v7());
+        let claims = TokenClaims {
+            scopes: vec![Scope::ChatsRead],
+            ..TokenClaims::new(1, 2, Some(session_id))
+        };
+        let token = encoding_key.sign(&claims, 60)?;
 
-        let user1 = decoding_key.verify(&token)?;
-        assert_eq!(user, user1);
+        let verified = decoding_key.verify(&token)?;
+        assert_eq!(verified.session_id, Some(session_id));
+        assert_eq!(verified.ws_id, 2);
+        assert!(verified.has_scope(Scope::ChatsRead));
+        assert!(!verified.has_scope(Scope::ChatsWrite));
+        assert!(verified.allows_chat(7));
+        assert_eq!("chats:write".parse(), Ok(Scope::ChatsWrite));
         Ok(())
     }
 }
@@ -1,3 +1,10 @@
+mod cache;
+mod jwks;
 mod jwt;
+mod session;
+mod user_cache;
 
+pub use jwks::*;
 pub use jwt::*;
+pub use session::*;
+pub use user_cache::*;
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use super::cache::TtlCache;
+use crate::SessionId;
+
+/// Answers whether a session is still active. Answers are cached for a short
+/// time so verifying a token doesn't hit the database on every request; a
+/// revocation is therefore seen by other processes only after `ttl`, unless
+/// they are told with `revoke`.
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    pool: PgPool,
+    cache: TtlCache<SessionId, bool>,
+}
+
+impl SessionStore {
+    pub fn new(pool: PgPool, ttl: Duration) -> Self {
+        Self {
+            pool,
+            cache: TtlCache::new(ttl),
+        }
+    }
+
+    /// Whether the session exists and isn't revoked. Looking it up in the
+    /// database also records it as seen.
+    pub async fn is_active(&self, id: SessionId) -> Result<bool, sqlx::Error> {
+        if let Some(active) = self.cache.get(&id) {
+            return Ok(active);
+        }
+        let row: Option<(i64,)> = sqlx::query_as(
+            r#"
+            UPDATE sessions SET last_seen_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND revoked_at IS NULL
+            RETURNING user_id
+            "#,
+        )
+        .bind(id.0)
+        .fetch_optional(&self.pool)
+        .await?;
+        let active = row.is_some();
+        self.cache.insert(id, active);
+        Ok(active)
+    }
+
+    /// Forget a revoked session right away
+    pub fn revoke(&self, id: SessionId) {
+        self.cache.insert(id, false);
+    }
+}
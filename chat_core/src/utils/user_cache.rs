@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use super::cache::TtlCache;
+use crate::User;
+
+/// Resolves the user of a verified token. Tokens only carry the user id, the
+/// profile is looked up here and cached for `ttl`; call `invalidate` after
+/// changing a user so this process sees the change right away.
+#[derive(Debug, Clone)]
+pub struct UserCache {
+    pool: PgPool,
+    cache: TtlCache<i64, User>,
+}
+
+impl UserCache {
+    pub fn new(pool: PgPool, ttl: Duration) -> Self {
+        Self {
+            pool,
+            cache: TtlCache::new(ttl),
+        }
+    }
+
+    pub async fn get(&self, id: i64) -> Result<Option<User>, sqlx::Error> {
+        if let Some(user) = self.cache.get(&id) {
+            return Ok(Some(user));
+        }
+        let user: Option<User> = sqlx::query_as(
+            "select id, ws_id, fullname, email, verified_at, created_at, updated_at from users where id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(user) = &user {
+            self.cache.insert(id, user.clone());
+        }
+        Ok(user)
+    }
+
+    pub fn invalidate(&self, id: i64) {
+        self.cache.remove(&id);
+    }
+}
@@ -4,16 +4,16 @@ VALUES ('Test ws', 0),
 ('Test ws 2', 0);
 
 -- insert 5 users
-insert into users(email, password_hash, fullname, ws_id)
-VALUES ('test@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User', 1),
-('test2@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 2', 1),
-('test3@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 3', 2),
-('test4@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 4', 2),
-('test5@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 5', 2),
-('test6@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 6', 2),
-('test7@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 7', 2),
-('test8@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 8', 2),
-('test9@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 9', 2);
+insert into users(email, password_hash, fullname, ws_id, verified_at)
+VALUES ('test@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User', 1, now()),
+('test2@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 2', 1, now()),
+('test3@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 3', 2, now()),
+('test4@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 4', 2, now()),
+('test5@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 5', 2, now()),
+('test6@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 6', 2, now()),
+('test7@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 7', 2, now()),
+('test8@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 8', 2, now()),
+('test9@yahoo.com', '$argon2id$v=19$m=19456,t=2,p=1$XS5QS+jiOarheORcKR6K5g$8Of79bSnJ5LFbcfxT/xt7iXRuW5p5Lu7PR+ctGX/lzQ', 'Test User 9', 2, now());
 
 -- insert 4 chats
 -- insert public/private channel
@@ -0,0 +1,20 @@
+use anyhow::Result;
+use chat_server::{AppConfig, AppState};
+use tracing::{info, level_filters::LevelFilter};
+use tracing_subscriber::{
+    fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt as _, Layer as _,
+};
+
+/// Re-address files uploaded before the switch to sha256 and rewrite the
+/// messages referencing them. Run it against the same config as the server.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let layer = Layer::new().with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(layer).init();
+
+    let config = AppConfig::load()?;
+    let state = AppState::try_new(config).await?;
+    let report = state.file_migrate_hashes().await?;
+    info!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
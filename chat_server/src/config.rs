@@ -3,12 +3,18 @@ use std::{fs::File, path::PathBuf};
 use serde::{Deserialize, Serialize};
 
 use anyhow::Result;
+use chat_core::{KeyConfig, Scope};
+use ipnet::IpNet;
 
 // app config from app.yml
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub files: FileConfig,
+    #[serde(default)]
+    pub mail: MailConfig,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -16,12 +22,313 @@ pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
     pub base_dir: PathBuf,
+    // reverse proxies whose x-forwarded-for / x-real-ip headers are believed,
+    // other clients are identified by their peer address
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AuthConfig {
-    pub sk: String,
-    pub pk: String,
+    // token signing keys, see `KeyConfig` for rotating them
+    pub keys: Vec<KeyConfig>,
+    #[serde(default = "default_access_token_ttl_secs")]
+    pub access_token_ttl_secs: u64,
+    #[serde(default = "default_refresh_token_ttl_secs")]
+    pub refresh_token_ttl_secs: u64,
+    // how long a session lookup is trusted before asking the database again
+    #[serde(default = "default_session_cache_secs")]
+    pub session_cache_secs: u64,
+    // how long a user looked up for a token is trusted
+    #[serde(default = "default_user_cache_secs")]
+    pub user_cache_secs: u64,
+    #[serde(default = "default_password_reset_ttl_secs")]
+    pub password_reset_ttl_secs: u64,
+    #[serde(default = "default_email_verification_ttl_secs")]
+    pub email_verification_ttl_secs: u64,
+    #[serde(default = "default_magic_link_ttl_secs")]
+    pub magic_link_ttl_secs: u64,
+    // what users can do before confirming their email
+    #[serde(default)]
+    pub unverified: UnverifiedPolicy,
+    #[serde(default)]
+    pub mfa: MfaConfig,
+    #[serde(default)]
+    pub sso: SsoConfig,
+    #[serde(default)]
+    pub signin: SigninThrottleConfig,
+    #[serde(default)]
+    pub password: PasswordConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PasswordConfig {
+    pub min_length: usize,
+    pub max_length: usize,
+    // reject passwords on the bundled list of breached passwords
+    pub reject_breached: bool,
+    // reject passwords containing the email or its local part
+    pub reject_email: bool,
+    pub argon2: Argon2Config,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            reject_breached: true,
+            reject_email: true,
+            argon2: Argon2Config::default(),
+        }
+    }
+}
+
+/// Argon2id cost parameters, hashes made with others are replaced when the
+/// user signs in
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct Argon2Config {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        // the argon2 crate's defaults, as recommended by OWASP
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SigninThrottleConfig {
+    // failed sign ins before an account or an IP is locked out
+    pub account_max_failures: u32,
+    pub ip_max_failures: u32,
+    pub lockout_secs: u64,
+    // wait after the first failure, doubled with every further one
+    pub backoff_base_secs: u64,
+    pub backoff_max_secs: u64,
+}
+
+impl Default for SigninThrottleConfig {
+    fn default() -> Self {
+        Self {
+            account_max_failures: 5,
+            ip_max_failures: 20,
+            lockout_secs: 15 * 60,
+            backoff_base_secs: 1,
+            backoff_max_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SsoConfig {
+    // where identity providers send the browser back to, register it with
+    // the provider
+    pub redirect_url: String,
+    // how long a sign in at the provider may take
+    pub login_ttl_secs: u64,
+    // how long users have to confirm signing in with a provider account
+    // that has their email
+    pub link_ttl_secs: u64,
+    // let providers live on private networks and loopback, e.g. in
+    // development. Workspace admins could then make the server call any
+    // internal service.
+    pub allow_private_issuers: bool,
+}
+
+impl Default for SsoConfig {
+    fn default() -> Self {
+        Self {
+            redirect_url: "http://localhost:6688/api/sso/callback".to_string(),
+            login_ttl_secs: 600,
+            link_ttl_secs: 86400,
+            allow_private_issuers: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MfaConfig {
+    // shown next to the account in authenticator apps
+    pub issuer: String,
+    // how long the password step of a 2FA sign in stays valid
+    pub pending_ttl_secs: u64,
+}
+
+impl Default for MfaConfig {
+    fn default() -> Self {
+        Self {
+            issuer: "Chat".to_string(),
+            pending_ttl_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UnverifiedPolicy {
+    // scopes of tokens issued to unverified users
+    pub scopes: Vec<Scope>,
+    // whether unverified users can be added to another workspace
+    pub join_workspaces: bool,
+}
+
+impl Default for UnverifiedPolicy {
+    fn default() -> Self {
+        // read only
+        Self {
+            scopes: vec![Scope::ChatsRead, Scope::FilesRead, Scope::Account],
+            join_workspaces: false,
+        }
+    }
+}
+
+fn default_access_token_ttl_secs() -> u64 {
+    60 * 15
+}
+
+fn default_refresh_token_ttl_secs() -> u64 {
+    60 * 60 * 24 * 30
+}
+
+fn default_session_cache_secs() -> u64 {
+    10
+}
+
+fn default_user_cache_secs() -> u64 {
+    30
+}
+
+fn default_password_reset_ttl_secs() -> u64 {
+    60 * 60
+}
+
+fn default_email_verification_ttl_secs() -> u64 {
+    60 * 60 * 48
+}
+
+fn default_magic_link_ttl_secs() -> u64 {
+    60 * 10
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FileConfig {
+    // unreferenced files younger than this are never collected
+    pub gc_grace_secs: u64,
+    // run the file gc periodically, 0 disables it
+    pub gc_interval_secs: u64,
+    // hmac secret for signed file urls, required. Changing it invalidates the
+    // urls signed so far
+    pub url_secret: Option<String>,
+    pub url_ttl_secs: u64,
+    // extensions a workspace may upload unless it configures its own list
+    pub allowed_exts: Vec<String>,
+    // scan uploads for malware, disabled if not set
+    pub scanner: Option<ScannerConfig>,
+    // strip EXIF and other metadata from uploaded images
+    pub strip_metadata: bool,
+    // extract the text of uploads in the background for message search
+    pub extract_text: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScannerConfig {
+    // clamd tcp address, e.g. 127.0.0.1:3310
+    pub clamd_addr: String,
+    // accept uploads right away and hold them in quarantine until scanned,
+    // otherwise the upload waits for the scan
+    #[serde(default)]
+    pub quarantine: bool,
+    #[serde(default = "default_scan_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_scan_timeout_secs() -> u64 {
+    30
+}
+
+impl Default for FileConfig {
+    fn default() -> Self {
+        Self {
+            gc_grace_secs: 60 * 60 * 24,
+            gc_interval_secs: 0,
+            url_secret: None,
+            url_ttl_secs: 60 * 5,
+            allowed_exts: [
+                "jpg", "png", "gif", "webp", "heic", "pdf", "txt", "md", "csv", "json", "yml",
+                "yaml", "doc", "docx", "xls", "xlsx", "ppt", "pptx", "zip", "mp3", "m4a", "wav",
+                "mp4", "mov", "webm",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+            scanner: None,
+            strip_metadata: true,
+            extract_text: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MailConfig {
+    // sender of outgoing mail
+    pub from: String,
+    // base url of the web app, links in mail point there
+    pub app_url: String,
+    // deliver over SMTP, otherwise mail is written to `dir`
+    pub smtp: Option<SmtpConfig>,
+    // defaults to `mail` under server.base_dir
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    // plain text, only for a relay on the same host
+    None,
+    #[default]
+    Starttls,
+    Tls,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            from: "Chat <noreply@localhost>".to_string(),
+            app_url: "http://localhost:6688".to_string(),
+            smtp: None,
+            dir: None,
+        }
+    }
 }
 
 impl AppConfig {
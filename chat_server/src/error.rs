@@ -1,4 +1,4 @@
-use axum::{response::IntoResponse, Json};
+use axum::{http::header, response::IntoResponse, Json};
 use serde_json::json;
 use thiserror::Error;
 
@@ -48,6 +48,51 @@ pub enum AppError {
 
     #[error("invalid chat file path: {0}")]
     InvalidChatFilePath(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("file is infected: {0}")]
+    FileInfected(String),
+
+    #[error("file is quarantined: {0}")]
+    FileQuarantined(String),
+
+    #[error("file scan error: {0}")]
+    FileScanError(String),
+
+    #[error("invalid image: {0}")]
+    InvalidImage(String),
+
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+
+    #[error("mail error: {0}")]
+    MailError(String),
+
+    #[error("email already verified")]
+    EmailAlreadyVerified,
+
+    #[error("two-factor authentication already enabled")]
+    MfaAlreadyEnabled,
+
+    #[error("identity provider error: {0}")]
+    SsoError(String),
+
+    #[error("access token error: {0}")]
+    AccessTokenError(String),
+
+    #[error("too many attempts, retry in {0} seconds")]
+    TooManyAttempts(u64),
+
+    #[error("weak password: {0}")]
+    WeakPassword(String),
+
+    #[error("invalid profile: {0}")]
+    InvalidProfile(String),
+
+    #[error("invalid status: {0}")]
+    InvalidStatus(String),
 }
 
 impl IntoResponse for AppError {
@@ -68,11 +113,30 @@ impl IntoResponse for AppError {
             AppError::InvalidFileURL(_) => axum::http::StatusCode::BAD_REQUEST,
             AppError::CreateMessageError(_) => axum::http::StatusCode::BAD_REQUEST,
             AppError::InvalidChatFilePath(_) => axum::http::StatusCode::BAD_REQUEST,
+            AppError::PermissionDenied(_) => axum::http::StatusCode::FORBIDDEN,
+            AppError::FileInfected(_) => axum::http::StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::FileQuarantined(_) => axum::http::StatusCode::LOCKED,
+            AppError::FileScanError(_) => axum::http::StatusCode::BAD_GATEWAY,
+            AppError::InvalidImage(_) => axum::http::StatusCode::BAD_REQUEST,
+            AppError::InvalidToken(_) => axum::http::StatusCode::UNAUTHORIZED,
+            AppError::MailError(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::EmailAlreadyVerified => axum::http::StatusCode::CONFLICT,
+            AppError::MfaAlreadyEnabled => axum::http::StatusCode::CONFLICT,
+            AppError::SsoError(_) => axum::http::StatusCode::BAD_GATEWAY,
+            AppError::AccessTokenError(_) => axum::http::StatusCode::BAD_REQUEST,
+            AppError::TooManyAttempts(_) => axum::http::StatusCode::TOO_MANY_REQUESTS,
+            AppError::WeakPassword(_) => axum::http::StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::InvalidProfile(_) => axum::http::StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::InvalidStatus(_) => axum::http::StatusCode::UNPROCESSABLE_ENTITY,
         };
 
         let body = Json(json!({
             "error": self.to_string(),
         }));
-        (status_code, body).into_response()
+        let mut res = (status_code, body).into_response();
+        if let AppError::TooManyAttempts(secs) = self {
+            res.headers_mut().insert(header::RETRY_AFTER, secs.into());
+        }
+        res
     }
 }
@@ -0,0 +1,115 @@
+use tracing::warn;
+
+// keeps the tsvector of a single file well below the postgres limit of 1MB
+const MAX_TEXT_LEN: usize = 256 * 1024;
+
+/// Extract the searchable text of a file: the text layer of a PDF, or the
+/// content of plain text, markdown, code and other UTF-8 files. Returns
+/// `None` for binary formats or if extraction fails.
+///
+/// PDF parsing is CPU bound, call it from a blocking task.
+pub fn extract_text(ext: &str, data: &[u8]) -> Option<String> {
+    let text = match ext {
+        "pdf" => match std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem(data)) {
+            Ok(Ok(text)) => text,
+            Ok(Err(e)) => {
+                warn!("failed to extract text from pdf: {}", e);
+                return None;
+            }
+            Err(_) => {
+                warn!("pdf text extraction panicked");
+                return None;
+            }
+        },
+        _ => match infer::get(data) {
+            Some(kind) if kind.matcher_type() != infer::MatcherType::Text => return None,
+            _ => std::str::from_utf8(data).ok()?.to_string(),
+        },
+    };
+    let text = text.replace('\0', "");
+    let text = truncate(text.trim(), MAX_TEXT_LEN);
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn truncate(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A one page PDF showing `text` in Helvetica
+    pub(crate) fn pdf_with_text(text: &str) -> Vec<u8> {
+        let content = format!("BT /F1 12 Tf 72 712 Td ({}) Tj ET", text);
+        let objects = [
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(),
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] \
+             /Resources << /Font << /F1 4 0 R >> >> /Contents 5 0 R >>"
+                .to_string(),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>".to_string(),
+            format!(
+                "<< /Length {} >>\nstream\n{}\nendstream",
+                content.len(),
+                content
+            ),
+        ];
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).into_bytes());
+        }
+        let xref = pdf.len();
+        pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).into_bytes());
+        for offset in offsets {
+            pdf.extend(format!("{:010} 00000 n \n", offset).into_bytes());
+        }
+        pdf.extend(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                xref
+            )
+            .into_bytes(),
+        );
+        pdf
+    }
+
+    #[test]
+    fn extract_text_should_read_pdf() {
+        let pdf = pdf_with_text("quarterly revenue report");
+        let text = extract_text("pdf", &pdf).unwrap();
+        assert!(text.contains("quarterly revenue report"), "{}", text);
+        assert!(extract_text("pdf", b"%PDF-1.4 garbage").is_none());
+    }
+
+    #[test]
+    fn extract_text_should_read_text_files() {
+        assert_eq!(
+            extract_text("md", b"# Title\n\nbody\n").unwrap(),
+            "# Title\n\nbody"
+        );
+        assert_eq!(
+            extract_text("rs", b"fn main() {}\0").unwrap(),
+            "fn main() {}"
+        );
+        assert!(extract_text("png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").is_none());
+        assert!(extract_text("bin", &[0xff, 0xfe, 0x00]).is_none());
+        assert!(extract_text("txt", b"   ").is_none());
+    }
+
+    #[test]
+    fn truncate_should_respect_char_boundaries() {
+        assert_eq!(truncate("héllo", 2), "h");
+        assert_eq!(truncate("hello", 10), "hello");
+    }
+}
@@ -0,0 +1,38 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::TokenClaims;
+
+use crate::{models::CreateAccessToken, AppError, AppState, User};
+
+pub(crate) async fn list_access_tokens_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    let tokens = state.access_token_list(user.id).await?;
+    Ok(Json(tokens))
+}
+
+/// Create a personal access token, the response is the only time it is shown
+pub(crate) async fn create_access_token_handler(
+    State(state): State<AppState>,
+    Extension(claims): Extension<TokenClaims>,
+    Json(input): Json<CreateAccessToken>,
+) -> Result<impl IntoResponse, AppError> {
+    let created = state.access_token_create(&claims, input).await?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+pub(crate) async fn revoke_access_token_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state.access_token_revoke(user.id, id).await? {
+        return Err(AppError::NotFound(format!("access token {} not found", id)));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,55 +1,196 @@
 use axum::{
     extract::State,
     http::{header, StatusCode},
-    response::IntoResponse,
-    Json,
+    response::{IntoResponse, Response},
+    Extension, Json,
 };
+use axum_extra::{headers::Cookie, TypedHeader};
 
-use crate::models::{CreateUserPayload, SignInPayload};
-use crate::{AppError, AppState};
+use crate::models::{
+    ClientInfo, CreateUserPayload, ForgotPasswordPayload, MagicLinkPayload, MagicLinkSignInPayload,
+    MfaSignInPayload, RefreshTokenPayload, ResetPasswordPayload, SignInPayload, VerifyEmailPayload,
+    MAGIC_LINK_COOKIE,
+};
+use crate::{AppError, AppState, User};
 
 pub(crate) async fn signup_handler(
     State(state): State<AppState>,
+    client: ClientInfo,
     Json(payload): Json<CreateUserPayload>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.user_create(payload.clone()).await?;
+    state.email_verification_send(&user).await?;
 
-    let token = state.sk.sign(user)?;
+    let output = state.token_issue(user, &client).await?;
 
     Ok((
         StatusCode::CREATED,
-        [(header::AUTHORIZATION, format!("Bearer {}", token))],
-        (),
+        [(header::AUTHORIZATION, format!("Bearer {}", output.token))],
+        Json(output),
     ))
 }
 
-/// Sign in handler
+/// Sign in handler, users with 2FA on get an mfa token to finish signing in
+/// at `/signin/mfa` instead of tokens.
 pub(crate) async fn signin_handler(
     State(state): State<AppState>,
+    client: ClientInfo,
     Json(payload): Json<SignInPayload>,
+) -> Result<Response, AppError> {
+    let user = state.signin(&payload, &client).await?;
+
+    if !state.workspace_password_login(user.ws_id).await? {
+        return Err(AppError::PermissionDenied(
+            "password sign in is turned off, sign in with single sign-on".to_string(),
+        ));
+    }
+    signin_response(&state, user, &client).await
+}
+
+/// Mail a sign in link, the response sets the cookie the link is bound to.
+/// Answers the same whether the email is known.
+pub(crate) async fn magic_link_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<MagicLinkPayload>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = state.user_verify(&payload.email, &payload.password).await?;
-
-    match user {
-        Some(user) => {
-            let token = state.sk.sign(user)?;
-            Ok((
-                StatusCode::CREATED,
-                [(header::AUTHORIZATION, format!("Bearer {}", token))],
-                "",
-            ))
-        }
-        None => Err(AppError::NotFound(format!(
-            "User with email {} not found",
-            payload.email
-        ))),
+    let nonce = state.magic_link_send(&payload.email, &client).await?;
+    let cookie = format!(
+        "{}={}; Path=/api/signin/magic; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        MAGIC_LINK_COOKIE, nonce, state.config.auth.magic_link_ttl_secs
+    );
+    Ok((StatusCode::ACCEPTED, [(header::SET_COOKIE, cookie)]))
+}
+
+/// Finish a sign in with the token of a mailed link, in the browser that
+/// asked for it. Users with 2FA on get an mfa token like at `/signin`.
+pub(crate) async fn magic_link_signin_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    cookies: Option<TypedHeader<Cookie>>,
+    Json(payload): Json<MagicLinkSignInPayload>,
+) -> Result<Response, AppError> {
+    let Some(nonce) = cookies
+        .as_ref()
+        .and_then(|TypedHeader(cookies)| cookies.get(MAGIC_LINK_COOKIE))
+    else {
+        return Err(AppError::InvalidToken(
+            "sign in links only work in the browser that asked for them".to_string(),
+        ));
+    };
+    let user = state.magic_link_verify(&payload.token, nonce).await?;
+    let mut res = signin_response(&state, user, &client).await?;
+    let cookie = format!("{}=; Path=/api/signin/magic; Max-Age=0", MAGIC_LINK_COOKIE);
+    res.headers_mut()
+        .insert(header::SET_COOKIE, cookie.parse()?);
+    Ok(res)
+}
+
+// tokens for a user who proved who they are, or the 2FA challenge
+pub(crate) async fn signin_response(
+    state: &AppState,
+    user: User,
+    client: &ClientInfo,
+) -> Result<Response, AppError> {
+    if state.mfa_enabled(user.id).await? {
+        let output = state.mfa_challenge(user.id).await?;
+        return Ok((StatusCode::OK, Json(output)).into_response());
     }
+    let output = state.token_issue(user, client).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::AUTHORIZATION, format!("Bearer {}", output.token))],
+        Json(output),
+    )
+        .into_response())
+}
+
+/// Finish a 2FA sign in with the mfa token and a TOTP or recovery code
+pub(crate) async fn signin_mfa_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<MfaSignInPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.mfa_complete(&payload, &client).await?;
+    let output = state.token_issue(user, &client).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::AUTHORIZATION, format!("Bearer {}", output.token))],
+        Json(output),
+    ))
+}
+
+/// Exchange a refresh token for a new access token, the refresh token rotates
+pub(crate) async fn refresh_token_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshTokenPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let output = state.token_refresh(&payload.refresh_token).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::AUTHORIZATION, format!("Bearer {}", output.token))],
+        Json(output),
+    ))
+}
+
+/// Mail a password reset link, answers the same whether the email is known
+pub(crate) async fn forgot_password_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<ForgotPasswordPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    state.password_forgot(&payload.email, &client).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Set a new password with a mailed reset token
+pub(crate) async fn reset_password_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<ResetPasswordPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    state.password_reset(payload).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Confirm an email with the token of the mailed link
+pub(crate) async fn verify_email_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<VerifyEmailPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.email_verify(&payload.token).await?;
+    Ok(Json(user))
+}
+
+/// Mail a new verification link, earlier links stop working
+pub(crate) async fn resend_verification_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    state.email_verification_send(&user).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// The public keys tokens are verified with, for other services
+pub(crate) async fn jwks_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        Json(state.pk.jwks()),
+    )
 }
 
 #[cfg(test)]
 mod tests {
 
+    use axum_extra::headers::Header;
+    use chat_core::TokenVerify;
+    use http_body_util::BodyExt;
+
     use super::*;
+    use crate::{
+        mailer::tests::{mailed_token, wait_for_mail},
+        models::{AuthOutput, MfaPendingOutput},
+        totp::Totp,
+    };
 
     #[tokio::test]
     async fn signin_handler_should_work() -> Result<(), AppError> {
@@ -58,22 +199,89 @@ mod tests {
         let create_user = CreateUserPayload {
             email: "test@test.com".to_string(),
             fullname: "test".to_string(),
-            password: "test".to_string(),
+            password: "hunter42".to_string(),
             workspace: "Default".to_string(),
         };
         state.user_create(create_user).await?;
 
         let user = SignInPayload {
             email: "test@test.com".to_string(),
-            password: "test".to_string(),
+            password: "hunter42".to_string(),
         };
-        let res = signin_handler(State(state), Json(user))
+        let res = signin_handler(State(state.clone()), ClientInfo::default(), Json(user))
             .await?
             .into_response();
         assert_eq!(res.status(), axum::http::StatusCode::CREATED);
         assert!(res.headers().get(header::AUTHORIZATION).is_some());
+
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let output: AuthOutput = serde_json::from_slice(&body).unwrap();
+        let payload = RefreshTokenPayload {
+            refresh_token: output.refresh_token,
+        };
+        let res = refresh_token_handler(State(state.clone()), Json(payload.clone()))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), axum::http::StatusCode::CREATED);
+
+        // refresh tokens are single use
+        let res = refresh_token_handler(State(state), Json(payload))
+            .await
+            .into_response();
+        assert_eq!(res.status(), axum::http::StatusCode::UNAUTHORIZED);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn signin_handler_should_ask_for_second_factor() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = 1")
+            .bind(state.password_hash("hunter42")?)
+            .execute(&state.pool)
+            .await?;
+        let totp = Totp::generate();
+        sqlx::query("INSERT INTO user_totp (user_id, secret, confirmed_at) VALUES (1, $1, now())")
+            .bind(totp.secret())
+            .execute(&state.pool)
+            .await?;
+
+        let user = SignInPayload {
+            email: "test@yahoo.com".to_string(),
+            password: "hunter42".to_string(),
+        };
+        let res = signin_handler(State(state.clone()), ClientInfo::default(), Json(user)).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(header::AUTHORIZATION).is_none());
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let pending: MfaPendingOutput = serde_json::from_slice(&body).unwrap();
+
+        let payload = MfaSignInPayload {
+            mfa_token: pending.mfa_token,
+            code: totp.code_at(Totp::step(chrono::Utc::now().timestamp())),
+        };
+        let res = signin_mfa_handler(State(state), ClientInfo::default(), Json(payload))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert!(res.headers().get(header::AUTHORIZATION).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn jwks_handler_should_publish_keys() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let res = jwks_handler(State(state.clone())).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let jwks: chat_core::Jwks = serde_json::from_slice(&body).unwrap();
+        let pk = chat_core::DecodingKey::from_jwks(&jwks)?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        let output = state.token_issue(user, &ClientInfo::default()).await?;
+        assert_eq!(pk.verify(&output.token)?.user_id, 1);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn signin_handler_should_fail_when_user_not_found() -> Result<(), AppError> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -81,20 +289,18 @@ mod tests {
         let create_user = CreateUserPayload {
             email: "test@test.com".to_string(),
             fullname: "test".to_string(),
-            password: "test".to_string(),
+            password: "hunter42".to_string(),
             workspace: "Default".to_string(),
         };
         state.user_create(create_user).await?;
 
         let user = SignInPayload {
             email: "test1@test.com".to_string(),
-            password: "test".to_string(),
+            password: "hunter42".to_string(),
         };
-        let res = signin_handler(State(state), Json(user)).await;
-        assert!(matches!(
-            res,
-            Err(AppError::NotFound(msg)) if msg == "User with email test1@test.com not found"
-        ));
+        // unknown emails can't be told apart from wrong passwords
+        let res = signin_handler(State(state), ClientInfo::default(), Json(user)).await;
+        assert!(matches!(res, Err(AppError::InvalidCredentials)));
         Ok(())
     }
 
@@ -105,7 +311,7 @@ mod tests {
         let create_user = CreateUserPayload {
             email: "test@test.com".to_string(),
             fullname: "test".to_string(),
-            password: "test".to_string(),
+            password: "hunter42".to_string(),
             workspace: "Default".to_string(),
         };
         state.user_create(create_user).await?;
@@ -114,7 +320,7 @@ mod tests {
             email: "test@test.com".to_string(),
             password: "test1".to_string(),
         };
-        let res = signin_handler(State(state), Json(user)).await;
+        let res = signin_handler(State(state), ClientInfo::default(), Json(user)).await;
         matches!(res, Err(AppError::InvalidCredentials));
 
         // assert_eq!(
@@ -130,10 +336,10 @@ mod tests {
         let user = CreateUserPayload {
             email: "test@test.com".to_string(),
             fullname: "test".to_string(),
-            password: "test".to_string(),
+            password: "hunter42".to_string(),
             workspace: "Default".to_string(),
         };
-        let res = signup_handler(State(state), Json(user.clone()))
+        let res = signup_handler(State(state), ClientInfo::default(), Json(user.clone()))
             .await?
             .into_response();
         assert_eq!(res.status(), axum::http::StatusCode::CREATED);
@@ -148,13 +354,55 @@ mod tests {
         let user = CreateUserPayload {
             email: "test@test.com".to_string(),
             fullname: "test".to_string(),
-            password: "test".to_string(),
+            password: "hunter42".to_string(),
             workspace: "Default".to_string(),
         };
         state.user_create(user.clone()).await?;
 
-        let res = signup_handler(State(state), Json(user)).await;
+        let res = signup_handler(State(state), ClientInfo::default(), Json(user)).await;
         matches!(res, Err(AppError::UserAlreadyExists));
         Ok(())
     }
+
+    #[tokio::test]
+    async fn magic_link_should_sign_in_with_the_cookie() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let payload = MagicLinkPayload {
+            email: "test@yahoo.com".to_string(),
+        };
+        let res = magic_link_handler(State(state.clone()), ClientInfo::default(), Json(payload))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let set_cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.contains("HttpOnly"));
+        let cookie = set_cookie.split(';').next().unwrap();
+        let token = mailed_token(&wait_for_mail(&state.config.server.base_dir.join("mail")).await);
+
+        let payload = MagicLinkSignInPayload {
+            token: token.clone(),
+        };
+        let ret = magic_link_signin_handler(
+            State(state.clone()),
+            ClientInfo::default(),
+            None,
+            Json(payload.clone()),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+
+        let cookies = Cookie::decode(&mut std::iter::once(&cookie.parse()?)).unwrap();
+        let res = magic_link_signin_handler(
+            State(state.clone()),
+            ClientInfo::default(),
+            Some(TypedHeader(cookies)),
+            Json(payload),
+        )
+        .await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let output: AuthOutput = serde_json::from_slice(&body).unwrap();
+        assert_eq!(state.verify(&output.token).await?.user_id, 1);
+        Ok(())
+    }
 }
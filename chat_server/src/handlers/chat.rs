@@ -6,13 +6,17 @@ use axum::{
     response::IntoResponse,
     Extension, Json,
 };
+use chat_core::TokenClaims;
 
 pub(crate) async fn list_chat_handler(
     State(state): State<AppState>,
     Extension(user): Extension<User>,
+    Extension(claims): Extension<TokenClaims>,
 ) -> Result<impl IntoResponse, AppError> {
     tracing::info!("user: {:?}", user);
-    let chats = state.chat_fetched_all_by_ws_id(user.ws_id as u64).await?;
+    let mut chats = state.chat_fetched_all_by_ws_id(user.ws_id as u64).await?;
+    // tokens may be limited to some chats
+    chats.retain(|chat| claims.allows_chat(chat.id));
 
     Ok((StatusCode::OK, Json(chats)))
 }
@@ -1,17 +1,20 @@
 use axum::{
     extract::{Multipart, Path, Query, State},
     http::{
-        header::{CONTENT_LENGTH, CONTENT_TYPE},
-        HeaderMap,
+        header::{CONTENT_LENGTH, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+        HeaderMap, HeaderValue,
     },
     response::IntoResponse,
     Extension, Json,
 };
+use serde::Deserialize;
+use std::time::Duration;
 use tokio::fs;
-use tracing::{info, warn};
+use tracing::warn;
 
-use crate::models::{CreateMessage, ListMessages};
+use crate::models::{CreateMessage, ListChatFiles, ListMessages, SearchMessages, SignFiles};
 use crate::{models::ChatFile, AppError, AppState, User};
+use chat_core::TokenClaims;
 
 pub(crate) async fn send_message_handler(
     State(state): State<AppState>,
@@ -39,13 +42,32 @@ pub(crate) async fn list_message_handler(
     Ok(Json(messages))
 }
 
+/// Search messages by content and by the text of their attachments
+pub(crate) async fn search_messages_handler(
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+    Query(input): Query<SearchMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state.message_search(input, chat_id).await?;
+    Ok(Json(messages))
+}
+
+/// Files posted in the chat, each with the id of the message for jump-to-context
+pub(crate) async fn list_chat_files_handler(
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+    Query(input): Query<ListChatFiles>,
+) -> Result<impl IntoResponse, AppError> {
+    let files = state.file_list_by_chat(input, chat_id).await?;
+    Ok(Json(files))
+}
+
 #[allow(unused)]
 pub(crate) async fn upload_handler(
     State(state): State<AppState>,
     Extension(user): Extension<User>,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
-    let ws_id = user.ws_id.to_string();
     let mut files: Vec<String> = vec![];
     while let Some(mut field) = multipart.next_field().await? {
         let filename = field.file_name().map(|name| name.to_string());
@@ -54,15 +76,10 @@ pub(crate) async fn upload_handler(
             warn!("Failed to read multipart field");
             continue;
         };
-        let file = ChatFile::new(ws_id.parse().unwrap(), filename, &data);
-        let path = file.path(&state.config.server.base_dir);
-        if path.exists() {
-            info!("File already exists: {}", path.display());
-        } else {
-            fs::create_dir_all(path.parent().expect("file path parent should exist")).await?;
-            fs::write(path, data).await?;
-        }
-        files.push(file.url());
+        let meta = state
+            .file_upload(user.ws_id as _, user.id as _, filename, &data)
+            .await?;
+        files.push(meta.path);
     }
     Ok(Json(files))
 }
@@ -71,25 +88,96 @@ pub(crate) async fn upload_handler(
 pub(crate) async fn download_handler(
     State(state): State<AppState>,
     Extension(user): Extension<User>,
+    Extension(claims): Extension<TokenClaims>,
     Path((ws_id, file_url)): Path<(i64, String)>,
 ) -> Result<impl IntoResponse, AppError> {
+    let chat_ids = claims.chat_ids.as_deref();
     if user.ws_id != ws_id {
         return Err(AppError::NotFound(
             "File doesn't exist or you don't have permission".to_string(),
         ));
     }
 
-    let base_dir = state.config.server.base_dir.join(ws_id.to_string());
+    let file = state
+        .file_parse(&format!("/files/{}/{}", ws_id, file_url))
+        .await?;
+    if !state
+        .file_can_access(&file.url(), user.id as _, chat_ids)
+        .await?
+    {
+        return Err(AppError::NotFound(
+            "File doesn't exist or you don't have permission".to_string(),
+        ));
+    }
+
+    state.file_check_available(&file.url()).await?;
 
-    let file_path = base_dir.join(file_url);
+    let file_path = file.path(&state.config.server.base_dir);
     if !file_path.exists() {
         return Err(AppError::NotFound("File doesn't exist".to_string()));
     }
-    let mime = mime_guess::from_path(&file_path).first_or_octet_stream();
     let body = fs::read(file_path).await?;
+    let content_type = match state.file_fetch_by_url(&file.url()).await? {
+        Some(meta) => meta.content_type,
+        None => ChatFile::content_type(&body),
+    };
     let mut header = HeaderMap::new();
-    header.insert(CONTENT_TYPE, mime.to_string().parse().unwrap());
-    header.insert(CONTENT_LENGTH, body.len().to_string().parse().unwrap());
+    header.insert(CONTENT_TYPE, content_type.parse()?);
+    header.insert(CONTENT_LENGTH, body.len().to_string().parse()?);
+    header.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
 
     Ok((header, body))
 }
+
+/// Issue short-lived signed urls, e.g. for `<img src>`, so no session token ends up in the url
+pub(crate) async fn sign_files_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Extension(claims): Extension<TokenClaims>,
+    Json(input): Json<SignFiles>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut urls = Vec::with_capacity(input.files.len());
+    for url in &input.files {
+        let file = state.file_parse(url).await?;
+        if file.ws_id != user.ws_id as u64
+            || !state
+                .file_can_access(&file.url(), user.id as _, claims.chat_ids.as_deref())
+                .await?
+        {
+            return Err(AppError::NotFound(
+                "File doesn't exist or you don't have permission".to_string(),
+            ));
+        }
+        state.file_check_available(&file.url()).await?;
+        urls.push(state.file_url_sign(&file.url(), user.id)?);
+    }
+    Ok(Json(urls))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct FileGcParams {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Collect unreferenced files of the user's workspace, workspace owner only
+pub(crate) async fn file_gc_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(params): Query<FileGcParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws_id = user.ws_id as u64;
+    let workspace = state
+        .workspace_fetch_by_id(ws_id)
+        .await?
+        .ok_or(AppError::WorkspaceNotFound)?;
+    if workspace.owner_id != user.id {
+        return Err(AppError::PermissionDenied(
+            "only the workspace owner can collect files".to_string(),
+        ));
+    }
+
+    let grace = Duration::from_secs(state.config.files.gc_grace_secs);
+    let report = state.file_gc(Some(ws_id), grace, params.dry_run).await?;
+    Ok(Json(report))
+}
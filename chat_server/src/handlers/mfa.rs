@@ -0,0 +1,49 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+
+use crate::{
+    models::{ClientInfo, MfaCodePayload},
+    AppError, AppState, User,
+};
+
+/// Start setting up TOTP, returns the secret and its otpauth URI
+pub(crate) async fn enroll_totp_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    let enrollment = state.mfa_totp_enroll(&user).await?;
+    Ok((StatusCode::CREATED, Json(enrollment)))
+}
+
+/// Turn 2FA on with a first code, returns the recovery codes
+pub(crate) async fn confirm_totp_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(payload): Json<MfaCodePayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let codes = state.mfa_totp_confirm(user.id, &payload.code).await?;
+    Ok(Json(codes))
+}
+
+/// Turn 2FA off with a TOTP or recovery code
+pub(crate) async fn disable_mfa_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    client: ClientInfo,
+    Json(payload): Json<MfaCodePayload>,
+) -> Result<impl IntoResponse, AppError> {
+    state.mfa_disable(&user, &payload.code, &client).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replace the recovery codes, the old ones stop working
+pub(crate) async fn regenerate_recovery_codes_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    client: ClientInfo,
+    Json(payload): Json<MfaCodePayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let codes = state
+        .mfa_recovery_codes_regenerate(&user, &payload.code, &client)
+        .await?;
+    Ok(Json(codes))
+}
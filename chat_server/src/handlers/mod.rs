@@ -1,12 +1,22 @@
+mod access_token;
 mod auth;
 mod chat;
 mod messages;
+mod mfa;
+mod profile;
+mod session;
+mod sso;
 mod workspace;
 use axum::response::IntoResponse;
 
+pub(crate) use access_token::*;
 pub(crate) use auth::*;
 pub(crate) use chat::*;
 pub(crate) use messages::*;
+pub(crate) use mfa::*;
+pub(crate) use profile::*;
+pub(crate) use session::*;
+pub(crate) use sso::*;
 pub(crate) use workspace::*;
 
 pub(crate) async fn index() -> impl IntoResponse {
@@ -0,0 +1,159 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{
+    models::{DndSettings, UpdateProfile, UserStatus},
+    AppError, AppState, User,
+};
+
+pub(crate) async fn get_me_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(profile) = state.user_profile(user.id, user.ws_id).await? else {
+        return Err(AppError::NotFound(format!("user {} not found", user.id)));
+    };
+    Ok(Json(profile))
+}
+
+/// Update the user's own profile, members of the workspace get a
+/// `UserUpdated` event
+pub(crate) async fn update_me_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(input): Json<UpdateProfile>,
+) -> Result<impl IntoResponse, AppError> {
+    let profile = state.user_profile_update(&user, input).await?;
+    Ok(Json(profile))
+}
+
+pub(crate) async fn get_status_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    let status = state.user_status(user.id).await?;
+    Ok(Json(status))
+}
+
+/// Set the custom status shown in user listings, it's cleared once
+/// `expires_at` passed
+pub(crate) async fn update_status_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(input): Json<UserStatus>,
+) -> Result<impl IntoResponse, AppError> {
+    let status = state.user_status_update(user.id, input).await?;
+    Ok(Json(status))
+}
+
+pub(crate) async fn clear_status_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .user_status_update(user.id, UserStatus::default())
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn get_dnd_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    let dnd = state.user_dnd(user.id).await?;
+    Ok(Json(dnd))
+}
+
+/// Replace the do not disturb settings, notifications other than urgent
+/// ones are held while it's active
+pub(crate) async fn update_dnd_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(input): Json<DndSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    let dnd = state.user_dnd_update(user.id, input).await?;
+    Ok(Json(dnd))
+}
+
+pub(crate) async fn clear_dnd_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .user_dnd_update(user.id, DndSettings::default())
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The profile of a user of the same workspace, users of other workspaces
+/// look like they don't exist
+pub(crate) async fn get_user_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(profile) = state.user_profile(id, user.ws_id).await? else {
+        return Err(AppError::NotFound(format!("user {} not found", id)));
+    };
+    Ok(Json(profile))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use chat_core::UserProfile;
+    use http_body_util::BodyExt;
+
+    use super::*;
+    use crate::{handlers::user_list_handler, ChatUser};
+
+    #[tokio::test]
+    async fn get_user_handler_should_stay_in_the_workspace() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+
+        let res = get_user_handler(State(state.clone()), Extension(user.clone()), Path(2))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let profile: UserProfile = serde_json::from_slice(&body).unwrap();
+        assert_eq!(profile.email, "test2@yahoo.com");
+
+        let ret = get_user_handler(State(state.clone()), Extension(user.clone()), Path(3)).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn numeric_workspace_name_should_not_hide_user_ids() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE workspaces SET name = '2' WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+
+        let res = user_list_handler(
+            State(state.clone()),
+            Extension(user.clone()),
+            Path("2".to_string()),
+        )
+        .await?
+        .into_response();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let users: Vec<ChatUser> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(users.len(), 2);
+
+        let res = get_user_handler(State(state), Extension(user), Path(2))
+            .await?
+            .into_response();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let profile: UserProfile = serde_json::from_slice(&body).unwrap();
+        assert_eq!(profile.id, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Path, State},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::SessionId;
+use ipnet::IpNet;
+use uuid::Uuid;
+
+use crate::{models::ClientInfo, AppError, AppState, User};
+
+impl FromRequestParts<AppState> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let user_agent = header_str(&parts.headers, header::USER_AGENT.as_str());
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| {
+                client_ip(
+                    addr.ip(),
+                    &parts.headers,
+                    &state.config.server.trusted_proxies,
+                )
+                .to_string()
+            });
+        Ok(Self { user_agent, ip })
+    }
+}
+
+/// The address of the client. Behind a trusted proxy the peer address is the
+/// proxy's, the client is the last address in x-forwarded-for that isn't a
+/// trusted proxy. Anyone else could make up the headers.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+    if let Some(forwarded) = header_str(headers, "x-forwarded-for") {
+        let mut client = None;
+        for hop in forwarded.rsplit(',') {
+            let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = Some(ip);
+            if !is_trusted(&ip) {
+                break;
+            }
+        }
+        if let Some(ip) = client {
+            return ip;
+        }
+    }
+    header_str(headers, "x-real-ip")
+        .and_then(|ip| ip.parse().ok())
+        .unwrap_or(peer)
+}
+
+fn header_str(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+}
+
+/// List the signed in devices of the user
+pub(crate) async fn list_sessions_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Extension(session_id): Extension<SessionId>,
+) -> Result<impl IntoResponse, AppError> {
+    let sessions = state.session_list(user.id, Some(session_id)).await?;
+    Ok(Json(sessions))
+}
+
+/// Sign out a device, its tokens stop working right away
+pub(crate) async fn revoke_session_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state.session_revoke(user.id, SessionId(id)).await? {
+        return Err(AppError::NotFound(format!("session {}", id)));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sign out everywhere, including the current session
+pub(crate) async fn revoke_all_sessions_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    state.session_revoke_all(user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use chat_core::TokenVerify;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn client_info_should_ignore_forwarded_ip_of_untrusted_peers() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let req = Request::builder()
+            .header(header::USER_AGENT, "curl/8.0")
+            .header("x-forwarded-for", "203.0.113.7")
+            .header("x-real-ip", "203.0.113.8")
+            .extension(ConnectInfo(
+                "198.51.100.1:4000".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::empty())
+            .unwrap();
+        let (mut parts, _) = req.into_parts();
+        let client = ClientInfo::from_request_parts(&mut parts, &state)
+            .await
+            .unwrap();
+        assert_eq!(
+            client,
+            ClientInfo {
+                user_agent: Some("curl/8.0".to_string()),
+                ip: Some("198.51.100.1".to_string())
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn client_ip_should_skip_trusted_proxies() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let headers = |name: &str, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+            headers
+        };
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        // the client may prepend anything, only the hop the proxy added counts
+        let forwarded = headers("x-forwarded-for", "1.2.3.4, 203.0.113.7, 10.0.0.2");
+        assert_eq!(
+            client_ip(proxy, &forwarded, &trusted),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        let real_ip = headers("x-real-ip", "203.0.113.8");
+        assert_eq!(
+            client_ip(proxy, &real_ip, &trusted),
+            "203.0.113.8".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(client_ip(proxy, &HeaderMap::new(), &trusted), proxy);
+        let peer: IpAddr = "198.51.100.1".parse().unwrap();
+        assert_eq!(client_ip(peer, &forwarded, &trusted), peer);
+    }
+
+    #[tokio::test]
+    async fn revoke_session_handler_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        let output = state
+            .token_issue(user.clone(), &ClientInfo::default())
+            .await?;
+        let session_id = state.verify(&output.token).await?.session_id.unwrap();
+
+        let res = revoke_session_handler(
+            State(state.clone()),
+            Extension(user.clone()),
+            Path(Uuid::now_v7()),
+        )
+        .await;
+        assert!(matches!(res, Err(AppError::NotFound(_))));
+
+        let res = revoke_session_handler(State(state.clone()), Extension(user), Path(session_id.0))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(state.session_list(1, None).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+
+use super::auth::signin_response;
+use crate::{
+    models::{ClientInfo, SsoCallbackQuery, SsoLinkPayload},
+    AppError, AppState,
+};
+
+/// Send the browser to the workspace's identity provider
+pub(crate) async fn sso_login_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(ws_name): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let url = state.sso_login_start(&ws_name, &client).await?;
+    Ok(Redirect::to(&url))
+}
+
+/// Where the identity provider sends the browser back to, answers like a
+/// password sign in, users with 2FA on get an mfa token. Accepted without
+/// tokens when the account has the email of a user who has to confirm the
+/// link by mail first.
+pub(crate) async fn sso_callback_handler(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Query(query): Query<SsoCallbackQuery>,
+) -> Result<Response, AppError> {
+    let Some(user) = state.sso_login_finish(&query, &client).await? else {
+        return Ok(StatusCode::ACCEPTED.into_response());
+    };
+    signin_response(&state, user, &client).await
+}
+
+/// Confirm linking a provider account with the token of the mailed link
+pub(crate) async fn sso_link_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<SsoLinkPayload>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.sso_link_confirm(&payload.token).await?;
+    Ok(Json(user))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header;
+    use chat_core::TokenVerify;
+    use http_body_util::BodyExt;
+
+    use super::*;
+    use crate::{
+        models::{AuthOutput, MfaPendingOutput, SignInPayload, UpdateWorkspaceSettings},
+        oidc::tests::{authorize, mock_sso, MockAccount},
+        signin_handler,
+    };
+
+    #[tokio::test]
+    async fn sso_login_should_work_end_to_end() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let account = MockAccount {
+            subject: "alice".to_string(),
+            email: "alice@acme.org".to_string(),
+            email_verified: true,
+        };
+        mock_sso(&state, 1, account).await;
+
+        let res = sso_login_handler(
+            State(state.clone()),
+            ClientInfo::default(),
+            Path("Test ws".to_string()),
+        )
+        .await?
+        .into_response();
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        let url = res.headers()[header::LOCATION].to_str().unwrap();
+        let query = authorize(url).await;
+
+        let res = sso_callback_handler(State(state.clone()), ClientInfo::default(), Query(query))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let output: AuthOutput = serde_json::from_slice(&body).unwrap();
+        let claims = state.verify(&output.token).await?;
+        assert_eq!(state.user(&claims).await?.email, "alice@acme.org");
+
+        // 2FA isn't skipped
+        let user = state.user(&claims).await?;
+        state.mfa_totp_enroll(&user).await?;
+        sqlx::query("UPDATE user_totp SET confirmed_at = CURRENT_TIMESTAMP WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&state.pool)
+            .await?;
+        let url = state
+            .sso_login_start("Test ws", &ClientInfo::default())
+            .await?;
+        let query = authorize(&url).await;
+        let res =
+            sso_callback_handler(State(state.clone()), ClientInfo::default(), Query(query)).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(header::AUTHORIZATION).is_none());
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let _: MfaPendingOutput = serde_json::from_slice(&body).unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn password_login_should_be_turned_off_with_sso() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let off = UpdateWorkspaceSettings {
+            password_login: Some(Some(false)),
+            ..Default::default()
+        };
+        let ret = state.workspace_settings_update(1, off.clone()).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let account = MockAccount {
+            subject: "alice".to_string(),
+            email: "alice@acme.org".to_string(),
+            email_verified: true,
+        };
+        mock_sso(&state, 1, account).await;
+        state.workspace_settings_update(1, off).await?;
+        let sso_user = state
+            .sso_login_finish(
+                &authorize(
+                    &state
+                        .sso_login_start("Test ws", &ClientInfo::default())
+                        .await?,
+                )
+                .await,
+                &ClientInfo::default(),
+            )
+            .await?
+            .unwrap();
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = 1")
+            .bind(state.password_hash("hunter42")?)
+            .execute(&state.pool)
+            .await?;
+        let payload = SignInPayload {
+            email: "test@yahoo.com".to_string(),
+            password: "hunter42".to_string(),
+        };
+        let ret = signin_handler(State(state.clone()), ClientInfo::default(), Json(payload)).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // removing the provider turns password sign in back on
+        assert!(state.workspace_sso_delete(1).await?);
+        assert!(state.workspace_password_login(1).await?);
+        // users created by single sign-on have no password
+        let payload = SignInPayload {
+            email: sso_user.email,
+            password: "".to_string(),
+        };
+        let ret = signin_handler(State(state), ClientInfo::default(), Json(payload)).await;
+        assert!(matches!(ret, Err(AppError::InvalidCredentials)));
+        Ok(())
+    }
+}
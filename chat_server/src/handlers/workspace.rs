@@ -1,25 +1,103 @@
 use axum::{
     extract::{Path, State},
+    http::StatusCode,
     response::IntoResponse,
-    Json,
+    Extension, Json,
 };
 
-use crate::{AppError, AppState};
+use crate::{
+    models::{UpdateWorkspaceSettings, WorkspaceSso},
+    AppError, AppState, User,
+};
 
+/// The users of the user's own workspace, other workspaces look like they
+/// don't exist
 pub(crate) async fn user_list_handler(
     State(state): State<AppState>,
+    Extension(user): Extension<User>,
     Path(ws_name): Path<String>,
 ) -> Result<impl IntoResponse, AppError> {
     let workspace = state.workspace_fetch_by_name(&ws_name).await?;
-    if workspace.is_none() {
+    let Some(workspace) = workspace.filter(|ws| ws.id == user.ws_id) else {
         return Err(AppError::WorkspaceNotFound);
-    }
-    let users = state
-        .user_fetched_all_by_ws_id(workspace.unwrap().id as u64)
-        .await?;
+    };
+    let users = state.user_fetched_all_by_ws_id(workspace.id as u64).await?;
     Ok(Json(users))
 }
 
+pub(crate) async fn get_workspace_settings_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state.workspace_settings(user.ws_id as _).await?;
+    Ok(Json(settings))
+}
+
+/// Update the settings of the user's workspace, workspace owner only
+pub(crate) async fn update_workspace_settings_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(input): Json<UpdateWorkspaceSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    ensure_owner(&state, &user).await?;
+    let settings = state
+        .workspace_settings_update(user.ws_id as _, input)
+        .await?;
+    Ok(Json(settings))
+}
+
+/// The single sign-on provider of the user's workspace, workspace owner only
+pub(crate) async fn get_workspace_sso_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    ensure_owner(&state, &user).await?;
+    match state.workspace_sso(user.ws_id).await? {
+        Some(sso) => Ok(Json(sso)),
+        None => Err(AppError::NotFound(
+            "no single sign-on configured".to_string(),
+        )),
+    }
+}
+
+/// Set the single sign-on provider of the user's workspace, workspace owner only
+pub(crate) async fn update_workspace_sso_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(input): Json<WorkspaceSso>,
+) -> Result<impl IntoResponse, AppError> {
+    ensure_owner(&state, &user).await?;
+    let sso = state.workspace_sso_update(user.ws_id, input).await?;
+    Ok(Json(sso))
+}
+
+/// Remove the single sign-on provider, password sign in is turned back on
+pub(crate) async fn delete_workspace_sso_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    ensure_owner(&state, &user).await?;
+    if !state.workspace_sso_delete(user.ws_id).await? {
+        return Err(AppError::NotFound(
+            "no single sign-on configured".to_string(),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn ensure_owner(state: &AppState, user: &User) -> Result<(), AppError> {
+    let workspace = state
+        .workspace_fetch_by_id(user.ws_id as _)
+        .await?
+        .ok_or(AppError::WorkspaceNotFound)?;
+    if workspace.owner_id != user.id {
+        return Err(AppError::PermissionDenied(
+            "only the workspace owner can change settings".to_string(),
+        ));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -33,24 +111,32 @@ mod tests {
         let (_tdb, state) = AppState::new_for_test().await?;
 
         let workspace = state.workspace_create("my_ws").await?;
-        let _user = state
+        let user = state
             .user_create(CreateUserPayload {
                 email: "test@test.com".to_string(),
                 fullname: "test".to_string(),
-                password: "test".to_string(),
+                password: "hunter42".to_string(),
                 workspace: workspace.name.clone(),
             })
             .await?;
 
-        let res = user_list_handler(State(state), Path("my_ws".to_string()))
-            .await?
-            .into_response();
+        let res = user_list_handler(
+            State(state.clone()),
+            Extension(user.clone()),
+            Path("my_ws".to_string()),
+        )
+        .await?
+        .into_response();
 
         assert_eq!(res.status(), StatusCode::OK);
         let body = res.into_body().collect().await.unwrap();
         let users = serde_json::from_slice::<Vec<ChatUser>>(&body.to_bytes()).unwrap();
         assert_eq!(users.len(), 1);
         assert_eq!(users[0].email, "test@test.com");
+
+        let ret =
+            user_list_handler(State(state), Extension(user), Path("Test ws".to_string())).await;
+        assert!(matches!(ret, Err(AppError::WorkspaceNotFound)));
         Ok(())
     }
 }
@@ -1,53 +1,200 @@
 mod config;
 mod error;
+mod extract;
 mod handlers;
+mod mailer;
+mod metadata;
 mod middlewares;
 mod models;
+mod oidc;
+mod scanner;
+mod totp;
 
 use core::fmt;
-use std::{ops::Deref, sync::Arc};
+use std::{ops::Deref, sync::Arc, time::Duration};
 
 use anyhow::Context;
 use axum::{
-    middleware::from_fn_with_state,
-    routing::{get, post},
+    handler::Handler,
+    middleware::{from_fn, from_fn_with_state},
+    routing::{delete, get, post},
     Router,
 };
-use chat_core::{verify_token, DecodingKey, EncodingKey, TokenVerify};
+use chat_core::{
+    require_scope, verify_token, DecodingKey, EncodingKey, Scope, SessionStore, TokenClaims,
+    TokenVerify, UserCache,
+};
 use handlers::*;
-use middlewares::verify_chat;
+use mailer::new_mailer;
+use middlewares::{reject_chat_limited, verify_chat, verify_file_url};
+use oidc::OidcClient;
+use scanner::ClamdScanner;
 use sqlx::PgPool;
 use tokio::fs;
 
 pub use chat_core::User;
 pub use config::AppConfig;
 pub use error::AppError;
+pub use mailer::{Mail, Mailer};
 pub use models::*;
+pub use scanner::{FileScanner, ScanVerdict};
 
 pub async fn get_router(state: &mut AppState) -> Result<Router, AppError> {
+    let chats_read = from_fn_with_state(Scope::ChatsRead, require_scope);
+    let chats_write = from_fn_with_state(Scope::ChatsWrite, require_scope);
+    let files_read = from_fn_with_state(Scope::FilesRead, require_scope);
+    let files_write = from_fn_with_state(Scope::FilesWrite, require_scope);
+    let account = from_fn_with_state(Scope::Account, require_scope);
+
     let chat = Router::new()
         .route(
             "/{id}",
-            get(get_chat_handler)
-                .post(send_message_handler)
-                .patch(update_chat_handler)
-                .delete(delete_chat_handler),
+            get(get_chat_handler.layer(chats_read.clone()))
+                .post(send_message_handler.layer(chats_write.clone()))
+                .patch(update_chat_handler.layer(chats_write.clone()))
+                .delete(delete_chat_handler.layer(chats_write.clone())),
+        )
+        .route(
+            "/{id}/messages",
+            get(list_message_handler).route_layer(chats_read.clone()),
+        )
+        .route(
+            "/{id}/messages/search",
+            get(search_messages_handler).route_layer(chats_read.clone()),
+        )
+        .route(
+            "/{id}/files",
+            get(list_chat_files_handler).route_layer(chats_read.clone()),
         )
-        .route("/{id}/messages", get(list_message_handler))
         .layer(from_fn_with_state(state.clone(), verify_chat))
-        .route("/", get(list_chat_handler).post(create_chat_handler));
+        .route(
+            "/",
+            get(list_chat_handler.layer(chats_read.clone())).post(
+                create_chat_handler
+                    .layer(chats_write)
+                    .layer(from_fn(reject_chat_limited)),
+            ),
+        );
 
     let api_router = Router::new()
         .nest("/chats", chat)
-        .route("/users/{ws_name}", get(user_list_handler))
-        .route("/upload", post(upload_handler))
-        .route("/files/{ws_id}/{*file_url}", get(download_handler))
+        .route(
+            "/users/{ws_name}",
+            get(user_list_handler).route_layer(chats_read.clone()),
+        )
+        .route(
+            "/users/id/{id}",
+            get(get_user_handler).route_layer(chats_read),
+        )
+        .route(
+            "/me",
+            get(get_me_handler).patch(update_me_handler.layer(account.clone())),
+        )
+        .route(
+            "/me/status",
+            get(get_status_handler)
+                .put(update_status_handler)
+                .delete(clear_status_handler)
+                .route_layer(account.clone()),
+        )
+        .route(
+            "/me/dnd",
+            get(get_dnd_handler)
+                .put(update_dnd_handler)
+                .delete(clear_dnd_handler)
+                .route_layer(account.clone()),
+        )
+        .route(
+            "/workspace/settings",
+            get(get_workspace_settings_handler)
+                .patch(update_workspace_settings_handler)
+                .route_layer(account.clone()),
+        )
+        .route(
+            "/upload",
+            post(upload_handler)
+                .route_layer(files_write)
+                .route_layer(from_fn(reject_chat_limited)),
+        )
+        .route(
+            "/files/sign",
+            post(sign_files_handler).route_layer(files_read.clone()),
+        )
+        .route(
+            "/files/gc",
+            post(file_gc_handler).route_layer(account.clone()),
+        )
+        .route(
+            "/sessions",
+            get(list_sessions_handler)
+                .delete(revoke_all_sessions_handler)
+                .route_layer(account.clone()),
+        )
+        .route(
+            "/email/verify/resend",
+            post(resend_verification_handler).route_layer(account.clone()),
+        )
+        .route(
+            "/workspace/sso",
+            get(get_workspace_sso_handler)
+                .put(update_workspace_sso_handler)
+                .delete(delete_workspace_sso_handler)
+                .route_layer(account.clone()),
+        )
+        .route(
+            "/mfa/totp",
+            post(enroll_totp_handler).route_layer(account.clone()),
+        )
+        .route(
+            "/mfa/totp/confirm",
+            post(confirm_totp_handler).route_layer(account.clone()),
+        )
+        .route(
+            "/mfa/disable",
+            post(disable_mfa_handler).route_layer(account.clone()),
+        )
+        .route(
+            "/mfa/recovery-codes",
+            post(regenerate_recovery_codes_handler).route_layer(account.clone()),
+        )
+        .route(
+            "/tokens",
+            get(list_access_tokens_handler)
+                .post(create_access_token_handler)
+                .route_layer(account.clone()),
+        )
+        .route(
+            "/tokens/{id}",
+            delete(revoke_access_token_handler).route_layer(account.clone()),
+        )
+        .route(
+            "/sessions/{id}",
+            delete(revoke_session_handler).route_layer(account),
+        )
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+        .route(
+            "/files/{ws_id}/{*file_url}",
+            // signed urls carry claims with every scope, unsigned ones need a
+            // token allowed to read files
+            get(download_handler.layer(files_read))
+                .layer(from_fn_with_state(state.clone(), verify_file_url)),
+        )
         .route("/signin", post(signin_handler))
+        .route("/signin/mfa", post(signin_mfa_handler))
+        .route("/signin/magic", post(magic_link_handler))
+        .route("/signin/magic/verify", post(magic_link_signin_handler))
+        .route("/sso/{ws_name}/login", get(sso_login_handler))
+        .route("/sso/callback", get(sso_callback_handler))
+        .route("/sso/link", post(sso_link_handler))
+        .route("/token/refresh", post(refresh_token_handler))
+        .route("/password/forgot", post(forgot_password_handler))
+        .route("/password/reset", post(reset_password_handler))
+        .route("/email/verify", post(verify_email_handler))
         .route("/signup", post(signup_handler));
 
     let app = Router::new()
         .route("/", get(index))
+        .route("/.well-known/jwks.json", get(jwks_handler))
         .nest("/api", api_router)
         .with_state(state.clone());
 
@@ -64,18 +211,41 @@ impl AppState {
         fs::create_dir_all(&config.server.base_dir)
             .await
             .context("create base dir failed")?;
+        if config.files.url_secret.as_deref().is_none_or(str::is_empty) {
+            return Err(anyhow::anyhow!("files.url_secret must be set").into());
+        }
 
-        let sk = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
-        let pk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
+        let sk = EncodingKey::from_config(&config.auth.keys).context("load sk failed")?;
+        let pk = DecodingKey::from_config(&config.auth.keys).context("load pk failed")?;
         let pool = PgPool::connect(&config.server.db_url)
             .await
             .context("connect to db failed")?;
+        let scanner = config
+            .files
+            .scanner
+            .as_ref()
+            .map(|c| Arc::new(ClamdScanner::new(c)) as Arc<dyn FileScanner>);
+        let sessions = SessionStore::new(
+            pool.clone(),
+            Duration::from_secs(config.auth.session_cache_secs),
+        );
+        let users = UserCache::new(
+            pool.clone(),
+            Duration::from_secs(config.auth.user_cache_secs),
+        );
+        let mailer = new_mailer(&config)?;
+        let oidc = OidcClient::new(config.auth.sso.allow_private_issuers);
         Ok(Self {
             inner: Arc::new(AppStateInner {
                 config,
                 sk,
                 pk,
                 pool,
+                sessions,
+                users,
+                mailer,
+                oidc,
+                scanner,
             }),
         })
     }
@@ -84,8 +254,30 @@ impl AppState {
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> Result<User, Self::Error> {
-        self.inner.pk.verify(token).map_err(AppError::from)
+    async fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error> {
+        if token.starts_with(ACCESS_TOKEN_PREFIX) {
+            return self.access_token_verify(token).await;
+        }
+        let claims = self.inner.pk.verify(token)?;
+        let Some(session_id) = claims.session_id else {
+            return Err(AppError::InvalidToken("token has no session".to_string()));
+        };
+        if !self.sessions.is_active(session_id).await? {
+            return Err(AppError::InvalidToken("session revoked".to_string()));
+        }
+        Ok(claims)
+    }
+
+    async fn user(&self, claims: &TokenClaims) -> Result<User, Self::Error> {
+        let Some(user) = self.users.get(claims.user_id).await? else {
+            return Err(AppError::InvalidToken("user no longer exists".to_string()));
+        };
+        // the user moved since the token was issued, a refreshed token has
+        // the new workspace
+        if user.ws_id != claims.ws_id {
+            return Err(AppError::InvalidToken("workspace changed".to_string()));
+        }
+        Ok(user)
     }
 }
 
@@ -95,6 +287,11 @@ pub struct AppStateInner {
     pub sk: EncodingKey,
     pub pk: DecodingKey,
     pub pool: PgPool,
+    pub sessions: SessionStore,
+    pub users: UserCache,
+    pub mailer: Arc<dyn Mailer>,
+    pub oidc: OidcClient,
+    pub scanner: Option<Arc<dyn FileScanner>>,
 }
 
 impl Deref for AppState {
@@ -125,14 +322,28 @@ mod test_utils {
     impl AppState {
         #[allow(unused)]
         pub async fn new_for_test() -> Result<(TestPg, Self), AppError> {
-            let config = AppConfig::load()?;
+            let mut config = AppConfig::load()?;
 
-            let sk = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
-            let pk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
+            let sk = EncodingKey::from_config(&config.auth.keys).context("load sk failed")?;
+            let pk = DecodingKey::from_config(&config.auth.keys).context("load pk failed")?;
             let db_url_prefix = config.server.db_url.rfind('/').expect("db_url invalid");
             let db_url = config.server.db_url[..db_url_prefix].to_string();
 
             let (tdb, pool) = get_pg_and_pool(Some(&db_url)).await;
+            let sessions = SessionStore::new(
+                pool.clone(),
+                Duration::from_secs(config.auth.session_cache_secs),
+            );
+            let users = UserCache::new(
+                pool.clone(),
+                Duration::from_secs(config.auth.user_cache_secs),
+            );
+            // every test database gets its own file storage and mail directory
+            config.server.base_dir = config.server.base_dir.join(tdb.dbname.as_str());
+            config.mail.dir = None;
+            let mailer = new_mailer(&config)?;
+            // the mock identity provider listens on loopback
+            let oidc = OidcClient::new(true);
 
             let state = Self {
                 inner: Arc::new(AppStateInner {
@@ -140,6 +351,11 @@ mod test_utils {
                     sk,
                     pk,
                     pool,
+                    sessions,
+                    users,
+                    mailer,
+                    oidc,
+                    scanner: None,
                 }),
             };
             Ok((tdb, state))
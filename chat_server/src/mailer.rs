@@ -0,0 +1,196 @@
+use std::{future::Future, path::PathBuf, pin::Pin, sync::Arc, time::Duration};
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::{
+    config::{SmtpConfig, SmtpTls},
+    AppConfig, AppError,
+};
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type MailFuture<'a> = Pin<Box<dyn Future<Output = Result<(), AppError>> + Send + 'a>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    // plain text
+    pub body: String,
+}
+
+/// Delivers mail to users, e.g. password reset links.
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, mail: &'a Mail) -> MailFuture<'a>;
+}
+
+/// The mailer of the config: SMTP if configured, otherwise mail is written
+/// to a local directory.
+pub fn new_mailer(config: &AppConfig) -> Result<Arc<dyn Mailer>, AppError> {
+    let from: Mailbox = config
+        .mail
+        .from
+        .parse()
+        .map_err(|e| AppError::MailError(format!("invalid sender: {}", e)))?;
+    let mailer: Arc<dyn Mailer> = match &config.mail.smtp {
+        Some(smtp) => Arc::new(SmtpMailer::new(from, smtp)?),
+        None => {
+            let dir = match &config.mail.dir {
+                Some(dir) => dir.clone(),
+                None => config.server.base_dir.join("mail"),
+            };
+            Arc::new(DirMailer::new(from, dir))
+        }
+    };
+    Ok(mailer)
+}
+
+fn build_message(from: &Mailbox, mail: &Mail) -> Result<Message, AppError> {
+    let to: Mailbox = mail
+        .to
+        .parse()
+        .map_err(|e| AppError::MailError(format!("invalid recipient: {}", e)))?;
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(&mail.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(mail.body.clone())
+        .map_err(|e| AppError::MailError(e.to_string()))
+}
+
+/// Mailer relaying through an SMTP server.
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    pub fn new(from: Mailbox, config: &SmtpConfig) -> Result<Self, AppError> {
+        let builder = match config.tls {
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host),
+            SmtpTls::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                &config.host,
+            )),
+        }
+        .map_err(|e| AppError::MailError(e.to_string()))?;
+        let mut builder = builder.port(config.port).timeout(Some(SMTP_TIMEOUT));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Self {
+            from,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> MailFuture<'a> {
+        Box::pin(async move {
+            let message = build_message(&self.from, mail)?;
+            self.transport
+                .send(message)
+                .await
+                .map_err(|e| AppError::MailError(e.to_string()))?;
+            Ok(())
+        })
+    }
+}
+
+/// Mailer writing every mail as an `.eml` file into a directory, for
+/// development and tests.
+pub struct DirMailer {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl DirMailer {
+    pub fn new(from: Mailbox, dir: PathBuf) -> Self {
+        Self { from, dir }
+    }
+}
+
+impl Mailer for DirMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> MailFuture<'a> {
+        Box::pin(async move {
+            let message = build_message(&self.from, mail)?;
+            fs::create_dir_all(&self.dir).await?;
+            // v7 uuids sort by time
+            let path = self.dir.join(format!("{}.eml", Uuid::now_v7()));
+            fs::write(path, message.formatted()).await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    /// The latest mail written to `dir`, waiting for it to arrive
+    pub(crate) async fn wait_for_mail(dir: &Path) -> String {
+        for _ in 0..50 {
+            if let Ok(mut entries) = std::fs::read_dir(dir) {
+                let mut names: Vec<_> = entries
+                    .by_ref()
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .collect();
+                names.sort();
+                if let Some(path) = names.pop() {
+                    return std::fs::read_to_string(path).unwrap();
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("no mail in {}", dir.display());
+    }
+
+    /// The token of the link in a mail
+    pub(crate) fn mailed_token(eml: &str) -> String {
+        // long lines are quoted-printable encoded
+        let eml = eml.replace("=\r\n", "").replace("=3D", "=");
+        let start = eml.find("?token=").expect("mail has no link") + "?token=".len();
+        eml[start..]
+            .chars()
+            .take_while(|c| c.is_ascii_hexdigit())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn dir_mailer_should_write_eml() -> Result<(), AppError> {
+        let dir = std::env::temp_dir().join(format!("chat-mail-{}", Uuid::now_v7()));
+        let mailer = DirMailer::new("Chat <noreply@chat.test>".parse().unwrap(), dir.clone());
+        let mail = Mail {
+            to: "alice@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "hello world".to_string(),
+        };
+        mailer.send(&mail).await?;
+
+        let eml = wait_for_mail(&dir).await;
+        assert!(eml.contains("To: alice@example.com"));
+        assert!(eml.contains("Subject: Hello"));
+        assert!(eml.contains("hello world"));
+        std::fs::remove_dir_all(dir)?;
+
+        let mail = Mail {
+            to: "not an address".to_string(),
+            ..mail
+        };
+        assert!(matches!(
+            mailer.send(&mail).await,
+            Err(AppError::MailError(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+
 use anyhow::Result;
 use chat_server::{get_router, AppConfig, AppState};
 use tokio::net::TcpListener;
@@ -15,13 +17,19 @@ async fn main() -> Result<()> {
     let addr = format!("0.0.0.0:{}", config.server.port);
 
     let mut state = AppState::try_new(config).await?;
+    state.file_gc_schedule();
+    state.file_extract_schedule();
 
     let app = get_router(&mut state).await?;
 
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on: {}", addr);
 
-    axum::serve(listener, app.into_make_service()).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
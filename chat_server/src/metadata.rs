@@ -0,0 +1,326 @@
+use crate::AppError;
+
+/// image formats metadata can be stripped from, by sniffed extension
+pub const STRIPPABLE_EXTS: [&str; 3] = ["jpg", "png", "webp"];
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// EXIF orientation tag in IFD0
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Remove EXIF, XMP, comments and other text metadata (which may carry GPS
+/// coordinates or device details) from an image, leaving the pixel data and
+/// color profiles untouched. Returns `None` for formats that are not handled.
+///
+/// The EXIF orientation of a JPEG is kept so that photos taken in portrait
+/// mode are still displayed upright.
+pub fn strip_metadata(ext: &str, data: &[u8]) -> Result<Option<Vec<u8>>, AppError> {
+    let stripped = match ext {
+        "jpg" => strip_jpeg(data),
+        "png" => strip_png(data),
+        "webp" => strip_webp(data),
+        _ => return Ok(None),
+    };
+    match stripped {
+        Some(data) => Ok(Some(data)),
+        None => Err(AppError::InvalidImage(format!("malformed {} image", ext))),
+    }
+}
+
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        // markers may be preceded by any number of 0xFF fill bytes
+        while data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = *data.get(pos + 1)?;
+        match marker {
+            // markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            0xD9 => {
+                out.extend_from_slice(&data[pos..pos + 2]);
+                return Some(out);
+            }
+            _ => {}
+        }
+        let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        if len < 2 {
+            return None;
+        }
+        let segment = data.get(pos..pos + 2 + len)?;
+        if marker == 0xDA {
+            // start of scan, no metadata follows in the entropy coded data (0xFF
+            // is always escaped there). Anything after the end of image marker
+            // is dropped too, phones append extra images with their own EXIF.
+            let rest = &data[pos..];
+            let end = rest.windows(2).position(|w| w == [0xFF, 0xD9])?;
+            out.extend_from_slice(&rest[..end + 2]);
+            return Some(out);
+        }
+        let payload = &segment[4..];
+        match marker {
+            // APP1 holds EXIF and XMP
+            0xE1 => {
+                if let Some(orientation) = exif_orientation(payload) {
+                    out.extend_from_slice(&orientation_segment(orientation));
+                }
+            }
+            // APP2 is kept for ICC color profiles only
+            0xE2 if payload.starts_with(b"ICC_PROFILE\0") => out.extend_from_slice(segment),
+            // APP14 (Adobe) tells how to convert colors, APP0 is JFIF
+            0xE2..=0xED | 0xEF | 0xFE => {}
+            _ => out.extend_from_slice(segment),
+        }
+        pos += 2 + len;
+    }
+}
+
+fn exif_orientation(payload: &[u8]) -> Option<u16> {
+    let tiff = payload.strip_prefix(b"Exif\0\0")?;
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| -> Option<u16> {
+        let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(match big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    };
+    let ifd: [u8; 4] = tiff.get(4..8)?.try_into().ok()?;
+    let ifd = match big_endian {
+        true => u32::from_be_bytes(ifd),
+        false => u32::from_le_bytes(ifd),
+    } as usize;
+    let count = u16_at(ifd)? as usize;
+    let orientation = (0..count).find_map(|i| {
+        let entry = ifd + 2 + i * 12;
+        (u16_at(entry)? == ORIENTATION_TAG).then(|| u16_at(entry + 8))?
+    })?;
+    // 1 is the default, anything above 8 is invalid
+    (2..=8).contains(&orientation).then_some(orientation)
+}
+
+// a minimal big endian EXIF segment with only the orientation in IFD0
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut payload = b"Exif\0\0MM\0\x2a\0\0\0\x08".to_vec();
+    payload.extend_from_slice(&1u16.to_be_bytes());
+    payload.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    // type SHORT, count 1, value left aligned in the 4 byte field
+    payload.extend_from_slice(&3u16.to_be_bytes());
+    payload.extend_from_slice(&1u32.to_be_bytes());
+    payload.extend_from_slice(&orientation.to_be_bytes());
+    payload.extend_from_slice(&[0, 0]);
+    // no next IFD
+    payload.extend_from_slice(&0u32.to_be_bytes());
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(&payload);
+    segment
+}
+
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut rest = data.strip_prefix(PNG_SIGNATURE)?;
+    let mut out = PNG_SIGNATURE.to_vec();
+    loop {
+        let len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        // length, type, data and crc
+        let chunk = rest.get(..len.checked_add(12)?)?;
+        let kind = &chunk[4..8];
+        if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+            out.extend_from_slice(chunk);
+        }
+        rest = &rest[chunk.len()..];
+        if kind == b"IEND" {
+            return Some(out);
+        }
+    }
+}
+
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let riff_len = u32::from_le_bytes(data[4..8].try_into().ok()?) as usize;
+    let data = data.get(..riff_len.checked_add(8)?)?;
+    let mut out = data[..12].to_vec();
+    let mut pos = 12;
+    while pos < data.len() {
+        let header = data.get(pos..pos + 8)?;
+        let len = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+        // chunks are padded to an even size
+        let chunk = data.get(pos..pos + 8 + len + (len & 1))?;
+        match &header[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" if len >= 1 => {
+                // clear the EXIF and XMP flags
+                let mut chunk = chunk.to_vec();
+                chunk[8] &= !(0x08 | 0x04);
+                out.extend_from_slice(&chunk);
+            }
+            _ => out.extend_from_slice(chunk),
+        }
+        pos += chunk.len();
+    }
+    let riff_len = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    Some(out)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|w| w == needle)
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    // little endian EXIF with the orientation and a GPS IFD pointer
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut payload = b"Exif\0\0II\x2a\0\x08\0\0\0".to_vec();
+        payload.extend_from_slice(&2u16.to_le_bytes());
+        payload.extend_from_slice(&0x8825u16.to_le_bytes());
+        payload.extend_from_slice(&4u16.to_le_bytes());
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(&38u32.to_le_bytes());
+        payload.extend_from_slice(&ORIENTATION_TAG.to_le_bytes());
+        payload.extend_from_slice(&3u16.to_le_bytes());
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(&orientation.to_le_bytes());
+        payload.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        payload.extend_from_slice(b"GPS 37.7749N 122.4194W");
+        payload
+    }
+
+    /// A JPEG carrying EXIF with GPS data, XMP and a comment
+    pub(crate) fn jpeg_with_exif(orientation: u16) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        data.extend(jpeg_segment(0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0"));
+        data.extend(jpeg_segment(0xE1, &exif(orientation)));
+        data.extend(jpeg_segment(
+            0xE1,
+            b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta>Pixel 8</x:xmpmeta>",
+        ));
+        data.extend(jpeg_segment(0xE2, b"ICC_PROFILE\0\x01\x01sRGB"));
+        data.extend(jpeg_segment(0xFE, b"taken at home"));
+        data.extend(jpeg_segment(0xDB, &[0u8; 65]));
+        data.extend(jpeg_segment(0xDA, &[1, 1, 0, 0, 0x3F, 0]));
+        data.extend_from_slice(&[0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56, 0xFF, 0xD9]);
+        // trailing second image
+        data.extend_from_slice(b"\xFF\xD8GPS\xFF\xD9");
+        data
+    }
+
+    fn png_chunk(kind: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut chunk = (payload.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(payload);
+        // crc is not checked by the stripper
+        chunk.extend_from_slice(&[0, 0, 0, 0]);
+        chunk
+    }
+
+    fn webp_chunk(kind: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    #[test]
+    fn strip_jpeg_should_remove_metadata() {
+        let data = jpeg_with_exif(6);
+        let stripped = strip_metadata("jpg", &data).unwrap().unwrap();
+        assert!(!contains(&stripped, b"GPS"));
+        assert!(!contains(&stripped, b"Pixel 8"));
+        assert!(!contains(&stripped, b"taken at home"));
+        assert!(contains(&stripped, b"JFIF"));
+        assert!(contains(&stripped, b"ICC_PROFILE"));
+        assert!(stripped.ends_with(&[0x56, 0xFF, 0xD9]));
+        // the orientation survives, right after the JFIF segment
+        let exif = &stripped[20..];
+        assert_eq!(&exif[..2], &[0xFF, 0xE1]);
+        assert_eq!(exif_orientation(&exif[4..]), Some(6));
+
+        // stripping is idempotent
+        let again = strip_metadata("jpg", &stripped).unwrap().unwrap();
+        assert_eq!(again, stripped);
+    }
+
+    #[test]
+    fn strip_jpeg_should_drop_default_orientation() {
+        let stripped = strip_metadata("jpg", &jpeg_with_exif(1)).unwrap().unwrap();
+        assert!(!contains(&stripped, b"Exif"));
+    }
+
+    #[test]
+    fn strip_png_should_remove_metadata() {
+        let mut data = PNG_SIGNATURE.to_vec();
+        data.extend(png_chunk(b"IHDR", &[0u8; 13]));
+        data.extend(png_chunk(b"eXIf", b"MM\0\x2aGPS"));
+        data.extend(png_chunk(b"tEXt", b"Author\0jimmy"));
+        data.extend(png_chunk(b"iCCP", b"sRGB\0\0"));
+        data.extend(png_chunk(b"IDAT", &[1, 2, 3]));
+        data.extend(png_chunk(b"IEND", &[]));
+
+        let stripped = strip_metadata("png", &data).unwrap().unwrap();
+        assert!(!contains(&stripped, b"GPS"));
+        assert!(!contains(&stripped, b"jimmy"));
+        assert!(contains(&stripped, b"iCCP"));
+        assert!(stripped.ends_with(&png_chunk(b"IEND", &[])));
+    }
+
+    #[test]
+    fn strip_webp_should_remove_metadata() {
+        let mut chunks = webp_chunk(b"VP8X", &[0x2C, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        chunks.extend(webp_chunk(b"ICCP", b"sRGB"));
+        chunks.extend(webp_chunk(b"VP8 ", &[1, 2, 3]));
+        chunks.extend(webp_chunk(b"EXIF", b"MM\0\x2aGPS"));
+        chunks.extend(webp_chunk(b"XMP ", b"<x:xmpmeta/>"));
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+        data.extend_from_slice(b"WEBP");
+        data.extend(chunks);
+
+        let stripped = strip_metadata("webp", &data).unwrap().unwrap();
+        assert!(!contains(&stripped, b"GPS"));
+        assert!(!contains(&stripped, b"xmpmeta"));
+        // only the ICC flag is left
+        assert_eq!(stripped[20], 0x20);
+        let riff_len = u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize;
+        assert_eq!(riff_len + 8, stripped.len());
+    }
+
+    #[test]
+    fn strip_metadata_should_reject_malformed_images() {
+        let data = jpeg_with_exif(6);
+        assert!(strip_metadata("jpg", &data[..30]).is_err());
+        assert!(strip_metadata("png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").is_err());
+        assert!(strip_metadata("txt", b"hello").unwrap().is_none());
+    }
+}
@@ -5,6 +5,8 @@ use axum::{
     response::{IntoResponse, Response},
 };
 
+use chat_core::TokenClaims;
+
 use crate::{AppState, User};
 
 #[allow(unused)]
@@ -51,10 +53,36 @@ pub async fn verify_chat(State(state): State<AppState>, mut req: Request, next:
         }
     }
 
+    // personal access tokens may be limited to some chats
+    let allowed = parts
+        .extensions
+        .get::<TokenClaims>()
+        .is_none_or(|claims| claims.allows_chat(chat_id as _));
+    if !allowed {
+        return (
+            StatusCode::FORBIDDEN,
+            "The token is not allowed for this chat",
+        )
+            .into_response();
+    }
+
     let req = Request::from_parts(parts, body);
     next.run(req).await
 }
 
+/// Tokens limited to some chats can't be used for what isn't about a single
+/// chat, like creating chats or uploading files
+pub async fn reject_chat_limited(req: Request, next: Next) -> Response {
+    let limited = req
+        .extensions()
+        .get::<TokenClaims>()
+        .is_some_and(|claims| claims.chat_ids.is_some());
+    if limited {
+        return (StatusCode::FORBIDDEN, "The token is limited to some chats").into_response();
+    }
+    next.run(req).await
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -84,6 +112,7 @@ mod tests {
             ws_id: 1,
             fullname: "test".to_string(),
             password_hash: None,
+            verified_at: Some(Utc::now()),
             created_at: Utc::now(),
             updated_at: Utc::now(),
         };
@@ -97,10 +126,41 @@ mod tests {
 
         let mut req = Request::builder().uri("/api/1").body(Body::empty())?;
         // 为handler添加user
-        req.extensions_mut().insert(user);
+        req.extensions_mut().insert(user.clone());
         let res = app.clone().oneshot(req).await?;
         assert_eq!(res.status(), StatusCode::OK);
 
+        // an access token limited to other chats
+        let mut req = Request::builder().uri("/api/1").body(Body::empty())?;
+        req.extensions_mut().insert(user);
+        req.extensions_mut().insert(TokenClaims {
+            chat_ids: Some(vec![2]),
+            ..TokenClaims::new(1, 1, None)
+        });
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_chat_limited_should_only_let_unlimited_tokens_through() -> Result<()> {
+        let app = Router::new().route(
+            "/api/upload",
+            get(|| async { StatusCode::OK }).layer(axum::middleware::from_fn(reject_chat_limited)),
+        );
+        let request = |chat_ids: Option<Vec<i64>>| -> Result<Request> {
+            let mut req = Request::builder().uri("/api/upload").body(Body::empty())?;
+            req.extensions_mut().insert(TokenClaims {
+                chat_ids,
+                ..TokenClaims::new(1, 1, None)
+            });
+            Ok(req)
+        };
+        let res = app.clone().oneshot(request(None)?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.oneshot(request(Some(vec![1]))?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
         Ok(())
     }
 
@@ -120,6 +180,7 @@ mod tests {
             ws_id: 1,
             fullname: "test".to_string(),
             password_hash: None,
+            verified_at: Some(Utc::now()),
             created_at: Utc::now(),
             updated_at: Utc::now(),
         };
@@ -151,6 +212,7 @@ mod tests {
             ws_id: 1,
             fullname: "test".to_string(),
             password_hash: None,
+            verified_at: Some(Utc::now()),
             created_at: Utc::now(),
             updated_at: Utc::now(),
         };
@@ -0,0 +1,155 @@
+use axum::{
+    extract::{FromRequestParts, Query, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chat_core::{verify_token, TokenClaims};
+use tracing::warn;
+
+use crate::{models::FileUrlSignature, AppError, AppState};
+
+/// Authenticate a file download either by a signed url or, if the url is not
+/// signed, by the usual session token.
+pub async fn verify_file_url(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let Ok(Query(params)) = Query::<FileUrlSignature>::from_request_parts(&mut parts, &state).await
+    else {
+        let req = Request::from_parts(parts, body);
+        return verify_token::<AppState>(State(state), req, next).await;
+    };
+
+    // the route is nested under /api, the path here is the file url
+    let url = parts.uri.path().to_string();
+    let user_id = match state.file_url_verify(&url, &params) {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            warn!("file url verification failed: {}", e);
+            return e.into_response();
+        }
+    };
+    let user = match state.user_fetch_by_id(user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return AppError::NotFound(format!("User with id {} not found", user_id))
+                .into_response()
+        }
+        Err(e) => return e.into_response(),
+    };
+
+    // the url was only signed for a file the signing token could access
+    let claims = TokenClaims::new(user.id, user.ws_id, None);
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(user);
+    req.extensions_mut().insert(claims);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::{
+        body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Extension,
+        Router,
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{models::CreateAccessToken, User};
+    use axum::handler::Handler;
+    use chat_core::{require_scope, Scope};
+
+    async fn handler(Extension(user): Extension<User>) -> impl IntoResponse {
+        (StatusCode::OK, user.email)
+    }
+
+    fn app(state: AppState) -> Router {
+        let files_read = from_fn_with_state(Scope::FilesRead, require_scope);
+        let files = Router::new().route(
+            "/files/{ws_id}/{*file_url}",
+            get(handler.layer(files_read))
+                .layer(from_fn_with_state(state.clone(), verify_file_url))
+                .with_state(state),
+        );
+        Router::new().nest("/api", files)
+    }
+
+    #[tokio::test]
+    async fn verify_file_url_should_accept_signed_url() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let url = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt";
+        let signed = state.file_url_sign(url, 1)?;
+
+        let req = Request::builder()
+            .uri(signed.signed_url)
+            .body(Body::empty())?;
+        let res = app(state).oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(body, "test@yahoo.com");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_file_url_should_reject_url_signed_for_another_file() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let url = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt";
+        let signed = state.file_url_sign(url, 1)?;
+        let (_, query) = signed.signed_url.split_once('?').unwrap();
+
+        let req = Request::builder()
+            .uri(format!(
+                "/api/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt?{}",
+                query
+            ))
+            .body(Body::empty())?;
+        let res = app(state).oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_file_url_should_require_token_when_unsigned() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let req = Request::builder()
+            .uri("/api/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt")
+            .body(Body::empty())?;
+        let res = app(state).oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_file_url_should_require_files_read_when_unsigned() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = |scopes: Vec<Scope>| {
+            let state = state.clone();
+            async move {
+                let input = CreateAccessToken {
+                    name: "bot".to_string(),
+                    scopes,
+                    chat_ids: None,
+                    expires_in_days: None,
+                };
+                state
+                    .access_token_create(&TokenClaims::new(1, 1, None), input)
+                    .await
+                    .map(|created| created.token)
+            }
+        };
+        let request = |token: String| {
+            Request::builder()
+                .uri("/api/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+
+        let chats_only = token(vec![Scope::ChatsRead]).await?;
+        let res = app(state.clone()).oneshot(request(chats_only)?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let files = token(vec![Scope::FilesRead]).await?;
+        let res = app(state).oneshot(request(files)?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
 mod chat;
+mod file;
 
-pub use chat::verify_chat;
+pub use chat::{reject_chat_limited, verify_chat};
+pub use file::verify_file_url;
@@ -0,0 +1,315 @@
+use chat_core::{Scope, TokenClaims};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+
+use super::token::{generate_token, hash_token};
+use crate::{AppError, AppState};
+
+/// Personal access tokens start with this so they're told apart from JWTs
+/// and easy to spot when leaked
+pub const ACCESS_TOKEN_PREFIX: &str = "chat_pat_";
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PersonalAccessToken {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // `None` for every chat the user is a member of
+    pub chat_ids: Option<Vec<i64>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateAccessToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub chat_ids: Option<Vec<i64>>,
+    // never expires if not set
+    #[serde(default)]
+    pub expires_in_days: Option<u32>,
+}
+
+/// The token is only returned when it is created
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedAccessToken {
+    #[serde(flatten)]
+    pub meta: PersonalAccessToken,
+    pub token: String,
+}
+
+#[derive(Debug, FromRow)]
+struct AccessTokenRow {
+    id: i64,
+    name: String,
+    scopes: Vec<String>,
+    chat_ids: Option<Vec<i64>>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, FromRow)]
+struct AccessTokenGrant {
+    user_id: i64,
+    ws_id: i64,
+    scopes: Vec<String>,
+    chat_ids: Option<Vec<i64>>,
+}
+
+impl From<AccessTokenRow> for PersonalAccessToken {
+    fn from(row: AccessTokenRow) -> Self {
+        Self {
+            id: row.id,
+            name: row.name,
+            scopes: parse_scopes(&row.scopes),
+            chat_ids: row.chat_ids,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+            created_at: row.created_at,
+        }
+    }
+}
+
+impl AppState {
+    /// Create a token with at most the scopes of the token creating it.
+    /// Account management is never granted, tokens can't create tokens.
+    pub async fn access_token_create(
+        &self,
+        claims: &TokenClaims,
+        input: CreateAccessToken,
+    ) -> Result<CreatedAccessToken, AppError> {
+        let name = input.name.trim();
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(AppError::AccessTokenError(format!(
+                "name must be 1 to {} characters",
+                MAX_NAME_LEN
+            )));
+        }
+        if input.scopes.is_empty() {
+            return Err(AppError::AccessTokenError(
+                "at least one scope is required".to_string(),
+            ));
+        }
+        if input.scopes.contains(&Scope::Account) {
+            return Err(AppError::AccessTokenError(
+                "access tokens can't manage the account".to_string(),
+            ));
+        }
+        if let Some(scope) = input.scopes.iter().find(|scope| !claims.has_scope(**scope)) {
+            return Err(AppError::PermissionDenied(format!(
+                "can't grant scope {} you don't have",
+                scope
+            )));
+        }
+        for chat_id in input.chat_ids.iter().flatten() {
+            if !self
+                .chat_is_member(*chat_id as _, claims.user_id as _)
+                .await?
+            {
+                return Err(AppError::PermissionDenied(format!(
+                    "not a member of chat {}",
+                    chat_id
+                )));
+            }
+        }
+
+        let token = format!("{}{}", ACCESS_TOKEN_PREFIX, generate_token());
+        let expires_at = input
+            .expires_in_days
+            .map(|days| Utc::now() + chrono::Duration::days(days as _));
+        let scopes: Vec<&str> = input.scopes.iter().map(Scope::as_str).collect();
+        let row: AccessTokenRow = sqlx::query_as(
+            r#"
+            INSERT INTO personal_access_tokens
+                (user_id, ws_id, name, token_hash, scopes, chat_ids, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, name, scopes, chat_ids, expires_at, last_used_at, created_at
+            "#,
+        )
+        .bind(claims.user_id)
+        .bind(claims.ws_id)
+        .bind(name)
+        .bind(hash_token(&token))
+        .bind(&scopes)
+        .bind(&input.chat_ids)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(CreatedAccessToken {
+            meta: row.into(),
+            token,
+        })
+    }
+
+    pub async fn access_token_list(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<PersonalAccessToken>, AppError> {
+        let rows: Vec<AccessTokenRow> = sqlx::query_as(
+            r#"
+            SELECT id, name, scopes, chat_ids, expires_at, last_used_at, created_at
+            FROM personal_access_tokens
+            WHERE user_id = $1 AND revoked_at IS NULL
+            ORDER BY id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    /// Returns false if the user has no such token
+    pub async fn access_token_revoke(&self, user_id: i64, id: i64) -> Result<bool, AppError> {
+        let revoked = sqlx::query(
+            r#"
+            UPDATE personal_access_tokens SET revoked_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(revoked > 0)
+    }
+
+    /// The claims of a valid access token, its use is recorded
+    pub async fn access_token_verify(&self, token: &str) -> Result<TokenClaims, AppError> {
+        let grant: Option<AccessTokenGrant> = sqlx::query_as(
+            r#"
+            UPDATE personal_access_tokens SET last_used_at = CURRENT_TIMESTAMP
+            WHERE token_hash = $1 AND revoked_at IS NULL
+            AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+            RETURNING user_id, ws_id, scopes, chat_ids
+            "#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(grant) = grant else {
+            return Err(AppError::InvalidToken(
+                "invalid, expired or revoked access token".to_string(),
+            ));
+        };
+        Ok(TokenClaims {
+            scopes: parse_scopes(&grant.scopes),
+            chat_ids: grant.chat_ids,
+            ..TokenClaims::new(grant.user_id, grant.ws_id, None)
+        })
+    }
+}
+
+// scopes that no longer exist aren't granted
+fn parse_scopes(scopes: &[String]) -> Vec<Scope> {
+    scopes
+        .iter()
+        .filter_map(|scope| scope.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chat_core::TokenVerify;
+
+    use super::*;
+
+    fn input(scopes: Vec<Scope>, chat_ids: Option<Vec<i64>>) -> CreateAccessToken {
+        CreateAccessToken {
+            name: "deploy bot".to_string(),
+            scopes,
+            chat_ids,
+            expires_in_days: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn access_token_should_be_accepted_until_revoked() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let claims = TokenClaims::new(1, 1, None);
+        let created = state
+            .access_token_create(&claims, input(vec![Scope::ChatsWrite], Some(vec![1])))
+            .await?;
+        assert!(created.token.starts_with(ACCESS_TOKEN_PREFIX));
+
+        let verified = state.verify(&created.token).await?;
+        assert_eq!(verified.user_id, 1);
+        assert_eq!(verified.session_id, None);
+        assert!(verified.has_scope(Scope::ChatsWrite));
+        assert!(!verified.has_scope(Scope::ChatsRead));
+        assert!(verified.allows_chat(1) && !verified.allows_chat(2));
+        assert_eq!(state.user(&verified).await?.id, 1);
+
+        let tokens = state.access_token_list(1).await?;
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].last_used_at.is_some());
+        assert!(state.access_token_list(2).await?.is_empty());
+
+        assert!(!state.access_token_revoke(2, created.meta.id).await?);
+        assert!(state.access_token_revoke(1, created.meta.id).await?);
+        assert!(state.verify(&created.token).await.is_err());
+        assert!(state.access_token_list(1).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn access_token_create_should_limit_grants() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let claims = TokenClaims {
+            scopes: vec![Scope::ChatsRead, Scope::Account],
+            ..TokenClaims::new(1, 1, None)
+        };
+        let ret = state
+            .access_token_create(&claims, input(vec![Scope::Account], None))
+            .await;
+        assert!(matches!(ret, Err(AppError::AccessTokenError(_))));
+        let ret = state
+            .access_token_create(&claims, input(vec![Scope::FilesWrite], None))
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        // user 1 isn't a member of every chat
+        let chats = sqlx::query_as::<_, (i64,)>(
+            "SELECT id FROM chats WHERE NOT (1 = ANY(members)) LIMIT 1",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        let ret = state
+            .access_token_create(&claims, input(vec![Scope::ChatsRead], Some(vec![chats.0])))
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state
+            .access_token_create(&claims, input(vec![], None))
+            .await;
+        assert!(matches!(ret, Err(AppError::AccessTokenError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn access_token_should_expire() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let claims = TokenClaims::new(1, 1, None);
+        let created = state
+            .access_token_create(
+                &claims,
+                CreateAccessToken {
+                    expires_in_days: Some(30),
+                    ..input(vec![Scope::FilesWrite], None)
+                },
+            )
+            .await?;
+        assert!(created.meta.expires_at.is_some());
+        sqlx::query("UPDATE personal_access_tokens SET expires_at = CURRENT_TIMESTAMP - INTERVAL '1 second'")
+            .execute(&state.pool)
+            .await?;
+        let ret = state.verify(&created.token).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        Ok(())
+    }
+}
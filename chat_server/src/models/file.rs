@@ -1,17 +1,183 @@
-use std::path::{Path, PathBuf};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use super::ChatFile;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Postgres, Transaction};
+use tracing::{info, warn};
 
-use sha1::{Digest, Sha1};
+use super::{is_valid_ext, ChatFile};
+use crate::{
+    metadata::{strip_metadata, STRIPPABLE_EXTS},
+    AppError, AppState, FileScanner, ScanVerdict,
+};
+
+use sha2::{Digest, Sha256};
+
+// wait this long before scanning a quarantined file again after the scanner failed
+const SCAN_RETRY_DELAYS: [Duration; 2] = [Duration::from_secs(1), Duration::from_secs(5)];
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct FileMeta {
+    pub id: i64,
+    pub ws_id: i64,
+    pub path: String,
+    pub hash: String,
+    pub ext: String,
+    pub size: i64,
+    pub content_type: String,
+    pub scan_status: FileScanStatus,
+    pub scan_result: Option<String>,
+    pub scanned_at: Option<DateTime<Utc>>,
+    pub text_extracted_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "file_scan_status", rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum FileScanStatus {
+    // no scanner configured when uploaded
+    Unscanned,
+    // quarantined until the scan passes
+    Pending,
+    Clean,
+    Infected,
+    // the scanner kept failing, quarantined until uploaded again
+    Failed,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileGcReport {
+    pub dry_run: bool,
+    // urls of the collected (or collectable in dry run mode) files
+    pub files: Vec<String>,
+    pub reclaimed_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FileCategory {
+    Images,
+    Documents,
+    Media,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListChatFiles {
+    pub category: Option<FileCategory>,
+    // a user who uploaded the file
+    pub uploader: Option<u64>,
+    pub last_id: Option<u64>,
+    pub limit: u64,
+}
+
+/// A file posted in a chat, with the message it was posted in
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ChatFileItem {
+    pub message_id: i64,
+    pub sender_id: i64,
+    pub url: String,
+    pub ext: String,
+    pub content_type: Option<String>,
+    pub size: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl FileCategory {
+    pub fn exts(&self) -> &'static [&'static str] {
+        match self {
+            FileCategory::Images => &[
+                "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "bmp", "tif", "tiff", "avif",
+            ],
+            FileCategory::Documents => &[
+                "pdf", "txt", "md", "csv", "json", "yml", "yaml", "doc", "docx", "xls", "xlsx",
+                "ppt", "pptx", "odt", "ods", "odp", "rtf", "epub",
+            ],
+            FileCategory::Media => &[
+                "mp3", "m4a", "wav", "ogg", "flac", "aac", "mp4", "mov", "webm", "mkv", "avi",
+            ],
+        }
+    }
+
+    /// `LIKE` patterns of the sniffed content types in the category
+    pub fn content_types(&self) -> &'static [&'static str] {
+        match self {
+            FileCategory::Images => &["image/%"],
+            FileCategory::Documents => &[
+                "text/%",
+                "application/pdf",
+                "application/rtf",
+                "application/epub+zip",
+                "application/msword",
+                "application/vnd.ms-%",
+                "application/vnd.openxmlformats-officedocument.%",
+                "application/vnd.oasis.opendocument.%",
+            ],
+            FileCategory::Media => &["audio/%", "video/%"],
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct GcCandidate {
+    path: String,
+    updated_at: Option<DateTime<Utc>>,
+}
+
+pub(super) struct StoredBlob {
+    pub(super) url: String,
+    pub(super) path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
 
 impl ChatFile {
-    pub fn new(ws_id: u64, filename: String, data: &[u8]) -> Self {
-        let hash = Sha1::digest(data);
-        let ext = filename.rsplit('.').next().unwrap_or("txt");
-        Self {
+    /// The extension comes from the content if its type can be sniffed, the
+    /// filename is only trusted for formats without magic bytes, e.g. text.
+    pub fn new(ws_id: u64, filename: String, data: &[u8]) -> Result<Self, AppError> {
+        let ext = Self::sniff_ext(&filename, data);
+        if !is_valid_ext(&ext) {
+            return Err(AppError::InvalidChatFilePath(format!(
+                "Invalid file extension {}",
+                ext
+            )));
+        }
+        Ok(Self {
             ws_id,
-            ext: ext.to_string(),
-            hash: hex::encode(hash),
+            ext,
+            hash: Self::hash(data),
+        })
+    }
+
+    pub fn hash(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    pub fn sniff_ext(filename: &str, data: &[u8]) -> String {
+        match infer::get(data) {
+            Some(kind) => kind.extension().to_string(),
+            None => match filename.rsplit_once('.') {
+                Some((_, ext)) => ext.to_ascii_lowercase(),
+                None => "bin".to_string(),
+            },
+        }
+    }
+
+    /// Content type sniffed from magic bytes. Markup (html, xml, ...) and other
+    /// text is always served as plain text so uploads can't run scripts.
+    pub fn content_type(data: &[u8]) -> String {
+        match infer::get(data) {
+            Some(kind) if kind.matcher_type() != infer::MatcherType::Text => {
+                kind.mime_type().to_string()
+            }
+            _ if std::str::from_utf8(data).is_ok() => "text/plain; charset=utf-8".to_string(),
+            _ => "application/octet-stream".to_string(),
         }
     }
 
@@ -35,23 +201,1180 @@ impl ChatFile {
     }
 }
 
+impl AppState {
+    /// Record an uploaded file. Uploading the same content again in the same
+    /// workspace resolves to the same row and restarts its gc grace period.
+    /// The scan status of an existing file is only replaced if it was never scanned.
+    pub async fn file_create(
+        &self,
+        file: &ChatFile,
+        data: &[u8],
+        uploader_id: u64,
+        scan_status: FileScanStatus,
+    ) -> Result<FileMeta, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let meta: FileMeta = sqlx::query_as(
+            r#"
+            INSERT INTO files (ws_id, path, hash, ext, size, content_type, scan_status)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (path) DO UPDATE SET
+                updated_at = CURRENT_TIMESTAMP,
+                scan_status = CASE
+                    WHEN files.scan_status IN ('unscanned', 'failed') THEN excluded.scan_status
+                    ELSE files.scan_status
+                END,
+                scan_result = CASE
+                    WHEN files.scan_status = 'failed' THEN NULL
+                    ELSE files.scan_result
+                END,
+                scanned_at = CASE
+                    WHEN files.scan_status = 'failed' THEN NULL
+                    ELSE files.scanned_at
+                END
+            RETURNING *
+            "#,
+        )
+        .bind(file.ws_id as i64)
+        .bind(file.url())
+        .bind(&file.hash)
+        .bind(&file.ext)
+        .bind(data.len() as i64)
+        .bind(ChatFile::content_type(data))
+        .bind(scan_status)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO file_uploads (file_id, user_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(meta.id)
+        .bind(uploader_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(meta)
+    }
+
+    /// Store an uploaded file. Image metadata is stripped first, so the hash is
+    /// that of the stored content. With a scanner configured the content is
+    /// either scanned right away, or, in quarantine mode, stored as pending and
+    /// scanned in the background.
+    pub async fn file_upload(
+        &self,
+        ws_id: u64,
+        uploader_id: u64,
+        filename: String,
+        data: &[u8],
+    ) -> Result<FileMeta, AppError> {
+        let stripped = self.file_strip_metadata(ws_id, &filename, data).await?;
+        let data = stripped.as_deref().unwrap_or(data);
+        let file = ChatFile::new(ws_id, filename, data)?;
+        self.file_check_ext(&file).await?;
+        let existing = self.file_fetch_by_url(&file.url()).await?;
+        let existing_status = existing.map(|meta| (meta.scan_status, meta.scan_result));
+        if let Some((FileScanStatus::Infected, signature)) = existing_status {
+            return Err(AppError::FileInfected(signature.unwrap_or_default()));
+        }
+
+        let quarantine = self
+            .config
+            .files
+            .scanner
+            .as_ref()
+            .is_some_and(|c| c.quarantine);
+        let verdict = match (&self.scanner, existing_status) {
+            // same content was scanned before
+            (Some(_), Some((FileScanStatus::Clean, _))) => Some(ScanVerdict::Clean),
+            (Some(scanner), _) if !quarantine => Some(scanner.scan(data).await?),
+            _ => None,
+        };
+        if let Some(ScanVerdict::Infected(signature)) = verdict {
+            warn!("rejected infected upload {}: {}", file.url(), signature);
+            self.file_create(&file, data, uploader_id, FileScanStatus::Infected)
+                .await?;
+            self.file_scan_update(&file.url(), FileScanStatus::Infected, Some(&signature))
+                .await?;
+            return Err(AppError::FileInfected(signature));
+        }
+
+        // the gc can't remove the blob between checking that it exists and
+        // recording the upload
+        let lock = self.file_lock(&file.url()).await?;
+        let path = file.path(&self.config.server.base_dir);
+        if path.exists() {
+            info!("File already exists: {}", path.display());
+        } else {
+            tokio::fs::create_dir_all(path.parent().expect("file path parent should exist"))
+                .await?;
+            tokio::fs::write(&path, data).await?;
+        }
+
+        let status = match (&self.scanner, verdict) {
+            (_, Some(_)) => FileScanStatus::Clean,
+            (Some(_), None) => FileScanStatus::Pending,
+            (None, None) => FileScanStatus::Unscanned,
+        };
+        let meta = self.file_create(&file, data, uploader_id, status).await?;
+        lock.commit().await?;
+        // quarantined files are extracted once they pass the scan
+        if self.config.files.extract_text
+            && meta.text_extracted_at.is_none()
+            && matches!(
+                meta.scan_status,
+                FileScanStatus::Clean | FileScanStatus::Unscanned
+            )
+        {
+            self.file_extract_spawn(file.clone(), data.to_vec());
+        }
+        match (meta.scan_status, &self.scanner) {
+            (FileScanStatus::Clean, _) if meta.scanned_at.is_none() => {
+                return self
+                    .file_scan_update(&meta.path, FileScanStatus::Clean, None)
+                    .await;
+            }
+            (FileScanStatus::Pending, Some(scanner)) => {
+                self.file_scan_spawn(scanner.clone(), file, data.to_vec());
+            }
+            _ => {}
+        }
+        Ok(meta)
+    }
+
+    /// Strip metadata from an image unless disabled by config or by the
+    /// workspace for this file type. Returns `None` if the data is unchanged.
+    pub async fn file_strip_metadata(
+        &self,
+        ws_id: u64,
+        filename: &str,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>, AppError> {
+        let ext = ChatFile::sniff_ext(filename, data);
+        if !self.config.files.strip_metadata || !STRIPPABLE_EXTS.contains(&ext.as_str()) {
+            return Ok(None);
+        }
+        let settings = self.workspace_settings(ws_id).await?;
+        if settings
+            .keep_metadata_exts
+            .is_some_and(|exts| exts.contains(&ext))
+        {
+            return Ok(None);
+        }
+        strip_metadata(&ext, data)
+    }
+
+    fn file_scan_spawn(&self, scanner: Arc<dyn FileScanner>, file: ChatFile, data: Vec<u8>) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            let url = file.url();
+            let mut verdict = scanner.scan(&data).await;
+            for delay in SCAN_RETRY_DELAYS {
+                let Err(e) = &verdict else {
+                    break;
+                };
+                warn!("failed to scan file {}, retrying: {}", url, e);
+                tokio::time::sleep(delay).await;
+                verdict = scanner.scan(&data).await;
+            }
+            let ret = match verdict {
+                Ok(ScanVerdict::Clean) => {
+                    let ret = state
+                        .file_scan_update(&url, FileScanStatus::Clean, None)
+                        .await;
+                    if let Ok(meta) = &ret {
+                        if state.config.files.extract_text && meta.text_extracted_at.is_none() {
+                            state.file_extract_spawn(file, data);
+                        }
+                    }
+                    ret
+                }
+                Ok(ScanVerdict::Infected(signature)) => {
+                    warn!("quarantined file {} is infected: {}", url, signature);
+                    let path = file.path(&state.config.server.base_dir);
+                    if let Err(e) = tokio::fs::remove_file(&path).await {
+                        warn!("failed to remove file {}: {}", path.display(), e);
+                    }
+                    state
+                        .file_scan_update(&url, FileScanStatus::Infected, Some(&signature))
+                        .await
+                }
+                // keep the file quarantined until it is uploaded again, the
+                // error is kept for inspection
+                Err(e) => {
+                    warn!("failed to scan file {}: {}", url, e);
+                    state
+                        .file_scan_update(&url, FileScanStatus::Failed, Some(&e.to_string()))
+                        .await
+                }
+            };
+            if let Err(e) = ret {
+                warn!("failed to update scan result of {}: {}", url, e);
+            }
+        });
+    }
+
+    pub async fn file_scan_update(
+        &self,
+        url: &str,
+        status: FileScanStatus,
+        result: Option<&str>,
+    ) -> Result<FileMeta, AppError> {
+        let meta = sqlx::query_as(
+            r#"
+            UPDATE files
+            SET scan_status = $2, scan_result = $3, scanned_at = CURRENT_TIMESTAMP
+            WHERE path = $1
+            RETURNING *
+            "#,
+        )
+        .bind(url)
+        .bind(status)
+        .bind(result)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(meta)
+    }
+
+    /// Files held in quarantine, found infected or that couldn't be scanned
+    /// can't be downloaded or shared
+    pub async fn file_check_available(&self, url: &str) -> Result<(), AppError> {
+        let Some(meta) = self.file_fetch_by_url(url).await? else {
+            return Ok(());
+        };
+        match meta.scan_status {
+            FileScanStatus::Pending => Err(AppError::FileQuarantined(
+                "file is being scanned".to_string(),
+            )),
+            FileScanStatus::Failed => Err(AppError::FileQuarantined(
+                "file could not be scanned, upload it again".to_string(),
+            )),
+            FileScanStatus::Infected => {
+                Err(AppError::FileInfected(meta.scan_result.unwrap_or_default()))
+            }
+            FileScanStatus::Unscanned | FileScanStatus::Clean => Ok(()),
+        }
+    }
+
+    /// Parse an untrusted file url. Sha1 urls of migrated files resolve to
+    /// their sha256 counterpart.
+    pub async fn file_parse(&self, url: &str) -> Result<ChatFile, AppError> {
+        let mut file: ChatFile = url.parse()?;
+        if file.is_legacy() {
+            if let Some(target) = self.file_alias(&file.url()).await? {
+                file = target.parse()?;
+            }
+        }
+        Ok(file)
+    }
+
+    /// Check the extension of an upload against the allowlist of its
+    /// workspace, files uploaded before the list changed stay usable
+    pub async fn file_check_ext(&self, file: &ChatFile) -> Result<(), AppError> {
+        let settings = self.workspace_settings(file.ws_id).await?;
+        let allowed = settings
+            .allowed_file_exts
+            .as_ref()
+            .unwrap_or(&self.config.files.allowed_exts);
+        if !allowed.contains(&file.ext) {
+            return Err(AppError::InvalidChatFilePath(format!(
+                "File extension {} is not allowed",
+                file.ext
+            )));
+        }
+        Ok(())
+    }
+
+    /// A user can access a file if they uploaded it, if it is referenced in
+    /// a message of a chat they are a member of, or if it is the avatar of a
+    /// user of their workspace. With `chat_ids`, e.g. for a token limited to
+    /// some chats, only files of messages in those chats are accessible.
+    pub async fn file_can_access(
+        &self,
+        url: &str,
+        user_id: u64,
+        chat_ids: Option<&[i64]>,
+    ) -> Result<bool, AppError> {
+        let (can_access,): (bool,) = sqlx::query_as(
+            r#"
+            SELECT ($3::bigint[] IS NULL AND EXISTS (
+                SELECT 1 FROM file_uploads fu
+                JOIN files f ON f.id = fu.file_id
+                WHERE f.path = $1 AND fu.user_id = $2
+            )) OR EXISTS (
+                SELECT 1 FROM message_files mf
+                JOIN messages m ON m.id = mf.message_id
+                JOIN chats c ON c.id = m.chat_id
+                WHERE mf.path = $1 AND $2 = ANY(c.members)
+                AND ($3::bigint[] IS NULL OR c.id = ANY($3))
+            ) OR ($3::bigint[] IS NULL AND EXISTS (
+                SELECT 1 FROM users u
+                JOIN users viewer ON viewer.ws_id = u.ws_id
+                WHERE u.avatar_url = $1 AND viewer.id = $2
+            ))
+            "#,
+        )
+        .bind(url)
+        .bind(user_id as i64)
+        .bind(chat_ids)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(can_access)
+    }
+
+    pub async fn file_fetch_by_url(&self, url: &str) -> Result<Option<FileMeta>, AppError> {
+        let meta = sqlx::query_as("SELECT * FROM files WHERE path = $1")
+            .bind(url)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(meta)
+    }
+
+    /// List the files posted in a chat, newest message first. Pages are cut at
+    /// message boundaries, so `last_id` is the id of the last message seen and
+    /// `limit` the number of messages per page.
+    pub async fn file_list_by_chat(
+        &self,
+        input: ListChatFiles,
+        chat_id: u64,
+    ) -> Result<Vec<ChatFileItem>, AppError> {
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let exts: Option<Vec<&str>> = input.category.map(|c| c.exts().to_vec());
+        let content_types: Option<Vec<&str>> = input.category.map(|c| c.content_types().to_vec());
+        // files uploaded before they were recorded fall back to their url and
+        // to the sender of the message
+        let files = sqlx::query_as(
+            r#"
+            WITH items AS (
+                SELECT m.id AS message_id, m.sender_id, mf.path AS url,
+                    COALESCE(f.ext, substring(mf.path from '\.([a-z0-9]+)$')) AS ext,
+                    f.content_type, f.size, m.created_at
+                FROM message_files mf
+                JOIN messages m ON m.id = mf.message_id
+                LEFT JOIN files f ON f.path = mf.path
+                WHERE m.chat_id = $1
+                AND m.id < $2
+                AND ($3::BIGINT IS NULL OR CASE
+                    WHEN f.id IS NULL THEN m.sender_id = $3
+                    ELSE EXISTS (
+                        SELECT 1 FROM file_uploads fu WHERE fu.file_id = f.id AND fu.user_id = $3
+                    )
+                END)
+                AND ($4::TEXT[] IS NULL OR CASE
+                    WHEN f.id IS NULL THEN substring(mf.path from '\.([a-z0-9]+)$') = ANY($4)
+                    ELSE f.content_type LIKE ANY($5)
+                END)
+            ), page AS (
+                SELECT DISTINCT message_id FROM items
+                ORDER BY message_id DESC
+                LIMIT $6
+            )
+            SELECT * FROM items
+            WHERE message_id IN (SELECT message_id FROM page)
+            ORDER BY message_id DESC, url
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(last_id as i64)
+        .bind(input.uploader.map(|id| id as i64))
+        .bind(exts)
+        .bind(content_types)
+        .bind(input.limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(files)
+    }
+
+    /// number of messages referencing the file
+    pub async fn file_ref_count(&self, url: &str) -> Result<i64, AppError> {
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM message_files WHERE path = $1")
+            .bind(url)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count)
+    }
+
+    /// Serialize uploads and the gc of a file, the lock is held until the
+    /// transaction ends
+    pub(crate) async fn file_lock(
+        &self,
+        url: &str,
+    ) -> Result<Transaction<'static, Postgres>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('files:' || $1))")
+            .bind(url)
+            .execute(&mut *tx)
+            .await?;
+        Ok(tx)
+    }
+
+    /// Delete stored files that no message references, that are nobody's
+    /// avatar and that have not been uploaded within `grace`. Blobs without a `files` row (uploaded before
+    /// files were tracked) fall back to their modification time.
+    /// If `ws_id` is given, only that workspace is collected.
+    pub async fn file_gc(
+        &self,
+        ws_id: Option<u64>,
+        grace: Duration,
+        dry_run: bool,
+    ) -> Result<FileGcReport, AppError> {
+        let base_dir = self.config.server.base_dir.clone();
+        let blobs = tokio::task::spawn_blocking(move || {
+            let root = match ws_id {
+                Some(ws_id) => base_dir.join(ws_id.to_string()),
+                None => base_dir.clone(),
+            };
+            let mut blobs = vec![];
+            collect_blobs(&base_dir, &root, &mut blobs)?;
+            Ok::<_, io::Error>(blobs)
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        let mut report = FileGcReport {
+            dry_run,
+            ..Default::default()
+        };
+        if blobs.is_empty() {
+            return Ok(report);
+        }
+
+        let urls: Vec<&str> = blobs.iter().map(|b| b.url.as_str()).collect();
+        let candidates: Vec<GcCandidate> = sqlx::query_as(
+            r#"
+            SELECT u.path, f.updated_at
+            FROM unnest($1::text[]) AS u(path)
+            LEFT JOIN files f ON f.path = u.path
+            WHERE NOT EXISTS (SELECT 1 FROM message_files mf WHERE mf.path = u.path)
+            AND NOT EXISTS (SELECT 1 FROM users WHERE avatar_url = u.path)
+            "#,
+        )
+        .bind(&urls)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let cutoff = Utc::now() - grace;
+        for candidate in candidates {
+            let Some(blob) = blobs.iter().find(|b| b.url == candidate.path) else {
+                continue;
+            };
+            let last_upload = candidate
+                .updated_at
+                .unwrap_or_else(|| DateTime::<Utc>::from(blob.modified));
+            if last_upload >= cutoff {
+                continue;
+            }
+
+            if !dry_run {
+                // re-check under the lock, the row is only gone once the blob is
+                let mut tx = self.file_lock(&blob.url).await?;
+                sqlx::query("DELETE FROM files WHERE path = $1 AND updated_at < $2")
+                    .bind(&blob.url)
+                    .bind(cutoff)
+                    .execute(&mut *tx)
+                    .await?;
+                let (in_use,): (bool,) = sqlx::query_as(
+                    r#"
+                    SELECT EXISTS (SELECT 1 FROM files WHERE path = $1)
+                    OR EXISTS (SELECT 1 FROM message_files WHERE path = $1)
+                    OR EXISTS (SELECT 1 FROM users WHERE avatar_url = $1)
+                    "#,
+                )
+                .bind(&blob.url)
+                .fetch_one(&mut *tx)
+                .await?;
+                if in_use {
+                    continue;
+                }
+                if let Err(e) = tokio::fs::remove_file(&blob.path).await {
+                    warn!("failed to remove file {}: {}", blob.path.display(), e);
+                    continue;
+                }
+                tx.commit().await?;
+                info!("file gc removed {}", blob.url);
+            }
+
+            report.files.push(blob.url.clone());
+            report.reclaimed_bytes += blob.size;
+        }
+
+        Ok(report)
+    }
+
+    /// spawn the periodic file gc if `files.gc_interval_secs` is set
+    pub fn file_gc_schedule(&self) {
+        let interval = self.config.files.gc_interval_secs;
+        if interval == 0 {
+            return;
+        }
+        let state = self.clone();
+        tokio::spawn(async move {
+            let grace = Duration::from_secs(state.config.files.gc_grace_secs);
+            let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+            loop {
+                ticker.tick().await;
+                match state.file_gc(None, grace, false).await {
+                    Ok(report) => info!(
+                        "file gc collected {} files, {} bytes",
+                        report.files.len(),
+                        report.reclaimed_bytes
+                    ),
+                    Err(e) => warn!("file gc failed: {}", e),
+                }
+            }
+        });
+    }
+}
+
+// walk `dir` and collect every blob that maps back to a valid chat file url
+pub(super) fn collect_blobs(
+    base_dir: &Path,
+    dir: &Path,
+    out: &mut Vec<StoredBlob>,
+) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_blobs(base_dir, &path, out)?;
+            continue;
+        }
+        let Ok(rel) = path.strip_prefix(base_dir) else {
+            continue;
+        };
+        let url = format!("/files/{}", rel.to_string_lossy());
+        let Ok(file) = url.parse::<ChatFile>() else {
+            continue;
+        };
+        out.push(StoredBlob {
+            url: file.url(),
+            path,
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        });
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        config::ScannerConfig,
+        metadata::tests::jpeg_with_exif,
+        models::{file_text::tests::wait_for_text, CreateMessage, UpdateWorkspaceSettings},
+        scanner::{
+            tests::{fake_clamd, EICAR},
+            ClamdScanner,
+        },
+    };
+
+    async fn with_scanner(state: AppState, quarantine: bool) -> anyhow::Result<AppState> {
+        let config = ScannerConfig {
+            clamd_addr: fake_clamd().await?.to_string(),
+            quarantine,
+            timeout_secs: 5,
+        };
+        let mut inner = (*state.inner).clone();
+        inner.scanner = Some(Arc::new(ClamdScanner::new(&config)));
+        inner.config.files.scanner = Some(config);
+        Ok(AppState {
+            inner: Arc::new(inner),
+        })
+    }
+
+    async fn wait_for_scan(state: &AppState, url: &str) -> Result<FileMeta, AppError> {
+        for _ in 0..50 {
+            let meta = state.file_fetch_by_url(url).await?.unwrap();
+            if meta.scan_status != FileScanStatus::Pending {
+                return Ok(meta);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("file {} was not scanned", url);
+    }
 
     #[test]
     fn test_hash_to_path() {
-        let file = ChatFile::new(1, "test.txt".to_string(), b"hello");
+        let file = ChatFile::new(1, "test.txt".to_string(), b"hello").unwrap();
         assert_eq!(
             file.hash_to_path(),
-            "1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt"
+            "1/2cf/24d/ba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824.txt"
         );
     }
 
     #[test]
     fn test_new_should_work() {
-        let file = ChatFile::new(1, "test.txt".to_string(), b"hello");
+        let file = ChatFile::new(1, "test.txt".to_string(), b"hello").unwrap();
         assert_eq!(file.ext, "txt");
-        assert_eq!(file.hash, "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d");
+        assert_eq!(
+            file.hash,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+
+    #[test]
+    fn test_new_should_use_sniffed_extension() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let file = ChatFile::new(1, "photo.txt".to_string(), png).unwrap();
+        assert_eq!(file.ext, "png");
+        assert_eq!(ChatFile::content_type(png), "image/png");
+
+        let file = ChatFile::new(1, "README.MD".to_string(), b"# hello").unwrap();
+        assert_eq!(file.ext, "md");
+        assert!(ChatFile::new(1, "evil.p/hp".to_string(), b"<?php").is_err());
+    }
+
+    #[test]
+    fn test_content_type_should_not_trust_markup() {
+        let html = b"<html><script>alert(1)</script></html>";
+        assert_eq!(ChatFile::content_type(html), "text/plain; charset=utf-8");
+        assert_eq!(
+            ChatFile::content_type(&[0xff, 0xfe, 0x00]),
+            "application/octet-stream"
+        );
+    }
+
+    async fn store_file(state: &AppState, data: &[u8]) -> Result<ChatFile, AppError> {
+        let file = ChatFile::new(1, "test.txt".to_string(), data)?;
+        let path = file.path(&state.config.server.base_dir);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, data)?;
+        state
+            .file_create(&file, data, 1, FileScanStatus::Unscanned)
+            .await?;
+        Ok(file)
+    }
+
+    #[tokio::test]
+    async fn test_file_create_should_dedup() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(1, "test.txt".to_string(), b"hello")?;
+        let meta = state
+            .file_create(&file, b"hello", 1, FileScanStatus::Unscanned)
+            .await?;
+        let meta1 = state
+            .file_create(&file, b"hello", 2, FileScanStatus::Unscanned)
+            .await?;
+        assert_eq!(meta.content_type, "text/plain; charset=utf-8");
+        assert_eq!(meta.id, meta1.id);
+        assert_eq!(meta.path, file.url());
+        assert!(meta1.updated_at >= meta.updated_at);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_can_access_should_follow_chat_membership() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // referenced in chat 1, members are 1 and 2
+        let file: ChatFile = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt".parse()?;
+        assert!(state.file_can_access(&file.url(), 1, None).await?);
+        assert!(state.file_can_access(&file.url(), 2, None).await?);
+        assert!(!state.file_can_access(&file.url(), 6, None).await?);
+
+        // uploaded by user 1 but not shared yet
+        let file = store_file(&state, b"private").await?;
+        assert!(state.file_can_access(&file.url(), 1, None).await?);
+        assert!(!state.file_can_access(&file.url(), 2, None).await?);
+
+        // shared in chat 3, members are 1, 2 and 6
+        state
+            .message_create(
+                CreateMessage {
+                    content: "".to_string(),
+                    files: vec![file.url()],
+                    urgent: false,
+                },
+                1,
+                3,
+            )
+            .await?;
+        assert!(state.file_can_access(&file.url(), 6, None).await?);
+        assert!(!state.file_can_access(&file.url(), 3, None).await?);
+
+        // tokens limited to some chats only reach the files of those chats,
+        // not even the user's own uploads
+        assert!(state.file_can_access(&file.url(), 1, Some(&[3])).await?);
+        assert!(!state.file_can_access(&file.url(), 1, Some(&[1])).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_upload_should_scan_before_storing() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let state = with_scanner(state, false).await?;
+
+        let meta = state
+            .file_upload(1, 1, "clean.txt".to_string(), b"clean")
+            .await?;
+        assert_eq!(meta.scan_status, FileScanStatus::Clean);
+        assert!(meta.scanned_at.is_some());
+        state.file_check_available(&meta.path).await?;
+
+        let ret = state
+            .file_upload(1, 1, "eicar.txt".to_string(), EICAR)
+            .await;
+        assert!(matches!(ret, Err(AppError::FileInfected(sig)) if sig == "Eicar-Test-Signature"));
+        let file = ChatFile::new(1, "eicar.txt".to_string(), EICAR)?;
+        assert!(!file.path(&state.config.server.base_dir).exists());
+        let meta = state.file_fetch_by_url(&file.url()).await?.unwrap();
+        assert_eq!(meta.scan_status, FileScanStatus::Infected);
+        assert_eq!(meta.scan_result.as_deref(), Some("Eicar-Test-Signature"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_upload_should_fail_the_scan_while_the_scanner_is_down() -> anyhow::Result<()>
+    {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let config = ScannerConfig {
+            clamd_addr: listener.local_addr()?.to_string(),
+            quarantine: true,
+            timeout_secs: 5,
+        };
+        drop(listener);
+        let mut inner = (*state.inner).clone();
+        inner.scanner = Some(Arc::new(ClamdScanner::new(&config)));
+        inner.config.files.scanner = Some(config);
+        let down = AppState {
+            inner: Arc::new(inner),
+        };
+
+        let meta = down
+            .file_upload(1, 1, "notes.txt".to_string(), b"notes")
+            .await?;
+        // retried before giving up
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let pending = down.file_fetch_by_url(&meta.path).await?.unwrap();
+        assert_eq!(pending.scan_status, FileScanStatus::Pending);
+        tokio::time::sleep(SCAN_RETRY_DELAYS.iter().sum()).await;
+        let meta = wait_for_scan(&down, &meta.path).await?;
+        assert_eq!(meta.scan_status, FileScanStatus::Failed);
+        assert!(meta.scan_result.is_some());
+        assert!(matches!(
+            down.file_check_available(&meta.path).await,
+            Err(AppError::FileQuarantined(_))
+        ));
+
+        // uploading it again scans it again
+        let state = with_scanner(state, true).await?;
+        let meta = state
+            .file_upload(1, 1, "notes.txt".to_string(), b"notes")
+            .await?;
+        assert_eq!(meta.scan_status, FileScanStatus::Pending);
+        let meta = wait_for_scan(&state, &meta.path).await?;
+        assert_eq!(meta.scan_status, FileScanStatus::Clean);
+        assert_eq!(meta.scan_result, None);
+        state.file_check_available(&meta.path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_upload_should_quarantine_until_scanned() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let state = with_scanner(state, true).await?;
+
+        let meta = state
+            .file_upload(1, 1, "clean.txt".to_string(), b"clean")
+            .await?;
+        assert_eq!(meta.scan_status, FileScanStatus::Pending);
+        let meta = wait_for_scan(&state, &meta.path).await?;
+        assert_eq!(meta.scan_status, FileScanStatus::Clean);
+        state.file_check_available(&meta.path).await?;
+        // the text is extracted once the file passed
+        wait_for_text(&state, &meta.path).await?;
+
+        let meta = state
+            .file_scan_update(&meta.path, FileScanStatus::Pending, None)
+            .await?;
+        assert!(matches!(
+            state.file_check_available(&meta.path).await,
+            Err(AppError::FileQuarantined(_))
+        ));
+
+        let meta = state
+            .file_upload(1, 1, "eicar.txt".to_string(), EICAR)
+            .await?;
+        assert_eq!(meta.scan_status, FileScanStatus::Pending);
+        let meta = wait_for_scan(&state, &meta.path).await?;
+        assert_eq!(meta.scan_status, FileScanStatus::Infected);
+        assert!(matches!(
+            state.file_check_available(&meta.path).await,
+            Err(AppError::FileInfected(_))
+        ));
+        assert!(meta.text_extracted_at.is_none());
+        let file: ChatFile = meta.path.parse()?;
+        assert!(!file.path(&state.config.server.base_dir).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_check_ext_should_use_workspace_allowlist() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let url = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt";
+        let txt = state.file_parse(url).await?;
+        state.file_check_ext(&txt).await?;
+
+        let exe = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.exe";
+        assert!(matches!(
+            state.file_check_ext(&state.file_parse(exe).await?).await,
+            Err(AppError::InvalidChatFilePath(msg)) if msg == "File extension exe is not allowed"
+        ));
+
+        state
+            .workspace_settings_update(
+                1,
+                UpdateWorkspaceSettings {
+                    allowed_file_exts: Some(Some(vec!["exe".to_string()])),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        state.file_check_ext(&state.file_parse(exe).await?).await?;
+        assert!(state.file_check_ext(&txt).await.is_err());
+        // files uploaded before the list changed can still be used
+        assert_eq!(state.file_parse(url).await?.url(), txt.url());
+        // other workspaces keep the default
+        let exe = "/files/2/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.exe";
+        assert!(state
+            .file_check_ext(&state.file_parse(exe).await?)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_upload_should_strip_image_metadata() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let data = jpeg_with_exif(6);
+        let meta = state
+            .file_upload(1, 1, "photo.jpg".to_string(), &data)
+            .await?;
+        let stored = fs::read(state.config.server.base_dir.join(&meta.path[7..]))?;
+        assert!(stored.len() < data.len());
+        assert_eq!(meta.size, stored.len() as i64);
+        assert!(!stored.windows(3).any(|w| w == b"GPS"));
+        assert_eq!(meta.hash, ChatFile::hash(&stored));
+
+        // the workspace opted out for jpg
+        state
+            .workspace_settings_update(
+                1,
+                UpdateWorkspaceSettings {
+                    keep_metadata_exts: Some(Some(vec!["jpg".to_string()])),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let kept = state
+            .file_upload(1, 1, "photo.jpg".to_string(), &data)
+            .await?;
+        assert_ne!(kept.path, meta.path);
+        assert_eq!(kept.size, data.len() as i64);
+
+        let ret = state
+            .file_upload(2, 1, "photo.jpg".to_string(), &data[..30])
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidImage(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_list_by_chat_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = ListChatFiles {
+            category: None,
+            uploader: None,
+            last_id: None,
+            limit: 3,
+        };
+        let files = state.file_list_by_chat(input.clone(), 1).await?;
+        let ids: Vec<i64> = files.iter().map(|f| f.message_id).collect();
+        assert_eq!(ids, vec![12, 11, 10]);
+        assert_eq!(files[0].ext, "txt");
+        assert_eq!(
+            files[0].url,
+            "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt"
+        );
+
+        let files = state
+            .file_list_by_chat(
+                ListChatFiles {
+                    last_id: Some(7),
+                    ..input.clone()
+                },
+                1,
+            )
+            .await?;
+        let ids: Vec<i64> = files.iter().map(|f| f.message_id).collect();
+        assert_eq!(ids, vec![6]);
+
+        // a page never splits the files of a message
+        let png = store_file(&state, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").await?;
+        let txt = store_file(&state, b"notes").await?;
+        let message = state
+            .message_create(
+                CreateMessage {
+                    content: "".to_string(),
+                    files: vec![png.url(), txt.url()],
+                    urgent: false,
+                },
+                1,
+                1,
+            )
+            .await?;
+        let files = state
+            .file_list_by_chat(
+                ListChatFiles {
+                    limit: 1,
+                    ..input.clone()
+                },
+                1,
+            )
+            .await?;
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|f| f.message_id == message.id));
+        assert_eq!(files[0].content_type.as_deref(), Some("image/png"));
+
+        let files = state
+            .file_list_by_chat(
+                ListChatFiles {
+                    category: Some(FileCategory::Images),
+                    limit: 10,
+                    ..input.clone()
+                },
+                1,
+            )
+            .await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].url, png.url());
+
+        let files = state
+            .file_list_by_chat(
+                ListChatFiles {
+                    category: Some(FileCategory::Documents),
+                    limit: 10,
+                    ..input.clone()
+                },
+                1,
+            )
+            .await?;
+        assert_eq!(files.len(), 8);
+
+        let files = state
+            .file_list_by_chat(
+                ListChatFiles {
+                    uploader: Some(2),
+                    limit: 10,
+                    ..input.clone()
+                },
+                1,
+            )
+            .await?;
+        assert!(files.is_empty());
+
+        // the category follows the content, not the name
+        let song = state
+            .file_upload(1, 2, "song.mp3".to_string(), b"lyrics")
+            .await?;
+        let posted = |sender_id| {
+            state.message_create(
+                CreateMessage {
+                    content: "".to_string(),
+                    files: vec![song.path.clone()],
+                    urgent: false,
+                },
+                sender_id,
+                1,
+            )
+        };
+        let by_uploader = posted(2).await?;
+        // someone else shares it again
+        let shared = posted(1).await?;
+        let files = state
+            .file_list_by_chat(
+                ListChatFiles {
+                    category: Some(FileCategory::Media),
+                    limit: 10,
+                    ..input.clone()
+                },
+                1,
+            )
+            .await?;
+        assert!(files.is_empty());
+
+        let files = state
+            .file_list_by_chat(
+                ListChatFiles {
+                    uploader: Some(2),
+                    limit: 10,
+                    ..input.clone()
+                },
+                1,
+            )
+            .await?;
+        let ids: Vec<i64> = files.iter().map(|f| f.message_id).collect();
+        assert_eq!(ids, vec![shared.id, by_uploader.id]);
+        let files = state
+            .file_list_by_chat(
+                ListChatFiles {
+                    uploader: Some(1),
+                    limit: 10,
+                    ..input
+                },
+                1,
+            )
+            .await?;
+        assert!(files.iter().all(|f| f.url != song.path));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_ref_count_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file: ChatFile = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt".parse()?;
+        // the fixtures reference this file from 7 messages
+        assert_eq!(state.file_ref_count(&file.url()).await?, 7);
+
+        let file = store_file(&state, b"hello world").await?;
+        assert_eq!(state.file_ref_count(&file.url()).await?, 0);
+        state
+            .message_create(
+                CreateMessage {
+                    content: "".to_string(),
+                    files: vec![file.url()],
+                    urgent: false,
+                },
+                1,
+                1,
+            )
+            .await?;
+        assert_eq!(state.file_ref_count(&file.url()).await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_gc_dry_run_should_keep_files() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = store_file(&state, b"orphan").await?;
+
+        let report = state.file_gc(Some(1), Duration::ZERO, true).await?;
+        assert!(report.dry_run);
+        assert_eq!(report.files, vec![file.url()]);
+        assert_eq!(report.reclaimed_bytes, 6);
+        assert!(file.path(&state.config.server.base_dir).exists());
+        assert!(state.file_fetch_by_url(&file.url()).await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_gc_should_remove_unreferenced_files() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let orphan = store_file(&state, b"orphan").await?;
+        let used = store_file(&state, b"used").await?;
+        state
+            .message_create(
+                CreateMessage {
+                    content: "".to_string(),
+                    files: vec![used.url()],
+                    urgent: false,
+                },
+                1,
+                1,
+            )
+            .await?;
+
+        // still within the grace period
+        let report = state
+            .file_gc(Some(1), Duration::from_secs(3600), false)
+            .await?;
+        assert!(report.files.is_empty());
+
+        let report = state.file_gc(Some(1), Duration::ZERO, false).await?;
+        assert_eq!(report.files, vec![orphan.url()]);
+        assert!(!orphan.path(&state.config.server.base_dir).exists());
+        assert!(state.file_fetch_by_url(&orphan.url()).await?.is_none());
+        assert!(used.path(&state.config.server.base_dir).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_gc_should_wait_for_uploads_of_the_file() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = store_file(&state, b"uploaded again").await?;
+        let path = file.path(&state.config.server.base_dir);
+
+        // an upload of the same content is in progress
+        let lock = state.file_lock(&file.url()).await?;
+        let gc = tokio::spawn({
+            let state = state.clone();
+            async move { state.file_gc(Some(1), Duration::ZERO, false).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!gc.is_finished());
+        assert!(path.exists());
+
+        // it recorded the upload, the file is no longer collectable
+        sqlx::query(
+            "UPDATE files SET updated_at = CURRENT_TIMESTAMP + INTERVAL '1 hour' WHERE path = $1",
+        )
+        .bind(file.url())
+        .execute(&state.pool)
+        .await?;
+        lock.commit().await?;
+        let report = gc.await.unwrap()?;
+        assert!(report.files.is_empty());
+        assert!(path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_gc_should_collect_after_message_deleted() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = store_file(&state, b"deleted later").await?;
+        let message = state
+            .message_create(
+                CreateMessage {
+                    content: "".to_string(),
+                    files: vec![file.url()],
+                    urgent: false,
+                },
+                1,
+                1,
+            )
+            .await?;
+        sqlx::query("DELETE FROM messages WHERE id = $1")
+            .bind(message.id)
+            .execute(&state.pool)
+            .await?;
+
+        let report = state.file_gc(Some(1), Duration::ZERO, false).await?;
+        assert_eq!(report.files, vec![file.url()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_gc_should_keep_avatars() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = store_file(&state, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").await?;
+        sqlx::query("UPDATE users SET avatar_url = $1 WHERE id = 1")
+            .bind(file.url())
+            .execute(&state.pool)
+            .await?;
+
+        let report = state.file_gc(Some(1), Duration::ZERO, false).await?;
+        assert!(report.files.is_empty());
+        assert!(file.path(&state.config.server.base_dir).exists());
+        Ok(())
     }
 }
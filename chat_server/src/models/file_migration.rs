@@ -0,0 +1,246 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tracing::{info, warn};
+
+use super::{collect_blobs, ChatFile};
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileHashMigration {
+    // number of blobs re-addressed by sha256
+    pub files: usize,
+    // number of messages whose file urls were rewritten
+    pub messages: u64,
+    // urls of blobs left alone because their content doesn't match the url
+    pub skipped: Vec<String>,
+}
+
+impl AppState {
+    /// Target of an url that was re-addressed
+    pub async fn file_alias(&self, url: &str) -> Result<Option<String>, AppError> {
+        let target: Option<(String,)> =
+            sqlx::query_as("SELECT target FROM file_aliases WHERE path = $1")
+                .bind(url)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(target.map(|(target,)| target))
+    }
+
+    /// Re-address all sha1 blobs by sha256: the blob is moved, its files row
+    /// and every message referencing it are rewritten, and the old url is
+    /// kept as an alias. Safe to run again, e.g. after an interruption.
+    pub async fn file_migrate_hashes(&self) -> Result<FileHashMigration, AppError> {
+        let base_dir = self.config.server.base_dir.clone();
+        let blobs = tokio::task::spawn_blocking(move || {
+            let mut blobs = vec![];
+            collect_blobs(&base_dir, &base_dir, &mut blobs)?;
+            Ok::<_, io::Error>(blobs)
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        let mut report = FileHashMigration::default();
+        for blob in blobs {
+            let file: ChatFile = blob.url.parse()?;
+            if !file.is_legacy() {
+                continue;
+            }
+            let data = tokio::fs::read(&blob.path).await?;
+            if hex::encode(Sha1::digest(&data)) != file.hash {
+                warn!("skip {}, content doesn't match its hash", blob.url);
+                report.skipped.push(blob.url);
+                continue;
+            }
+            let target = ChatFile {
+                hash: ChatFile::hash(&data),
+                ..file
+            };
+            let path = target.path(&self.config.server.base_dir);
+            if !path.exists() {
+                tokio::fs::create_dir_all(path.parent().expect("file path parent should exist"))
+                    .await?;
+                tokio::fs::write(&path, &data).await?;
+            }
+            report.messages += self.file_readdress(&blob.url, &target).await?;
+            tokio::fs::remove_file(&blob.path).await?;
+            report.files += 1;
+        }
+        info!(
+            "migrated {} files referenced by {} messages",
+            report.files, report.messages
+        );
+        Ok(report)
+    }
+
+    // point everything referencing `url` to `target`, returns the number of messages updated
+    async fn file_readdress(&self, url: &str, target: &ChatFile) -> Result<u64, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let existing: Option<(i64,)> = sqlx::query_as("SELECT id FROM files WHERE path = $1")
+            .bind(target.url())
+            .fetch_optional(&mut *tx)
+            .await?;
+        match existing {
+            // the same content was uploaded again after the switch
+            Some((id,)) => {
+                sqlx::query(
+                    r#"
+                    INSERT INTO file_uploads (file_id, user_id)
+                    SELECT $1, fu.user_id FROM file_uploads fu
+                    JOIN files f ON f.id = fu.file_id
+                    WHERE f.path = $2
+                    ON CONFLICT DO NOTHING
+                    "#,
+                )
+                .bind(id)
+                .bind(url)
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query("DELETE FROM files WHERE path = $1")
+                    .bind(url)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            None => {
+                sqlx::query("UPDATE files SET path = $2, hash = $3 WHERE path = $1")
+                    .bind(url)
+                    .bind(target.url())
+                    .bind(&target.hash)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+
+        // message_files is kept in sync by trigger
+        let messages = sqlx::query(
+            r#"
+            UPDATE messages SET files = array_replace(files, $1, $2)
+            WHERE id IN (SELECT message_id FROM message_files WHERE path = $1)
+            "#,
+        )
+        .bind(url)
+        .bind(target.url())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        sqlx::query(
+            r#"
+            INSERT INTO file_aliases (path, target) VALUES ($1, $2)
+            ON CONFLICT (path) DO UPDATE SET target = excluded.target
+            "#,
+        )
+        .bind(url)
+        .bind(target.url())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::models::{CreateMessage, FileScanStatus, ListChatFiles};
+
+    const LEGACY_URL: &str = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt";
+
+    fn store_legacy(state: &AppState, url: &str, data: &[u8]) -> Result<(), AppError> {
+        let file: ChatFile = url.parse()?;
+        let path = file.path(&state.config.server.base_dir);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_migrate_hashes_should_rewrite_urls() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // the fixture messages reference the sha1 url of "hello"
+        store_legacy(&state, LEGACY_URL, b"hello")?;
+        let legacy: ChatFile = LEGACY_URL.parse()?;
+        state
+            .file_create(&legacy, b"hello", 1, FileScanStatus::Unscanned)
+            .await?;
+        // content doesn't match the url
+        let corrupt = "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt";
+        store_legacy(&state, corrupt, b"tampered")?;
+
+        let report = state.file_migrate_hashes().await?;
+        assert_eq!(report.files, 1);
+        assert_eq!(report.messages, 7);
+        assert_eq!(report.skipped, vec![corrupt.to_string()]);
+
+        let target = ChatFile::new(1, "hello.txt".to_string(), b"hello")?;
+        assert!(!legacy.path(&state.config.server.base_dir).exists());
+        assert!(target.path(&state.config.server.base_dir).exists());
+        let meta = state.file_fetch_by_url(&target.url()).await?.unwrap();
+        assert_eq!(meta.hash, target.hash);
+        assert_eq!(state.file_ref_count(&target.url()).await?, 7);
+
+        let files = state
+            .file_list_by_chat(
+                ListChatFiles {
+                    category: None,
+                    uploader: None,
+                    last_id: None,
+                    limit: 10,
+                },
+                1,
+            )
+            .await?;
+        assert!(files.iter().all(|f| f.url == target.url()));
+
+        // old urls keep resolving
+        assert_eq!(state.file_parse(LEGACY_URL).await?.url(), target.url());
+        assert!(state.file_can_access(&target.url(), 2, None).await?);
+
+        // running it again is a no-op
+        let report = state.file_migrate_hashes().await?;
+        assert_eq!(report.files, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_migrate_hashes_should_merge_with_existing_upload() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        store_legacy(&state, LEGACY_URL, b"hello")?;
+        let legacy: ChatFile = LEGACY_URL.parse()?;
+        state
+            .file_create(&legacy, b"hello", 2, FileScanStatus::Unscanned)
+            .await?;
+        // uploaded again by another user after the switch
+        let meta = state
+            .file_upload(1, 3, "hello.txt".to_string(), b"hello")
+            .await?;
+
+        let report = state.file_migrate_hashes().await?;
+        assert_eq!(report.files, 1);
+        assert!(state.file_fetch_by_url(LEGACY_URL).await?.is_none());
+        let (uploaders,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM file_uploads WHERE file_id = $1")
+                .bind(meta.id)
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(uploaders, 2);
+
+        // messages can be sent with the old url
+        let message = state
+            .message_create(
+                CreateMessage {
+                    content: "".to_string(),
+                    files: vec![LEGACY_URL.to_string()],
+                    urgent: false,
+                },
+                2,
+                1,
+            )
+            .await?;
+        assert_eq!(message.files, Some(vec![meta.path]));
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use tracing::{info, warn};
+
+use super::ChatFile;
+use crate::{extract::extract_text, AppError, AppState};
+
+// files extracted per query when catching up on old uploads
+const BACKFILL_BATCH: i64 = 100;
+
+impl AppState {
+    pub async fn file_text_update(&self, url: &str, text: Option<&str>) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE files
+            SET text_content = $2, text_extracted_at = CURRENT_TIMESTAMP
+            WHERE path = $1
+            "#,
+        )
+        .bind(url)
+        .bind(text)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Extract the text of a stored file and record it for search
+    pub async fn file_extract(&self, file: ChatFile, data: Vec<u8>) -> Result<(), AppError> {
+        let url = file.url();
+        let text = tokio::task::spawn_blocking(move || extract_text(&file.ext, &data))
+            .await
+            .unwrap_or_default();
+        self.file_text_update(&url, text.as_deref()).await
+    }
+
+    pub(crate) fn file_extract_spawn(&self, file: ChatFile, data: Vec<u8>) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            let url = file.url();
+            if let Err(e) = state.file_extract(file, data).await {
+                warn!("failed to extract text of {}: {}", url, e);
+            }
+        });
+    }
+
+    /// Extract the text of files stored before extraction was enabled, files
+    /// not known to be clean are left alone. Returns the number of files
+    /// processed.
+    pub async fn file_extract_backfill(&self) -> Result<usize, AppError> {
+        let mut count = 0;
+        loop {
+            let paths: Vec<(String,)> = sqlx::query_as(
+                r#"
+                SELECT path FROM files
+                WHERE text_extracted_at IS NULL AND scan_status IN ('clean', 'unscanned')
+                ORDER BY id
+                LIMIT $1
+                "#,
+            )
+            .bind(BACKFILL_BATCH)
+            .fetch_all(&self.pool)
+            .await?;
+            if paths.is_empty() {
+                return Ok(count);
+            }
+            for (url,) in paths {
+                let file: ChatFile = url.parse()?;
+                match tokio::fs::read(file.path(&self.config.server.base_dir)).await {
+                    Ok(data) => self.file_extract(file, data).await?,
+                    // the blob is gone, don't pick the row up again
+                    Err(e) => {
+                        warn!("failed to read {}: {}", url, e);
+                        self.file_text_update(&url, None).await?;
+                    }
+                }
+                count += 1;
+            }
+        }
+    }
+
+    pub fn file_extract_schedule(&self) {
+        if !self.config.files.extract_text {
+            return;
+        }
+        let state = self.clone();
+        tokio::spawn(async move {
+            match state.file_extract_backfill().await {
+                Ok(count) => info!("extracted text of {} files", count),
+                Err(e) => warn!("file text backfill failed: {}", e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{extract::tests::pdf_with_text, models::FileScanStatus};
+
+    pub(crate) async fn wait_for_text(state: &AppState, url: &str) -> Result<(), AppError> {
+        for _ in 0..50 {
+            let meta = state.file_fetch_by_url(url).await?.unwrap();
+            if meta.text_extracted_at.is_some() {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("text of {} was not extracted", url);
+    }
+
+    async fn file_text(state: &AppState, url: &str) -> Result<Option<String>, AppError> {
+        let (text,): (Option<String>,) =
+            sqlx::query_as("SELECT text_content FROM files WHERE path = $1")
+                .bind(url)
+                .fetch_one(&state.pool)
+                .await?;
+        Ok(text)
+    }
+
+    #[tokio::test]
+    async fn file_upload_should_extract_text() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let pdf = pdf_with_text("quarterly revenue report");
+        let meta = state
+            .file_upload(1, 1, "report.pdf".to_string(), &pdf)
+            .await?;
+        wait_for_text(&state, &meta.path).await?;
+        let text = file_text(&state, &meta.path).await?.unwrap();
+        assert!(text.contains("quarterly revenue report"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_extract_backfill_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let notes = ChatFile::new(1, "notes.md".to_string(), b"# standup notes")?;
+        let path = notes.path(&state.config.server.base_dir);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, b"# standup notes")?;
+        state
+            .file_create(&notes, b"# standup notes", 1, FileScanStatus::Unscanned)
+            .await?;
+        // the blob was removed
+        let missing = ChatFile::new(1, "gone.txt".to_string(), b"gone")?;
+        state
+            .file_create(&missing, b"gone", 1, FileScanStatus::Unscanned)
+            .await?;
+
+        // still in quarantine
+        let pending = ChatFile::new(1, "pending.txt".to_string(), b"pending")?;
+        state
+            .file_create(&pending, b"pending", 1, FileScanStatus::Pending)
+            .await?;
+
+        assert_eq!(state.file_extract_backfill().await?, 2);
+        assert_eq!(
+            file_text(&state, &notes.url()).await?.as_deref(),
+            Some("# standup notes")
+        );
+        assert_eq!(file_text(&state, &missing.url()).await?, None);
+        let meta = state.file_fetch_by_url(&pending.url()).await?.unwrap();
+        assert!(meta.text_extracted_at.is_none());
+        assert_eq!(state.file_extract_backfill().await?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{AppError, AppState};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignFiles {
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedFileUrl {
+    pub url: String,
+    pub signed_url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// query parameters carried by a signed file url
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileUrlSignature {
+    pub uid: i64,
+    pub exp: i64,
+    pub sig: String,
+}
+
+impl AppState {
+    /// Sign a file url (`/files/...`) for `user_id`. The returned url can be
+    /// used as is, without a session token, until it expires.
+    pub fn file_url_sign(&self, url: &str, user_id: i64) -> Result<SignedFileUrl, AppError> {
+        let expires_at =
+            Utc::now() + chrono::Duration::seconds(self.config.files.url_ttl_secs as _);
+        let exp = expires_at.timestamp();
+        let sig = hex::encode(
+            self.file_url_mac(url, user_id, exp)?
+                .finalize()
+                .into_bytes(),
+        );
+        Ok(SignedFileUrl {
+            url: url.to_string(),
+            signed_url: format!("/api{}?uid={}&exp={}&sig={}", url, user_id, exp, sig),
+            expires_at,
+        })
+    }
+
+    /// Verify the signature of a file url, returns the id of the user it was issued to
+    pub fn file_url_verify(&self, url: &str, params: &FileUrlSignature) -> Result<i64, AppError> {
+        if params.exp < Utc::now().timestamp() {
+            return Err(AppError::PermissionDenied("signed url expired".to_string()));
+        }
+        let sig = hex::decode(&params.sig)
+            .map_err(|_| AppError::PermissionDenied("malformed signature".to_string()))?;
+        self.file_url_mac(url, params.uid, params.exp)?
+            .verify_slice(&sig)
+            .map_err(|_| AppError::PermissionDenied("invalid signature".to_string()))?;
+        Ok(params.uid)
+    }
+
+    fn file_url_mac(&self, url: &str, user_id: i64, exp: i64) -> Result<HmacSha256, AppError> {
+        // checked at startup, see `AppState::try_new`
+        let secret = self.config.files.url_secret.as_deref().unwrap_or_default();
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+            .map_err(|e| AppError::InvalidFileURL(e.to_string()))?;
+        mac.update(format!("{}\n{}\n{}", url, user_id, exp).as_bytes());
+        Ok(mac)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature_of(signed: &SignedFileUrl) -> FileUrlSignature {
+        let (_, query) = signed.signed_url.split_once('?').unwrap();
+        let params: Vec<(&str, &str)> = query
+            .split('&')
+            .map(|kv| kv.split_once('=').unwrap())
+            .collect();
+        FileUrlSignature {
+            uid: params[0].1.parse().unwrap(),
+            exp: params[1].1.parse().unwrap(),
+            sig: params[2].1.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn file_url_sign_and_verify_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let url = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt";
+        let signed = state.file_url_sign(url, 1)?;
+        assert!(signed.signed_url.starts_with("/api/files/1/aaf/"));
+        assert!(signed.expires_at > Utc::now());
+
+        let params = signature_of(&signed);
+        assert_eq!(state.file_url_verify(url, &params)?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_url_verify_should_reject_tampering() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let url = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt";
+        let params = signature_of(&state.file_url_sign(url, 1)?);
+
+        // another file
+        let other = "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt";
+        assert!(state.file_url_verify(other, &params).is_err());
+
+        // another user
+        let forged = FileUrlSignature {
+            uid: 2,
+            ..params.clone()
+        };
+        assert!(state.file_url_verify(url, &forged).is_err());
+
+        // extended expiry
+        let forged = FileUrlSignature {
+            exp: params.exp + 3600,
+            ..params.clone()
+        };
+        assert!(state.file_url_verify(url, &forged).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_url_verify_should_reject_expired() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let url = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt";
+        let exp = Utc::now().timestamp() - 1;
+        let sig = hex::encode(state.file_url_mac(url, 1, exp)?.finalize().into_bytes());
+        let params = FileUrlSignature { uid: 1, exp, sig };
+        assert!(matches!(
+            state.file_url_verify(url, &params),
+            Err(AppError::PermissionDenied(msg)) if msg == "signed url expired"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    mail::expires_in, signin::ThrottleAction, token::generate_token, ClientInfo, UserTokenPurpose,
+};
+use crate::{AppError, AppState, Mail, User};
+
+/// The cookie holding the nonce a magic link is bound to
+pub const MAGIC_LINK_COOKIE: &str = "magic_link_nonce";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MagicLinkPayload {
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MagicLinkSignInPayload {
+    pub token: String,
+}
+
+impl AppState {
+    /// Mail a sign in link if the email belongs to a user whose workspace
+    /// allows them. Returns the nonce for the browser asking, the link only
+    /// works together with it. Callers can't tell whether a mail was sent.
+    pub async fn magic_link_send(
+        &self,
+        email: &str,
+        client: &ClientInfo,
+    ) -> Result<String, AppError> {
+        let throttle = self.throttle(ThrottleAction::MagicLink, email, client);
+        self.throttle_attempt(&throttle).await?;
+        let nonce = generate_token();
+        let Some(user) = self.user_find_by_email(email).await? else {
+            return Ok(nonce);
+        };
+        if !self.workspace_magic_link_login(user.ws_id).await? {
+            return Ok(nonce);
+        }
+        let ttl = self.config.auth.magic_link_ttl_secs;
+        let token = self
+            .user_token_create_bound(user.id, UserTokenPurpose::MagicLink, ttl, &nonce)
+            .await?;
+        let body = format!(
+            "Hi {},\n\n\
+             Open the link below in the same browser to sign in, it expires in {}:\n\n\
+             {}\n\n\
+             If you didn't ask to sign in, you can ignore this mail.\n",
+            user.fullname,
+            expires_in(ttl),
+            self.mail_link("magic-link", &token)
+        );
+        self.mail_spawn(Mail {
+            to: user.email,
+            subject: "Your sign in link".to_string(),
+            body,
+        });
+        Ok(nonce)
+    }
+
+    /// The user of a mailed sign in link. Following the link proves the
+    /// email, it is marked verified.
+    pub async fn magic_link_verify(&self, token: &str, nonce: &str) -> Result<User, AppError> {
+        let invalid = || AppError::InvalidToken("invalid or expired sign in link".to_string());
+        let Some(user_id) = self
+            .user_token_peek(token, UserTokenPurpose::MagicLink)
+            .await?
+        else {
+            return Err(invalid());
+        };
+        let Some(user) = self.user_fetch_by_id(user_id).await? else {
+            return Err(invalid());
+        };
+        // the workspace may have turned them off since the link was sent, the
+        // link is left alone then
+        if !self.workspace_magic_link_login(user.ws_id).await? {
+            return Err(AppError::PermissionDenied(
+                "sign in links are turned off for this workspace".to_string(),
+            ));
+        }
+        if self
+            .user_token_consume_bound(token, UserTokenPurpose::MagicLink, nonce)
+            .await?
+            != Some(user_id)
+        {
+            return Err(invalid());
+        }
+        let user: User = sqlx::query_as(
+            r#"
+            UPDATE users SET verified_at = COALESCE(verified_at, CURRENT_TIMESTAMP)
+            WHERE id = $1
+            RETURNING id, ws_id, fullname, email, verified_at, created_at, updated_at
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        self.users.invalidate(user_id);
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mailer::tests::{mailed_token, wait_for_mail},
+        models::UpdateWorkspaceSettings,
+        oidc::tests::{mock_sso, MockAccount},
+    };
+
+    #[tokio::test]
+    async fn magic_link_should_sign_in_the_asking_browser() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let nonce = state
+            .magic_link_send("test@yahoo.com", &ClientInfo::default())
+            .await?;
+        let eml = wait_for_mail(&state.config.server.base_dir.join("mail")).await;
+        assert!(eml.contains("To: test@yahoo.com"));
+        let token = mailed_token(&eml);
+
+        // another browser can't use it
+        let ret = state.magic_link_verify(&token, "other").await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        let user = state.magic_link_verify(&token, &nonce).await?;
+        assert_eq!(user.id, 1);
+        // single use
+        let ret = state.magic_link_verify(&token, &nonce).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn magic_link_should_respect_workspace_setting() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // unknown emails look the same
+        state
+            .magic_link_send("nobody@acme.org", &ClientInfo::default())
+            .await?;
+
+        let nonce = state
+            .magic_link_send("test@yahoo.com", &ClientInfo::default())
+            .await?;
+        let token = mailed_token(&wait_for_mail(&state.config.server.base_dir.join("mail")).await);
+        state
+            .workspace_settings_update(
+                1,
+                UpdateWorkspaceSettings {
+                    magic_link_login: Some(Some(false)),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        sqlx::query("UPDATE users SET verified_at = NULL WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let ret = state.magic_link_verify(&token, &nonce).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        // the link is neither used up nor does it verify the email
+        assert!(!state.user_fetch_by_id(1).await?.unwrap().is_verified());
+        let (used,): (bool,) = sqlx::query_as(
+            "SELECT used_at IS NOT NULL FROM user_tokens WHERE purpose = 'magic_link'",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        assert!(!used);
+
+        // no new link is issued while they're off
+        sqlx::query("UPDATE signin_throttles SET blocked_until = NULL")
+            .execute(&state.pool)
+            .await?;
+        state
+            .magic_link_send("test@yahoo.com", &ClientInfo::default())
+            .await?;
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT count(*) FROM user_tokens WHERE purpose = 'magic_link'")
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(count, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn magic_link_should_be_off_with_single_sign_on_only() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let nonce = state
+            .magic_link_send("test@yahoo.com", &ClientInfo::default())
+            .await?;
+        let token = mailed_token(&wait_for_mail(&state.config.server.base_dir.join("mail")).await);
+
+        let account = MockAccount {
+            subject: "alice".to_string(),
+            email: "alice@acme.org".to_string(),
+            email_verified: true,
+        };
+        mock_sso(&state, 1, account).await;
+        let settings = UpdateWorkspaceSettings {
+            password_login: Some(Some(false)),
+            magic_link_login: Some(Some(true)),
+            ..Default::default()
+        };
+        state.workspace_settings_update(1, settings).await?;
+        assert!(!state.workspace_magic_link_login(1).await?);
+        let ret = state.magic_link_verify(&token, &nonce).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn magic_link_send_should_be_throttled() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let nonce = state
+            .magic_link_send("test@yahoo.com", &ClientInfo::default())
+            .await?;
+        let token = mailed_token(&wait_for_mail(&state.config.server.base_dir.join("mail")).await);
+        // asking again right away neither mails nor cancels the first link
+        let ret = state
+            .magic_link_send("Test@yahoo.com", &ClientInfo::default())
+            .await;
+        assert!(matches!(ret, Err(AppError::TooManyAttempts(_))));
+        assert_eq!(state.magic_link_verify(&token, &nonce).await?.id, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use tracing::warn;
+
+use crate::{AppState, Mail};
+
+impl AppState {
+    /// Send a mail in the background, failures are only logged
+    pub(crate) fn mail_spawn(&self, mail: Mail) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = state.mailer.send(&mail).await {
+                warn!("failed to send \"{}\" to {}: {}", mail.subject, mail.to, e);
+            }
+        });
+    }
+
+    /// A link into the web app carrying a mailed token
+    pub(crate) fn mail_link(&self, path: &str, token: &str) -> String {
+        format!(
+            "{}/{}?token={}",
+            self.config.mail.app_url.trim_end_matches('/'),
+            path.trim_start_matches('/'),
+            token
+        )
+    }
+}
+
+/// How long a mailed link stays valid, in words, e.g. `2 hours` or `10 minutes`
+pub(crate) fn expires_in(ttl_secs: u64) -> String {
+    let (n, unit) = match ttl_secs {
+        s if s >= 3600 && s % 3600 == 0 => (s / 3600, "hour"),
+        s if s >= 60 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+    format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_in_should_pick_a_readable_unit() {
+        assert_eq!(expires_in(172800), "48 hours");
+        assert_eq!(expires_in(3600), "1 hour");
+        assert_eq!(expires_in(5400), "90 minutes");
+        assert_eq!(expires_in(600), "10 minutes");
+        assert_eq!(expires_in(60), "1 minute");
+        assert_eq!(expires_in(30), "30 seconds");
+    }
+}
@@ -1,12 +1,16 @@
-use crate::{models::ChatFile, AppError, AppState};
+use crate::{AppError, AppState};
 
-use chat_core::Message;
+use chat_core::{ChatType, Message};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateMessage {
     pub content: String,
     pub files: Vec<String>,
+    // reaches members in do not disturb, only in direct messages or from the
+    // workspace owner
+    #[serde(default)]
+    pub urgent: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -15,6 +19,14 @@ pub struct ListMessages {
     pub limit: u64,
 }
 
+/// `q` accepts web search syntax, e.g. `"exact phrase" -excluded`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchMessages {
+    pub q: String,
+    pub last_id: Option<u64>,
+    pub limit: u64,
+}
+
 impl AppState {
     #[allow(unused)]
     pub async fn message_create(
@@ -30,30 +42,54 @@ impl AppState {
             ));
         }
         let base_dir = &self.config.server.base_dir;
+        let Some(chat) = self.chat_fetched_by_id(chat_id as _).await? else {
+            return Err(AppError::NotFound(format!(
+                "Chat with id {} not found",
+                chat_id
+            )));
+        };
+        if input.urgent && chat.r#type != ChatType::Single {
+            let workspace = self.workspace_fetch_by_id(chat.ws_id as _).await?;
+            if workspace.map(|ws| ws.owner_id) != Some(sender_id as i64) {
+                return Err(AppError::PermissionDenied(
+                    "only direct messages and the workspace owner can be urgent".to_string(),
+                ));
+            }
+        }
 
-        // verify files
+        // verify files, legacy urls are stored as the url they resolve to
+        let mut files = Vec::with_capacity(input.files.len());
         for file in &input.files {
-            let file: ChatFile = file.parse()?;
+            let file = self.file_parse(file).await?;
+            if file.ws_id != chat.ws_id as u64 {
+                return Err(AppError::CreateMessageError(format!(
+                    "File {} doesn't belong to this workspace",
+                    file.url()
+                )));
+            }
             let file_path = file.path(base_dir);
-            if !file_path.exists() {
+            if !file_path.exists() || !self.file_can_access(&file.url(), sender_id, None).await? {
                 return Err(AppError::CreateMessageError(format!(
                     "File not exists: {}",
                     file.url()
                 )));
             }
+            self.file_check_available(&file.url()).await?;
+            files.push(file.url());
         }
 
         let message = sqlx::query_as(
             r#"
-            INSERT INTO messages (content, files, chat_id, sender_id)
-            VALUES ($1, $2, $3, $4)
+            INSERT INTO messages (content, files, chat_id, sender_id, urgent)
+            VALUES ($1, $2, $3, $4, $5)
             RETURNING *
         "#,
         )
         .bind(input.content)
-        .bind(&input.files)
+        .bind(&files)
         .bind(chat_id as i64)
         .bind(sender_id as i64)
+        .bind(input.urgent)
         .fetch_one(&self.pool)
         .await?;
 
@@ -83,11 +119,52 @@ impl AppState {
         .await?;
         Ok(messages)
     }
+
+    /// Search the messages of a chat by their content and the text extracted
+    /// from their attachments, newest first.
+    pub async fn message_search(
+        &self,
+        input: SearchMessages,
+        chat_id: u64,
+    ) -> Result<Vec<Message>, AppError> {
+        if input.q.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+        let messages = sqlx::query_as(
+            r#"
+            SELECT m.* FROM messages m, websearch_to_tsquery('simple', $3) q
+            WHERE m.chat_id = $1
+            AND m.id < $2
+            AND (
+                to_tsvector('simple', m.content) @@ q
+                OR EXISTS (
+                    SELECT 1 FROM message_files mf
+                    JOIN files f ON f.path = mf.path
+                    WHERE mf.message_id = m.id AND f.text_tsv @@ q
+                )
+            )
+            ORDER BY m.id DESC
+            LIMIT $4
+        "#,
+        )
+        .bind(chat_id as i64)
+        .bind(last_id as i64)
+        .bind(input.q)
+        .bind(input.limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        extract::tests::pdf_with_text,
+        models::{file_text::tests::wait_for_text, ChatFile, CreateChat, FileScanStatus},
+    };
 
     #[tokio::test]
     async fn test_message_create_should_work() -> Result<(), AppError> {
@@ -97,6 +174,7 @@ mod tests {
                 CreateMessage {
                     content: "Hello, world!".to_string(),
                     files: vec![],
+                    urgent: false,
                 },
                 1,
                 1,
@@ -107,6 +185,33 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_message_create_should_limit_urgent_messages() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let urgent = || CreateMessage {
+            content: "Wake up!".to_string(),
+            files: vec![],
+            urgent: true,
+        };
+        let ret = state.message_create(urgent(), 2, 1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let dm = state
+            .chat_create(
+                1,
+                CreateChat {
+                    members: vec![1, 2],
+                    ..Default::default()
+                },
+            )
+            .await?;
+        assert!(state.message_create(urgent(), 2, dm.id as _).await?.urgent);
+
+        state.workspace_owner_update(1, 1).await?;
+        assert!(state.message_create(urgent(), 1, 1).await?.urgent);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_message_create_should_fail_if_content_and_files_are_empty() -> Result<(), AppError>
     {
@@ -116,6 +221,7 @@ mod tests {
                 CreateMessage {
                     content: "".to_string(),
                     files: vec![],
+                    urgent: false,
                 },
                 1,
                 1,
@@ -135,6 +241,7 @@ mod tests {
                 CreateMessage {
                     content: "Hello, world!".to_string(),
                     files: vec!["https://example.com/file.txt".to_string()],
+                    urgent: false,
                 },
                 1,
                 1,
@@ -157,6 +264,7 @@ mod tests {
                     files: vec![
                         "/files/a/a94/a8f/e5ccb19ba61c4c0873d391e987982fbbd3.txt".to_string()
                     ],
+                    urgent: false,
                 },
                 1,
                 1,
@@ -177,6 +285,7 @@ mod tests {
                 CreateMessage {
                     content: "Hello, world!".to_string(),
                     files: vec![file_url.clone()],
+                    urgent: false,
                 },
                 1,
                 1,
@@ -187,20 +296,68 @@ mod tests {
         assert_eq!(message.files.clone().unwrap().len(), 1);
         assert_eq!(
             message.files.clone().unwrap()[0],
-            "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt"
+            "/files/1/b94/d27/b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9.txt"
         );
         Ok(())
     }
 
     async fn upload_dummy_file(state: &AppState) -> Result<String, AppError> {
         let base_dir = &state.config.server.base_dir;
-        let file = ChatFile::new(1, "test.txt".to_string(), b"hello world");
+        let file = ChatFile::new(1, "test.txt".to_string(), b"hello world")?;
         let file_path = file.path(base_dir);
         std::fs::create_dir_all(file_path.parent().unwrap())?;
         std::fs::write(file_path, b"hello world")?;
+        state
+            .file_create(&file, b"hello world", 1, FileScanStatus::Unscanned)
+            .await?;
         Ok(file.url())
     }
 
+    #[tokio::test]
+    async fn test_message_create_should_fail_if_file_is_from_another_workspace(
+    ) -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file_url = upload_dummy_file(&state).await?;
+        // chat 2 is in workspace 2
+        let result = state
+            .message_create(
+                CreateMessage {
+                    content: "Hello, world!".to_string(),
+                    files: vec![file_url.clone()],
+                    urgent: false,
+                },
+                1,
+                2,
+            )
+            .await;
+        assert!(
+            matches!(result, Err(AppError::CreateMessageError(msg)) if msg == format!("File {} doesn't belong to this workspace", file_url))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_create_should_fail_if_file_is_not_accessible() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file_url = upload_dummy_file(&state).await?;
+        // uploaded by user 1, never shared with user 2
+        let result = state
+            .message_create(
+                CreateMessage {
+                    content: "Hello, world!".to_string(),
+                    files: vec![file_url.clone()],
+                    urgent: false,
+                },
+                2,
+                1,
+            )
+            .await;
+        assert!(
+            matches!(result, Err(AppError::CreateMessageError(msg)) if msg == format!("File not exists: {}", file_url))
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_message_list_should_work() -> Result<(), AppError> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -234,4 +391,52 @@ mod tests {
         assert_eq!(messages[0].content, Some("Hello, world2!".to_string()));
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_message_search_should_match_attachment_text() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = SearchMessages {
+            q: "world6".to_string(),
+            last_id: None,
+            limit: 10,
+        };
+        let messages = state.message_search(input.clone(), 1).await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, Some("Hello, world6!".to_string()));
+
+        let pdf = pdf_with_text("quarterly revenue report");
+        let meta = state
+            .file_upload(1, 1, "report.pdf".to_string(), &pdf)
+            .await?;
+        wait_for_text(&state, &meta.path).await?;
+        let message = state
+            .message_create(
+                CreateMessage {
+                    content: "see attached".to_string(),
+                    files: vec![meta.path],
+                    urgent: false,
+                },
+                1,
+                1,
+            )
+            .await?;
+
+        let search = |q: &str| SearchMessages {
+            q: q.to_string(),
+            ..input.clone()
+        };
+        let messages = state
+            .message_search(search("\"revenue report\""), 1)
+            .await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, message.id);
+        assert!(state
+            .message_search(search("\"report revenue\""), 1)
+            .await?
+            .is_empty());
+        // only messages of the chat are searched
+        assert!(state.message_search(search("revenue"), 2).await?.is_empty());
+        assert!(state.message_search(search("  "), 1).await?.is_empty());
+        Ok(())
+    }
 }
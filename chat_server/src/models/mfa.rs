@@ -0,0 +1,468 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+
+use super::{signin::ThrottleAction, token::hash_token, ClientInfo, UserTokenPurpose};
+use crate::{totp::Totp, AppError, AppState, User};
+
+const RECOVERY_CODES: usize = 10;
+
+/// Shown once when 2FA is set up, the secret is added to an authenticator app
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaCodePayload {
+    // a TOTP code, or a recovery code where noted
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Returned on sign in instead of tokens when the user has 2FA on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaPendingOutput {
+    pub mfa_token: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaSignInPayload {
+    pub mfa_token: String,
+    // a TOTP code or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct UserTotp {
+    secret: Vec<u8>,
+    confirmed_at: Option<DateTime<Utc>>,
+}
+
+impl AppState {
+    /// Start setting up TOTP, replaces a secret that wasn't confirmed yet
+    pub async fn mfa_totp_enroll(&self, user: &User) -> Result<TotpEnrollment, AppError> {
+        if self.mfa_enabled(user.id).await? {
+            return Err(AppError::MfaAlreadyEnabled);
+        }
+        let totp = Totp::generate();
+        sqlx::query(
+            r#"
+            INSERT INTO user_totp (user_id, secret) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET
+                secret = excluded.secret, last_step = NULL, created_at = CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(user.id)
+        .bind(totp.secret())
+        .execute(&self.pool)
+        .await?;
+        Ok(TotpEnrollment {
+            secret: totp.secret_base32(),
+            uri: totp.uri(&self.config.auth.mfa.issuer, &user.email),
+        })
+    }
+
+    /// Turn 2FA on with a first code from the authenticator, returns the
+    /// recovery codes.
+    pub async fn mfa_totp_confirm(
+        &self,
+        user_id: i64,
+        code: &str,
+    ) -> Result<RecoveryCodes, AppError> {
+        let Some(totp) = self.mfa_totp_fetch(user_id).await? else {
+            return Err(AppError::NotFound("no TOTP enrollment".to_string()));
+        };
+        if totp.confirmed_at.is_some() {
+            return Err(AppError::MfaAlreadyEnabled);
+        }
+        let Some(step) = Totp::new(totp.secret).verify(code, Utc::now().timestamp()) else {
+            return Err(AppError::InvalidToken("invalid code".to_string()));
+        };
+        sqlx::query(
+            "UPDATE user_totp SET confirmed_at = CURRENT_TIMESTAMP, last_step = $2 WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .bind(step)
+        .execute(&self.pool)
+        .await?;
+        self.mfa_recovery_codes_create(user_id).await
+    }
+
+    /// Turn 2FA off, not allowed if the workspace requires it
+    pub async fn mfa_disable(
+        &self,
+        user: &User,
+        code: &str,
+        client: &ClientInfo,
+    ) -> Result<(), AppError> {
+        if self.workspace_requires_mfa(user.ws_id).await? {
+            return Err(AppError::PermissionDenied(
+                "the workspace requires two-factor authentication".to_string(),
+            ));
+        }
+        self.mfa_verify_throttled(user, code, client).await?;
+        sqlx::query("DELETE FROM user_totp WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("DELETE FROM user_recovery_codes WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Replace the recovery codes, the old ones stop working
+    pub async fn mfa_recovery_codes_regenerate(
+        &self,
+        user: &User,
+        code: &str,
+        client: &ClientInfo,
+    ) -> Result<RecoveryCodes, AppError> {
+        self.mfa_verify_throttled(user, code, client).await?;
+        self.mfa_recovery_codes_create(user.id).await
+    }
+
+    pub async fn mfa_enabled(&self, user_id: i64) -> Result<bool, AppError> {
+        let totp = self.mfa_totp_fetch(user_id).await?;
+        Ok(totp.is_some_and(|totp| totp.confirmed_at.is_some()))
+    }
+
+    /// Whether the user's workspace requires 2FA and the user hasn't set it up
+    pub async fn mfa_missing(&self, user: &User) -> Result<bool, AppError> {
+        Ok(self.workspace_requires_mfa(user.ws_id).await? && !self.mfa_enabled(user.id).await?)
+    }
+
+    /// Check a TOTP code or use up a recovery code. A TOTP code is only
+    /// accepted once.
+    pub async fn mfa_verify(&self, user_id: i64, code: &str) -> Result<bool, AppError> {
+        let Some(totp) = self.mfa_totp_fetch(user_id).await? else {
+            return Ok(false);
+        };
+        if totp.confirmed_at.is_none() {
+            return Ok(false);
+        }
+        if let Some(step) = Totp::new(totp.secret).verify(code, Utc::now().timestamp()) {
+            let accepted = sqlx::query(
+                r#"
+                UPDATE user_totp SET last_step = $2
+                WHERE user_id = $1 AND (last_step IS NULL OR last_step < $2)
+                "#,
+            )
+            .bind(user_id)
+            .bind(step)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+            return Ok(accepted == 1);
+        }
+        let used = sqlx::query(
+            r#"
+            UPDATE user_recovery_codes SET used_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(hash_token(&normalize_recovery_code(code)))
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(used == 1)
+    }
+
+    /// The password checked out, hand out a token to finish signing in with
+    pub async fn mfa_challenge(&self, user_id: i64) -> Result<MfaPendingOutput, AppError> {
+        let ttl = self.config.auth.mfa.pending_ttl_secs;
+        let mfa_token = self
+            .user_token_create(user_id, UserTokenPurpose::MfaPending, ttl)
+            .await?;
+        Ok(MfaPendingOutput {
+            mfa_token,
+            expires_in: ttl,
+        })
+    }
+
+    /// Exchange an mfa pending token and a code for the user. The token is
+    /// used up either way, a wrong code means signing in again. Codes are
+    /// throttled together with the password sign ins of the account.
+    pub async fn mfa_complete(
+        &self,
+        input: &MfaSignInPayload,
+        client: &ClientInfo,
+    ) -> Result<User, AppError> {
+        let Some(user_id) = self
+            .user_token_consume(&input.mfa_token, UserTokenPurpose::MfaPending)
+            .await?
+        else {
+            return Err(AppError::InvalidToken(
+                "invalid or expired mfa token".to_string(),
+            ));
+        };
+        let Some(user) = self.user_fetch_by_id(user_id).await? else {
+            return Err(AppError::InvalidToken("user no longer exists".to_string()));
+        };
+        self.mfa_verify_throttled(&user, &input.code, client)
+            .await?;
+        Ok(user)
+    }
+
+    /// Check a code, throttled together with the password sign ins of the
+    /// account so a stolen session can't guess its way to changing 2FA
+    async fn mfa_verify_throttled(
+        &self,
+        user: &User,
+        code: &str,
+        client: &ClientInfo,
+    ) -> Result<(), AppError> {
+        let throttle = self.throttle(ThrottleAction::SignIn, &user.email, client);
+        self.throttle_attempt(&throttle).await?;
+        if !self.mfa_verify(user.id, code).await? {
+            return Err(AppError::InvalidToken("invalid code".to_string()));
+        }
+        self.throttle_succeeded(&throttle, false).await?;
+        Ok(())
+    }
+
+    async fn mfa_totp_fetch(&self, user_id: i64) -> Result<Option<UserTotp>, AppError> {
+        let totp = sqlx::query_as("SELECT secret, confirmed_at FROM user_totp WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(totp)
+    }
+
+    async fn mfa_recovery_codes_create(&self, user_id: i64) -> Result<RecoveryCodes, AppError> {
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| recovery_code()).collect();
+        let hashes: Vec<String> = codes
+            .iter()
+            .map(|code| hash_token(&normalize_recovery_code(code)))
+            .collect();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM user_recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO user_recovery_codes (user_id, code_hash) SELECT $1, unnest($2::text[])",
+        )
+        .bind(user_id)
+        .bind(&hashes)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(RecoveryCodes {
+            recovery_codes: codes,
+        })
+    }
+}
+
+/// 80 random bits, e.g. `4f1a9-0c3de-77b20-e95a1`, so their unsalted hashes
+/// can't be brute forced from a database dump
+fn recovery_code() -> String {
+    let mut bytes = [0u8; 10];
+    OsRng.fill_bytes(&mut bytes);
+    let code = hex::encode(bytes);
+    code.as_bytes()
+        .chunks(5)
+        .map(|group| std::str::from_utf8(group).expect("hex is ascii"))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.trim().replace('-', "").to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::UpdateWorkspaceSettings;
+
+    /// Turn 2FA on for a user, returns the TOTP to generate codes with
+    async fn enroll(state: &AppState, user_id: i64) -> Result<Totp, AppError> {
+        let user = state.user_fetch_by_id(user_id).await?.unwrap();
+        state.mfa_totp_enroll(&user).await?;
+        let totp = Totp::new(state.mfa_totp_fetch(user_id).await?.unwrap().secret);
+        // a code of the previous step, so the current one can be used next
+        let step = Totp::step(Utc::now().timestamp()) - 1;
+        state.mfa_totp_confirm(user_id, &totp.code_at(step)).await?;
+        Ok(totp)
+    }
+
+    fn current_code(totp: &Totp) -> String {
+        totp.code_at(Totp::step(Utc::now().timestamp()))
+    }
+
+    #[tokio::test]
+    async fn mfa_totp_enroll_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        let enrollment = state.mfa_totp_enroll(&user).await?;
+        assert!(enrollment.uri.starts_with("otpauth://totp/"));
+        assert!(enrollment.uri.contains(&enrollment.secret));
+        // not on until confirmed
+        assert!(!state.mfa_enabled(1).await?);
+        let ret = state.mfa_totp_confirm(1, "000000").await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+
+        let totp = Totp::new(state.mfa_totp_fetch(1).await?.unwrap().secret);
+        let codes = state.mfa_totp_confirm(1, &current_code(&totp)).await?;
+        assert_eq!(codes.recovery_codes.len(), RECOVERY_CODES);
+        assert!(state.mfa_enabled(1).await?);
+        assert!(matches!(
+            state.mfa_totp_enroll(&user).await,
+            Err(AppError::MfaAlreadyEnabled)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mfa_verify_should_reject_replayed_codes() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let totp = enroll(&state, 1).await?;
+        let code = current_code(&totp);
+        assert!(state.mfa_verify(1, &code).await?);
+        assert!(!state.mfa_verify(1, &code).await?);
+        assert!(!state.mfa_verify(2, &code).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mfa_recovery_codes_should_be_single_use() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let totp = enroll(&state, 1).await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        let codes = state
+            .mfa_recovery_codes_regenerate(&user, &current_code(&totp), &ClientInfo::default())
+            .await?
+            .recovery_codes;
+        assert!(codes.iter().all(|code| code.len() == 23));
+        let code = codes[0].to_uppercase();
+        assert!(state.mfa_verify(1, &code).await?);
+        assert!(!state.mfa_verify(1, &code).await?);
+        assert!(state.mfa_verify(1, &codes[1].replace('-', "")).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mfa_complete_should_use_up_the_token() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let totp = enroll(&state, 1).await?;
+
+        let pending = state.mfa_challenge(1).await?;
+        let ret = state
+            .mfa_complete(
+                &MfaSignInPayload {
+                    mfa_token: pending.mfa_token.clone(),
+                    code: "000000".to_string(),
+                },
+                &ClientInfo::default(),
+            )
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        // a wrong code means starting over
+        let input = MfaSignInPayload {
+            mfa_token: pending.mfa_token,
+            code: current_code(&totp),
+        };
+        let client = ClientInfo::default();
+        assert!(state.mfa_complete(&input, &client).await.is_err());
+
+        // the wrong code backs the account off like a wrong password
+        let pending = state.mfa_challenge(1).await?;
+        let input = MfaSignInPayload {
+            mfa_token: pending.mfa_token,
+            code: current_code(&totp),
+        };
+        let ret = state.mfa_complete(&input, &client).await;
+        assert!(matches!(ret, Err(AppError::TooManyAttempts(_))));
+
+        sqlx::query("UPDATE signin_throttles SET blocked_until = NULL")
+            .execute(&state.pool)
+            .await?;
+        let pending = state.mfa_challenge(1).await?;
+        let input = MfaSignInPayload {
+            mfa_token: pending.mfa_token,
+            code: current_code(&totp),
+        };
+        assert_eq!(state.mfa_complete(&input, &client).await?.id, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mfa_disable_should_respect_workspace_policy() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        let totp = enroll(&state, 1).await?;
+        state
+            .workspace_settings_update(
+                1,
+                UpdateWorkspaceSettings {
+                    require_mfa: Some(Some(true)),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let ret = state
+            .mfa_disable(&user, &current_code(&totp), &ClientInfo::default())
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        // user 2 of the same workspace hasn't set it up
+        let other = state.user_fetch_by_id(2).await?.unwrap();
+        assert!(state.mfa_missing(&other).await?);
+        assert!(!state.mfa_missing(&user).await?);
+
+        state
+            .workspace_settings_update(
+                1,
+                UpdateWorkspaceSettings {
+                    require_mfa: Some(None),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        state
+            .mfa_disable(&user, &current_code(&totp), &ClientInfo::default())
+            .await?;
+        assert!(!state.mfa_enabled(1).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mfa_changes_should_be_throttled() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        let totp = enroll(&state, 1).await?;
+        let client = ClientInfo::default();
+
+        let ret = state.mfa_disable(&user, "000000", &client).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        // guessing on backs off like a wrong password, even the right code waits
+        let ret = state
+            .mfa_recovery_codes_regenerate(&user, &current_code(&totp), &client)
+            .await;
+        assert!(matches!(ret, Err(AppError::TooManyAttempts(_))));
+        let ret = state
+            .mfa_disable(&user, &current_code(&totp), &client)
+            .await;
+        assert!(matches!(ret, Err(AppError::TooManyAttempts(_))));
+        assert!(state.mfa_enabled(1).await?);
+
+        sqlx::query("UPDATE signin_throttles SET blocked_until = NULL")
+            .execute(&state.pool)
+            .await?;
+        state
+            .mfa_disable(&user, &current_code(&totp), &client)
+            .await?;
+        assert!(!state.mfa_enabled(1).await?);
+        Ok(())
+    }
+}
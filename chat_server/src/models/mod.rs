@@ -4,15 +4,46 @@ use serde::{Deserialize, Serialize};
 
 use crate::AppError;
 
+mod access_token;
 mod chat;
 mod file;
+mod file_migration;
+mod file_text;
+mod file_url;
+mod magic_link;
+mod mail;
 mod message;
+mod mfa;
+mod password;
+mod profile;
+mod session;
+mod signin;
+mod sso;
+mod status;
+mod token;
 mod user;
+mod user_token;
+mod verification;
 mod workspace;
 
+pub use access_token::*;
 pub use chat::*;
+pub use file::*;
+pub use file_migration::*;
+pub use file_url::*;
+pub use magic_link::*;
 pub use message::*;
+pub use mfa::*;
+pub use password::*;
+pub use profile::*;
+pub use session::*;
+pub use sso::*;
+pub use status::*;
+pub use token::*;
 pub use user::*;
+pub use user_token::*;
+pub use verification::*;
+pub use workspace::*;
 
 const DEFAULT_OWNER_ID: i64 = 0;
 
@@ -26,6 +57,8 @@ pub struct ChatFile {
 impl FromStr for ChatFile {
     type Err = AppError;
 
+    /// The only way to turn an untrusted file url into a `ChatFile`, it rejects
+    /// anything that is not exactly a path `ChatFile::url` could have produced.
     fn from_str(file_url: &str) -> Result<Self, Self::Err> {
         // file format: /files/0/a94/a8f/e5ccb19ba61c4c0873d391e987982fbbd3.txt
         let Some(s) = file_url.strip_prefix("/files/") else {
@@ -57,7 +90,27 @@ impl FromStr for ChatFile {
             )));
         };
 
+        if parts[1].len() != 3 || parts[2].len() != 3 {
+            return Err(AppError::InvalidChatFilePath(format!(
+                "Invalid chat file path {}",
+                file_url
+            )));
+        }
         let hash = parts[1].to_owned() + parts[2] + part3;
+        if !matches!(hash.len(), HASH_LEN | LEGACY_HASH_LEN)
+            || !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        {
+            return Err(AppError::InvalidChatFilePath(format!(
+                "Invalid file hash {}",
+                hash
+            )));
+        }
+        if !is_valid_ext(ext) {
+            return Err(AppError::InvalidChatFilePath(format!(
+                "Invalid file extension {}",
+                ext
+            )));
+        }
 
         Ok(Self {
             ws_id,
@@ -66,3 +119,69 @@ impl FromStr for ChatFile {
         })
     }
 }
+
+/// lowercase hex sha256
+const HASH_LEN: usize = 64;
+/// files uploaded before the switch to sha256 are addressed by sha1
+const LEGACY_HASH_LEN: usize = 40;
+const MAX_EXT_LEN: usize = 16;
+
+impl ChatFile {
+    pub fn is_legacy(&self) -> bool {
+        self.hash.len() == LEGACY_HASH_LEN
+    }
+}
+
+pub(crate) fn is_valid_ext(ext: &str) -> bool {
+    !ext.is_empty()
+        && ext.len() <= MAX_EXT_LEN
+        && ext
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_file_from_str_should_work() {
+        let file: ChatFile = "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt"
+            .parse()
+            .unwrap();
+        assert_eq!(file.ws_id, 1);
+        assert_eq!(file.hash, "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d");
+        assert_eq!(file.ext, "txt");
+        assert!(file.is_legacy());
+
+        let file: ChatFile =
+            "/files/1/2cf/24d/ba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824.txt"
+                .parse()
+                .unwrap();
+        assert!(!file.is_legacy());
+    }
+
+    #[test]
+    fn chat_file_from_str_should_reject_malformed_paths() {
+        let invalid = [
+            "/files/1/../4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt",
+            "/files/1/aaf/4c6/../../../../etc/passwd",
+            "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt/x",
+            "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea94.txt",
+            "/files/1/AAF/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt",
+            "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434z.txt",
+            "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.",
+            "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.tar.gz",
+            "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.HTML",
+            "/files/1/aaf4/c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt",
+            "/files/1/2cf/24d/ba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b98.txt",
+        ];
+        for url in invalid {
+            assert!(
+                url.parse::<ChatFile>().is_err(),
+                "{} should be rejected",
+                url
+            );
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::{collections::HashSet, sync::LazyLock};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{mail::expires_in, signin::ThrottleAction, ClientInfo, UserTokenPurpose};
+use crate::{AppError, AppState, Mail, User};
+
+static BREACHED_PASSWORDS: LazyLock<HashSet<&'static str>> = LazyLock::new(|| {
+    include_str!("../../assets/breached_passwords.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForgotPasswordPayload {
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResetPasswordPayload {
+    pub token: String,
+    pub password: String,
+}
+
+impl AppState {
+    /// Check a new password against the configured policy
+    pub fn password_check(&self, email: &str, password: &str) -> Result<(), AppError> {
+        let policy = &self.config.auth.password;
+        let len = password.chars().count();
+        if len < policy.min_length {
+            return Err(AppError::WeakPassword(format!(
+                "must be at least {} characters",
+                policy.min_length
+            )));
+        }
+        if len > policy.max_length {
+            return Err(AppError::WeakPassword(format!(
+                "must be at most {} characters",
+                policy.max_length
+            )));
+        }
+        let password = password.to_lowercase();
+        if policy.reject_breached && BREACHED_PASSWORDS.contains(password.as_str()) {
+            return Err(AppError::WeakPassword(
+                "it is a commonly used password found in data breaches".to_string(),
+            ));
+        }
+        if policy.reject_email {
+            let email = email.trim().to_lowercase();
+            let local = email.split('@').next().unwrap_or_default();
+            // very short local parts would reject too much
+            if password.contains(&email) || (local.len() >= 3 && password.contains(local)) {
+                return Err(AppError::WeakPassword(
+                    "it must not contain your email".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Mail a password reset link if the email belongs to a user, callers
+    /// can't tell whether it does.
+    pub async fn password_forgot(&self, email: &str, client: &ClientInfo) -> Result<(), AppError> {
+        let throttle = self.throttle(ThrottleAction::PasswordForgot, email, client);
+        self.throttle_attempt(&throttle).await?;
+        let Some(user) = self.user_find_by_email(email).await? else {
+            return Ok(());
+        };
+        // answer before creating the token so a known email doesn't take
+        // longer than an unknown one
+        let state = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = state.password_reset_mail(user).await {
+                warn!("failed to mail a password reset: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    async fn password_reset_mail(&self, user: User) -> Result<(), AppError> {
+        let ttl = self.config.auth.password_reset_ttl_secs;
+        let token = self
+            .user_token_create(user.id, UserTokenPurpose::PasswordReset, ttl)
+            .await?;
+        let body = format!(
+            "Hi {},\n\n\
+             Open the link below to choose a new password, it expires in {}:\n\n\
+             {}\n\n\
+             If you didn't ask to reset your password, you can ignore this mail.\n",
+            user.fullname,
+            expires_in(ttl),
+            self.mail_link("reset-password", &token)
+        );
+        self.mail_spawn(Mail {
+            to: user.email,
+            subject: "Reset your password".to_string(),
+            body,
+        });
+        Ok(())
+    }
+
+    /// Set a new password with a mailed reset token, the user is signed out
+    /// everywhere.
+    pub async fn password_reset(&self, input: ResetPasswordPayload) -> Result<(), AppError> {
+        let invalid = || AppError::InvalidToken("invalid or expired reset token".to_string());
+        // a rejected password doesn't use up the token
+        let Some(user_id) = self
+            .user_token_peek(&input.token, UserTokenPurpose::PasswordReset)
+            .await?
+        else {
+            return Err(invalid());
+        };
+        let Some(user) = self.user_fetch_by_id(user_id).await? else {
+            return Err(invalid());
+        };
+        self.password_check(&user.email, &input.password)?;
+        let password_hash = self.password_hash(&input.password)?;
+        if self
+            .user_token_consume(&input.token, UserTokenPurpose::PasswordReset)
+            .await?
+            != Some(user_id)
+        {
+            return Err(invalid());
+        }
+        // the mailed token proves the user owns the email
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET password_hash = $1, verified_at = COALESCE(verified_at, CURRENT_TIMESTAMP)
+            WHERE id = $2
+            "#,
+        )
+        .bind(password_hash)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        self.users.invalidate(user_id);
+        self.session_revoke_all(user_id).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mailer::tests::{mailed_token, wait_for_mail},
+        models::ClientInfo,
+    };
+
+    #[tokio::test]
+    async fn password_reset_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE users SET verified_at = NULL WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        assert!(!user.is_verified());
+        let session = state
+            .token_issue(user.clone(), &ClientInfo::default())
+            .await?;
+
+        state
+            .password_forgot(&user.email, &ClientInfo::default())
+            .await?;
+        let eml = wait_for_mail(&state.config.server.base_dir.join("mail")).await;
+        assert!(eml.contains(&format!("To: {}", user.email)));
+        assert!(eml.contains("expires in 1 hour:"));
+        let token = mailed_token(&eml);
+
+        // a weak password is rejected and the token kept
+        let weak = ResetPasswordPayload {
+            token: token.clone(),
+            password: "password1".to_string(),
+        };
+        let ret = state.password_reset(weak).await;
+        assert!(matches!(ret, Err(AppError::WeakPassword(_))));
+
+        let input = ResetPasswordPayload {
+            token: token.clone(),
+            password: "new password".to_string(),
+        };
+        state.password_reset(input.clone()).await?;
+        assert!(state
+            .user_verify(&user.email, "new password")
+            .await?
+            .is_some());
+        // existing sign ins are revoked
+        assert!(state.token_refresh(&session.refresh_token).await.is_err());
+        // the email is confirmed by the mailed token
+        assert!(state.user_fetch_by_id(1).await?.unwrap().is_verified());
+        // the token is single use
+        let ret = state.password_reset(input).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn password_check_should_enforce_policy() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let email = "alice.smith@acme.org";
+        let weak = |password: &str| {
+            matches!(
+                state.password_check(email, password),
+                Err(AppError::WeakPassword(_))
+            )
+        };
+        assert!(weak(""));
+        assert!(weak("short"));
+        assert!(weak(&"a".repeat(129)));
+        assert!(weak("Password123"));
+        assert!(weak("QWERTYUIOP"));
+        assert!(weak("my Alice.Smith pass"));
+        assert!(weak("alice.smith@acme.org!"));
+        state.password_check(email, "correct horse battery")?;
+        // short local parts are ignored
+        state.password_check("al@acme.org", "totally alright")?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn password_forgot_should_not_reveal_unknown_email() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state
+            .password_forgot("nobody@example.com", &ClientInfo::default())
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use chat_core::UserProfile;
+use serde::{Deserialize, Serialize};
+
+use super::{workspace::nullable, FileCategory};
+use crate::{AppError, AppState, User};
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_TITLE_LEN: usize = 128;
+const MAX_LOCALE_LEN: usize = 35;
+
+/// A missing field is left unchanged, `null` or an empty string clears it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateProfile {
+    #[serde(default)]
+    pub fullname: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub timezone: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub locale: Option<Option<String>>,
+    // url of an image the user uploaded
+    #[serde(default, deserialize_with = "nullable")]
+    pub avatar_url: Option<Option<String>>,
+}
+
+impl AppState {
+    /// The profile of a user in workspace `ws_id`, users of other workspaces
+    /// aren't found
+    pub async fn user_profile(&self, id: i64, ws_id: i64) -> Result<Option<UserProfile>, AppError> {
+        let profile = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, fullname, email, display_name, title, timezone, locale,
+                avatar_url, updated_at
+            FROM users WHERE id = $1 AND ws_id = $2
+            "#,
+        )
+        .bind(id)
+        .bind(ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(profile)
+    }
+
+    /// Update the user's own profile, members of the workspace are notified
+    /// through `user_updated`
+    pub async fn user_profile_update(
+        &self,
+        user: &User,
+        input: UpdateProfile,
+    ) -> Result<UserProfile, AppError> {
+        let fullname = match input.fullname.as_deref().map(str::trim) {
+            Some("") => {
+                return Err(AppError::InvalidProfile(
+                    "fullname can't be empty".to_string(),
+                ))
+            }
+            Some(fullname) => Some(check_len("fullname", fullname, MAX_NAME_LEN)?),
+            None => None,
+        };
+        let display_name = optional_text(&input.display_name)
+            .map(|name| {
+                name.map(|name| check_len("display name", name, MAX_NAME_LEN))
+                    .transpose()
+            })
+            .transpose()?;
+        let title = optional_text(&input.title)
+            .map(|title| {
+                title
+                    .map(|title| check_len("title", title, MAX_TITLE_LEN))
+                    .transpose()
+            })
+            .transpose()?;
+        let timezone = optional_text(&input.timezone);
+        if let Some(Some(timezone)) = timezone {
+            self.timezone_check(timezone).await?;
+        }
+        let locale = optional_text(&input.locale);
+        if let Some(Some(locale)) = locale {
+            if !is_valid_locale(locale) {
+                return Err(AppError::InvalidProfile(format!(
+                    "{} is not a language tag",
+                    locale
+                )));
+            }
+        }
+        let avatar_url = optional_text(&input.avatar_url);
+        if let Some(Some(url)) = avatar_url {
+            self.avatar_check(user, url).await?;
+        }
+
+        let profile = sqlx::query_as(
+            r#"
+            UPDATE users SET
+                fullname = COALESCE($2, fullname),
+                display_name = CASE WHEN $4 THEN $3 ELSE display_name END,
+                title = CASE WHEN $6 THEN $5 ELSE title END,
+                timezone = CASE WHEN $8 THEN $7 ELSE timezone END,
+                locale = CASE WHEN $10 THEN $9 ELSE locale END,
+                avatar_url = CASE WHEN $12 THEN $11 ELSE avatar_url END,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            RETURNING id, ws_id, fullname, email, display_name, title, timezone, locale,
+                avatar_url, updated_at
+            "#,
+        )
+        .bind(user.id)
+        .bind(fullname)
+        .bind(display_name.flatten())
+        .bind(display_name.is_some())
+        .bind(title.flatten())
+        .bind(title.is_some())
+        .bind(timezone.flatten())
+        .bind(timezone.is_some())
+        .bind(locale.flatten())
+        .bind(locale.is_some())
+        .bind(avatar_url.flatten())
+        .bind(avatar_url.is_some())
+        .fetch_one(&self.pool)
+        .await?;
+        self.users.invalidate(user.id);
+        Ok(profile)
+    }
+
+    async fn timezone_check(&self, timezone: &str) -> Result<(), AppError> {
+        let (known,): (bool,) =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM pg_timezone_names WHERE name = $1)")
+                .bind(timezone)
+                .fetch_one(&self.pool)
+                .await?;
+        if !known {
+            return Err(AppError::InvalidProfile(format!(
+                "unknown time zone {}",
+                timezone
+            )));
+        }
+        Ok(())
+    }
+
+    // an image of the user's workspace they uploaded or can see
+    async fn avatar_check(&self, user: &User, url: &str) -> Result<(), AppError> {
+        let file = self.file_parse(url).await?;
+        if file.ws_id != user.ws_id as u64 || file.url() != url {
+            return Err(AppError::InvalidProfile(
+                "avatar must be a file of your workspace".to_string(),
+            ));
+        }
+        if !FileCategory::Images.exts().contains(&file.ext.as_str()) {
+            return Err(AppError::InvalidProfile(
+                "avatar must be an image".to_string(),
+            ));
+        }
+        if !self.file_can_access(url, user.id as _, None).await? {
+            return Err(AppError::PermissionDenied(
+                "you can't access this file".to_string(),
+            ));
+        }
+        self.file_check_available(url).await
+    }
+}
+
+// trimmed, an empty string clears the field like `null`
+fn optional_text(field: &Option<Option<String>>) -> Option<Option<&str>> {
+    field.as_ref().map(|value| {
+        value
+            .as_deref()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    })
+}
+
+fn check_len<'a>(field: &str, value: &'a str, max: usize) -> Result<&'a str, AppError> {
+    if value.chars().count() > max {
+        return Err(AppError::InvalidProfile(format!(
+            "{} must be at most {} characters",
+            field, max
+        )));
+    }
+    Ok(value)
+}
+
+// a BCP 47 tag like `en`, `zh-Hant-TW` or `es-419`, not checked against the
+// registry
+fn is_valid_locale(locale: &str) -> bool {
+    let mut subtags = locale.split('-');
+    let language = subtags.next().unwrap_or_default();
+    locale.len() <= MAX_LOCALE_LEN
+        && (2..=8).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|tag| {
+            (1..=8).contains(&tag.len()) && tag.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(json: &str) -> UpdateProfile {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn user_profile_update_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        let profile = state
+            .user_profile_update(
+                &user,
+                update(
+                    r#"{"display_name": " Tyr ", "title": "Engineer",
+                        "timezone": "Asia/Shanghai", "locale": "zh-CN"}"#,
+                ),
+            )
+            .await?;
+        assert_eq!(profile.display_name.as_deref(), Some("Tyr"));
+        assert_eq!(profile.timezone.as_deref(), Some("Asia/Shanghai"));
+        assert_eq!(profile.fullname, user.fullname);
+
+        // missing fields are kept, null clears
+        let profile = state
+            .user_profile_update(&user, update(r#"{"title": null, "fullname": "Tyr Chen"}"#))
+            .await?;
+        assert_eq!(profile.title, None);
+        assert_eq!(profile.locale.as_deref(), Some("zh-CN"));
+        assert_eq!(
+            state.user_fetch_by_id(1).await?.unwrap().fullname,
+            "Tyr Chen"
+        );
+
+        // members of the workspace see it, others don't
+        assert_eq!(state.user_profile(1, 1).await?, Some(profile));
+        assert_eq!(state.user_profile(1, 2).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_profile_update_should_validate() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        for json in [
+            r#"{"fullname": " "}"#,
+            r#"{"timezone": "Mars/Olympus"}"#,
+            r#"{"locale": "not a locale"}"#,
+        ] {
+            let ret = state.user_profile_update(&user, update(json)).await;
+            assert!(matches!(ret, Err(AppError::InvalidProfile(_))), "{}", json);
+        }
+        let long = format!(r#"{{"display_name": "{}"}}"#, "a".repeat(65));
+        let ret = state.user_profile_update(&user, update(&long)).await;
+        assert!(matches!(ret, Err(AppError::InvalidProfile(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn avatar_should_be_an_uploaded_image() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        let gif = b"GIF89a\x01\0\x01\0\0\0\0;";
+        let image = state.file_upload(1, 1, "me.gif".to_string(), gif).await?;
+        let text = state
+            .file_upload(1, 1, "notes.txt".to_string(), b"hello")
+            .await?;
+
+        let ret = state
+            .user_profile_update(
+                &user,
+                update(&format!(r#"{{"avatar_url": "{}"}}"#, text.path)),
+            )
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidProfile(_))));
+        // user 2 didn't upload it
+        let other = state.user_fetch_by_id(2).await?.unwrap();
+        let json = format!(r#"{{"avatar_url": "{}"}}"#, image.path);
+        let ret = state.user_profile_update(&other, update(&json)).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let profile = state.user_profile_update(&user, update(&json)).await?;
+        assert_eq!(profile.avatar_url, Some(image.path.clone()));
+        // members of the workspace can download it
+        assert!(state.file_can_access(&image.path, 2, None).await?);
+        assert!(!state.file_can_access(&image.path, 3, None).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn is_valid_locale_should_work() {
+        for locale in ["en", "zh-CN", "zh-Hant-TW", "es-419"] {
+            assert!(is_valid_locale(locale), "{}", locale);
+        }
+        for locale in ["", "e", "en_US", "en-", "zh CN"] {
+            assert!(!is_valid_locale(locale), "{}", locale);
+        }
+    }
+}
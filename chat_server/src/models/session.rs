@@ -0,0 +1,184 @@
+use chat_core::SessionId;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use uuid::Uuid;
+
+use crate::{AppError, AppState};
+
+/// Where a sign in came from, recorded with its session
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    // the session of the token used to list the sessions
+    pub current: bool,
+}
+
+impl AppState {
+    pub async fn session_create(
+        &self,
+        user_id: i64,
+        client: &ClientInfo,
+    ) -> Result<SessionId, AppError> {
+        let id = Uuid::now_v7();
+        sqlx::query("INSERT INTO sessions (id, user_id, user_agent, ip) VALUES ($1, $2, $3, $4)")
+            .bind(id)
+            .bind(user_id)
+            .bind(&client.user_agent)
+            .bind(&client.ip)
+            .execute(&self.pool)
+            .await?;
+        Ok(SessionId(id))
+    }
+
+    /// Sessions that are neither revoked nor expired, most recently seen first
+    pub async fn session_list(
+        &self,
+        user_id: i64,
+        current: Option<SessionId>,
+    ) -> Result<Vec<Session>, AppError> {
+        let sessions = sqlx::query_as(
+            r#"
+            SELECT s.id, s.user_agent, s.ip, s.created_at, s.last_seen_at,
+                s.id IS NOT DISTINCT FROM $2 AS current
+            FROM sessions s
+            WHERE s.user_id = $1 AND s.revoked_at IS NULL
+            AND EXISTS (
+                SELECT 1 FROM refresh_tokens rt
+                WHERE rt.family_id = s.id AND rt.used_at IS NULL
+                AND rt.revoked_at IS NULL AND rt.expires_at > CURRENT_TIMESTAMP
+            )
+            ORDER BY s.last_seen_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .bind(current.map(|id| id.0))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(sessions)
+    }
+
+    /// Revoke a session of the user along with its refresh tokens, returns
+    /// false if there is no such active session.
+    pub async fn session_revoke(&self, user_id: i64, id: SessionId) -> Result<bool, AppError> {
+        let revoked = sqlx::query(
+            r#"
+            UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(id.0)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if revoked == 0 {
+            return Ok(false);
+        }
+        self.refresh_token_revoke_family(id.0).await?;
+        self.sessions.revoke(id);
+        Ok(true)
+    }
+
+    /// Revoke every session of the user, e.g. to sign out everywhere
+    pub async fn session_revoke_all(&self, user_id: i64) -> Result<Vec<SessionId>, AppError> {
+        let ids: Vec<(Uuid,)> = sqlx::query_as(
+            r#"
+            UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND revoked_at IS NULL
+            RETURNING id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        let ids: Vec<SessionId> = ids.into_iter().map(|(id,)| SessionId(id)).collect();
+        for id in &ids {
+            self.sessions.revoke(*id);
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat_core::{TokenClaims, TokenVerify};
+
+    use super::*;
+
+    fn client(user_agent: &str) -> ClientInfo {
+        ClientInfo {
+            user_agent: Some(user_agent.to_string()),
+            ip: Some("127.0.0.1".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn session_list_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        let phone = state.token_issue(user.clone(), &client("phone")).await?;
+        state.token_issue(user, &client("laptop")).await?;
+
+        let current = state.verify(&phone.token).await?.session_id;
+        let sessions = state.session_list(1, current).await?;
+        assert_eq!(sessions.len(), 2);
+        let phone = sessions.iter().find(|s| s.current).unwrap();
+        assert_eq!(phone.user_agent.as_deref(), Some("phone"));
+        assert_eq!(phone.ip.as_deref(), Some("127.0.0.1"));
+        assert!(state.session_list(2, None).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_revoke_should_invalidate_tokens() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        let output = state.token_issue(user.clone(), &client("phone")).await?;
+        let other = state.token_issue(user, &client("laptop")).await?;
+        let session_id = state.verify(&output.token).await?.session_id.unwrap();
+
+        // only the owner can revoke a session
+        assert!(!state.session_revoke(2, session_id).await?);
+        assert!(state.verify(&output.token).await.is_ok());
+
+        assert!(state.session_revoke(1, session_id).await?);
+        assert!(state.verify(&output.token).await.is_err());
+        assert!(state.token_refresh(&output.refresh_token).await.is_err());
+        assert!(state.verify(&other.token).await.is_ok());
+        assert_eq!(state.session_list(1, None).await?.len(), 1);
+
+        let revoked = state.session_revoke_all(1).await?;
+        assert_eq!(revoked.len(), 1);
+        assert!(state.verify(&other.token).await.is_err());
+        assert!(state.token_refresh(&other.refresh_token).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_should_reject_tokens_without_session() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = state.sk.sign(&TokenClaims::new(1, 1, None), 60)?;
+        assert!(state.verify(&token).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,397 @@
+use chrono::{DateTime, Utc};
+use sqlx::prelude::FromRow;
+use tracing::warn;
+
+use super::{ClientInfo, SignInPayload};
+use crate::{AppError, AppState, User};
+
+/// What attempts are counted for, each has its own counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ThrottleAction {
+    // password sign ins and the 2FA codes that finish them
+    SignIn,
+    MagicLink,
+    PasswordForgot,
+    // starting a single sign-on, finishing it gives the attempt back
+    SsoLogin,
+}
+
+/// The counters an attempt is held against
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    account: Option<ThrottleKey>,
+    ip: Option<ThrottleKey>,
+}
+
+#[derive(Debug)]
+struct ThrottleKey {
+    key: String,
+    max_failures: u32,
+    // failures before the backoff starts, an IP may be shared by many users
+    // signing in at the same time
+    free_failures: u32,
+}
+
+#[derive(Debug, FromRow)]
+struct ThrottleRow {
+    key: String,
+    failures: i32,
+    blocked_until: Option<DateTime<Utc>>,
+    // no failures for a lockout period, they're forgotten
+    stale: bool,
+}
+
+impl ThrottleAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ThrottleAction::SignIn => "signin",
+            ThrottleAction::MagicLink => "magic_link",
+            ThrottleAction::PasswordForgot => "password_forgot",
+            ThrottleAction::SsoLogin => "sso_login",
+        }
+    }
+}
+
+impl Throttle {
+    fn keys(&self) -> impl Iterator<Item = &ThrottleKey> {
+        self.account.iter().chain(self.ip.as_ref())
+    }
+}
+
+impl AppState {
+    /// Check a password sign in. Attempts are throttled per account and per
+    /// IP, unknown emails and wrong passwords fail alike. Users with 2FA on
+    /// keep the attempt on their account until the code checked out.
+    pub async fn signin(
+        &self,
+        input: &SignInPayload,
+        client: &ClientInfo,
+    ) -> Result<User, AppError> {
+        let throttle = self.throttle(ThrottleAction::SignIn, &input.email, client);
+        self.throttle_attempt(&throttle).await?;
+        match self.user_verify(&input.email, &input.password).await {
+            Ok(Some(user)) => {
+                let mfa_pending = self.mfa_enabled(user.id).await?;
+                self.throttle_succeeded(&throttle, mfa_pending).await?;
+                Ok(user)
+            }
+            Ok(None) | Err(AppError::InvalidCredentials) => Err(AppError::InvalidCredentials),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(crate) fn throttle(
+        &self,
+        action: ThrottleAction,
+        email: &str,
+        client: &ClientInfo,
+    ) -> Throttle {
+        let config = &self.config.auth.signin;
+        Throttle {
+            account: Some(ThrottleKey {
+                key: format!("{}:email:{}", action.as_str(), email.trim().to_lowercase()),
+                max_failures: config.account_max_failures,
+                free_failures: 0,
+            }),
+            ..self.throttle_ip(action, client)
+        }
+    }
+
+    /// Attempts that aren't about an account are only counted per IP
+    pub(crate) fn throttle_ip(&self, action: ThrottleAction, client: &ClientInfo) -> Throttle {
+        let config = &self.config.auth.signin;
+        Throttle {
+            account: None,
+            ip: client.ip.as_ref().map(|ip| ThrottleKey {
+                key: format!("{}:ip:{}", action.as_str(), ip),
+                max_failures: config.ip_max_failures,
+                free_failures: 1,
+            }),
+        }
+    }
+
+    /// Count an attempt as failed before checking it, so parallel attempts
+    /// can't all get through before the first failure is recorded. The wait
+    /// before the next attempt doubles with every failure until the key is
+    /// locked out. Failures are forgotten after a lockout period without any.
+    pub(crate) async fn throttle_attempt(&self, throttle: &Throttle) -> Result<(), AppError> {
+        let config = &self.config.auth.signin;
+        let mut keys: Vec<&str> = throttle.keys().map(|key| key.key.as_str()).collect();
+        keys.sort_unstable();
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO signin_throttles (key, failures) SELECT unnest($1::text[]), 0
+            ON CONFLICT (key) DO NOTHING
+            "#,
+        )
+        .bind(&keys)
+        .execute(&mut *tx)
+        .await?;
+        // attempts on the same keys wait for each other here
+        let rows: Vec<ThrottleRow> = sqlx::query_as(
+            r#"
+            SELECT key, failures,
+                CASE WHEN blocked_until > CURRENT_TIMESTAMP THEN blocked_until END AS blocked_until,
+                updated_at < CURRENT_TIMESTAMP - make_interval(secs => $2) AS stale
+            FROM signin_throttles WHERE key = ANY($1)
+            ORDER BY key
+            FOR UPDATE
+            "#,
+        )
+        .bind(&keys)
+        .bind(config.lockout_secs as f64)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        if let Some(until) = rows.iter().filter_map(|row| row.blocked_until).max() {
+            let millis = (until - Utc::now()).num_milliseconds().max(0) as u64;
+            return Err(AppError::TooManyAttempts(millis.div_ceil(1000).max(1)));
+        }
+
+        for row in rows {
+            let Some(key) = throttle.keys().find(|key| key.key == row.key) else {
+                continue;
+            };
+            let failures = if row.stale {
+                1
+            } else {
+                row.failures as u32 + 1
+            };
+            let delay = if failures >= key.max_failures {
+                warn!("{} locked out after {} failed attempts", key.key, failures);
+                config.lockout_secs
+            } else if failures <= key.free_failures {
+                0
+            } else {
+                let backoff = config
+                    .backoff_base_secs
+                    .saturating_mul(1 << (failures - key.free_failures - 1).min(32));
+                backoff.min(config.backoff_max_secs)
+            };
+            sqlx::query(
+                r#"
+                UPDATE signin_throttles
+                SET failures = $2,
+                    blocked_until = CURRENT_TIMESTAMP + make_interval(secs => $3),
+                    updated_at = CURRENT_TIMESTAMP
+                WHERE key = $1
+                "#,
+            )
+            .bind(&key.key)
+            .bind(failures as i32)
+            .bind(delay as f64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// The attempt went through. The IP gets it back, the account's failures
+    /// are forgotten unless the sign in isn't finished yet, then only the
+    /// next step may go ahead right away.
+    pub(crate) async fn throttle_succeeded(
+        &self,
+        throttle: &Throttle,
+        keep_account: bool,
+    ) -> Result<(), AppError> {
+        let sql = if keep_account {
+            "UPDATE signin_throttles SET blocked_until = NULL WHERE key = $1"
+        } else {
+            "DELETE FROM signin_throttles WHERE key = $1"
+        };
+        if let Some(account) = &throttle.account {
+            sqlx::query(sql)
+                .bind(&account.key)
+                .execute(&self.pool)
+                .await?;
+        }
+        if let Some(ip) = &throttle.ip {
+            sqlx::query(
+                r#"
+                UPDATE signin_throttles SET
+                    failures = greatest(failures - 1, 0),
+                    blocked_until = CASE WHEN failures <= 1 THEN NULL ELSE blocked_until END
+                WHERE key = $1
+                "#,
+            )
+            .bind(&ip.key)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(email: &str, password: &str) -> SignInPayload {
+        SignInPayload {
+            email: email.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    fn client(ip: &str) -> ClientInfo {
+        ClientInfo {
+            ip: Some(ip.to_string()),
+            ..Default::default()
+        }
+    }
+
+    async fn set_password(state: &AppState) -> Result<(), AppError> {
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = 1")
+            .bind(state.password_hash("hunter42")?)
+            .execute(&state.pool)
+            .await?;
+        Ok(())
+    }
+
+    // attempts are let through again right away
+    async fn unblock(state: &AppState) -> Result<(), AppError> {
+        sqlx::query("UPDATE signin_throttles SET blocked_until = NULL")
+            .execute(&state.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_should_fail_alike_for_unknown_accounts() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        set_password(&state).await?;
+        let ret = state
+            .signin(&payload("nobody@acme.org", "hunter42"), &client("10.0.0.1"))
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidCredentials)));
+        let ret = state
+            .signin(&payload("test@yahoo.com", "wrong"), &client("10.0.0.2"))
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidCredentials)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_should_back_off_and_lock_accounts() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        set_password(&state).await?;
+        let max = state.config.auth.signin.account_max_failures;
+
+        let ret = state
+            .signin(&payload("test@yahoo.com", "wrong"), &client("10.0.0.1"))
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidCredentials)));
+        // even the right password has to wait
+        let ret = state
+            .signin(&payload("Test@yahoo.com", "hunter42"), &client("10.0.0.2"))
+            .await;
+        assert!(matches!(ret, Err(AppError::TooManyAttempts(_))));
+
+        for _ in 1..max {
+            unblock(&state).await?;
+            let ret = state
+                .signin(&payload("test@yahoo.com", "wrong"), &client("10.0.0.3"))
+                .await;
+            assert!(matches!(ret, Err(AppError::InvalidCredentials)));
+        }
+        let ret = state
+            .signin(&payload("test@yahoo.com", "hunter42"), &client("10.0.0.4"))
+            .await;
+        let lockout = state.config.auth.signin.lockout_secs;
+        assert!(matches!(ret, Err(AppError::TooManyAttempts(secs)) if secs > lockout - 5));
+
+        // a success clears the account's failures
+        unblock(&state).await?;
+        let user = state
+            .signin(&payload("test@yahoo.com", "hunter42"), &client("10.0.0.4"))
+            .await?;
+        assert_eq!(user.id, 1);
+        let ret = state
+            .signin(&payload("test@yahoo.com", "hunter42"), &client("10.0.0.4"))
+            .await;
+        assert!(ret.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_should_throttle_ips_across_accounts() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        set_password(&state).await?;
+        let max = state.config.auth.signin.ip_max_failures;
+        for i in 0..max {
+            unblock(&state).await?;
+            let email = format!("user{}@acme.org", i);
+            let ret = state
+                .signin(&payload(&email, "guess"), &client("10.0.0.9"))
+                .await;
+            assert!(matches!(ret, Err(AppError::InvalidCredentials)));
+        }
+        let ret = state
+            .signin(&payload("test@yahoo.com", "hunter42"), &client("10.0.0.9"))
+            .await;
+        assert!(matches!(ret, Err(AppError::TooManyAttempts(_))));
+        // other IPs are fine
+        let ret = state
+            .signin(&payload("test@yahoo.com", "hunter42"), &client("10.0.0.10"))
+            .await;
+        assert!(ret.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_should_count_parallel_attempts() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        set_password(&state).await?;
+        let mut attempts = tokio::task::JoinSet::new();
+        for i in 0..8 {
+            let state = state.clone();
+            attempts.spawn(async move {
+                let client = client(&format!("10.0.1.{}", i));
+                state
+                    .signin(&payload("test@yahoo.com", "wrong"), &client)
+                    .await
+            });
+        }
+        let rets = attempts.join_all().await;
+        // the first one holds the rest off until it's checked
+        let checked = rets
+            .iter()
+            .filter(|ret| matches!(ret, Err(AppError::InvalidCredentials)))
+            .count();
+        assert_eq!(checked, 1);
+        assert!(rets.iter().all(|ret| matches!(
+            ret,
+            Err(AppError::InvalidCredentials | AppError::TooManyAttempts(_))
+        )));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_should_keep_the_attempt_until_mfa_is_done() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        set_password(&state).await?;
+        sqlx::query(
+            "INSERT INTO user_totp (user_id, secret, confirmed_at) VALUES (1, '\\x00', CURRENT_TIMESTAMP)",
+        )
+        .execute(&state.pool)
+        .await?;
+        state
+            .signin(&payload("test@yahoo.com", "hunter42"), &client("10.0.0.1"))
+            .await?;
+        let failures: Vec<(String, i32)> =
+            sqlx::query_as("SELECT key, failures FROM signin_throttles ORDER BY key")
+                .fetch_all(&state.pool)
+                .await?;
+        assert_eq!(
+            failures,
+            vec![
+                ("signin:email:test@yahoo.com".to_string(), 1),
+                ("signin:ip:10.0.0.1".to_string(), 0),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,655 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use tracing::info;
+
+use super::{
+    mail::expires_in,
+    signin::ThrottleAction,
+    token::{generate_token, hash_token},
+    ClientInfo, UserTokenPurpose,
+};
+use crate::{
+    oidc::{IdToken, OidcProvider},
+    AppError, AppState, Mail, User,
+};
+
+/// The OpenID Connect provider members of a workspace sign in with
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceSso {
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(skip_serializing)]
+    pub client_secret: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SsoCallbackQuery {
+    pub code: Option<String>,
+    pub state: String,
+    // set by the provider when the sign in failed
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SsoLinkPayload {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct SsoLogin {
+    ws_id: i64,
+    nonce: String,
+    code_verifier: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl WorkspaceSso {
+    fn provider(&self) -> OidcProvider<'_> {
+        OidcProvider {
+            client_id: &self.client_id,
+            client_secret: &self.client_secret,
+        }
+    }
+}
+
+impl AppState {
+    pub async fn workspace_sso(&self, ws_id: i64) -> Result<Option<WorkspaceSso>, AppError> {
+        let sso = sqlx::query_as(
+            "SELECT issuer, client_id, client_secret FROM workspace_sso WHERE ws_id = $1",
+        )
+        .bind(ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(sso)
+    }
+
+    /// Set the provider of a workspace, its metadata has to be reachable at a
+    /// public address
+    pub async fn workspace_sso_update(
+        &self,
+        ws_id: i64,
+        input: WorkspaceSso,
+    ) -> Result<WorkspaceSso, AppError> {
+        self.oidc.discover(&input.issuer).await?;
+        let sso = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_sso (ws_id, issuer, client_id, client_secret)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (ws_id) DO UPDATE SET
+                issuer = excluded.issuer,
+                client_id = excluded.client_id,
+                client_secret = excluded.client_secret,
+                updated_at = CURRENT_TIMESTAMP
+            RETURNING issuer, client_id, client_secret
+            "#,
+        )
+        .bind(ws_id)
+        .bind(&input.issuer)
+        .bind(&input.client_id)
+        .bind(&input.client_secret)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(sso)
+    }
+
+    /// Remove the provider, password sign in is turned back on so members
+    /// aren't locked out
+    pub async fn workspace_sso_delete(&self, ws_id: i64) -> Result<bool, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = sqlx::query("DELETE FROM workspace_sso WHERE ws_id = $1")
+            .bind(ws_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("UPDATE workspace_settings SET password_login = NULL WHERE ws_id = $1")
+            .bind(ws_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(deleted > 0)
+    }
+
+    /// Start signing in to a workspace at its provider, returns the URL to
+    /// send the browser to. Sign ins that aren't finished are throttled per
+    /// IP, they are kept until they expire.
+    pub async fn sso_login_start(
+        &self,
+        ws_name: &str,
+        client: &ClientInfo,
+    ) -> Result<String, AppError> {
+        let throttle = self.throttle_ip(ThrottleAction::SsoLogin, client);
+        self.throttle_attempt(&throttle).await?;
+        sqlx::query("DELETE FROM sso_logins WHERE expires_at < CURRENT_TIMESTAMP")
+            .execute(&self.pool)
+            .await?;
+
+        let workspace = self
+            .workspace_fetch_by_name(ws_name)
+            .await?
+            .ok_or(AppError::WorkspaceNotFound)?;
+        let Some(sso) = self.workspace_sso(workspace.id).await? else {
+            return Err(AppError::NotFound(format!(
+                "workspace {} has no single sign-on",
+                ws_name
+            )));
+        };
+        let metadata = self.oidc.discover(&sso.issuer).await?;
+
+        let (state, nonce, code_verifier) = (generate_token(), generate_token(), generate_token());
+        let expires_at =
+            Utc::now() + chrono::Duration::seconds(self.config.auth.sso.login_ttl_secs as _);
+        sqlx::query(
+            r#"
+            INSERT INTO sso_logins (state_hash, ws_id, nonce, code_verifier, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(hash_token(&state))
+        .bind(workspace.id)
+        .bind(&nonce)
+        .bind(&code_verifier)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        self.oidc.authorization_url(
+            &metadata,
+            &sso.provider(),
+            &self.config.auth.sso.redirect_url,
+            &state,
+            &nonce,
+            &code_verifier,
+        )
+    }
+
+    /// Finish signing in when the provider redirects back, returns the user
+    /// the provider account belongs to, or `None` if the user has to confirm
+    /// linking it first
+    pub async fn sso_login_finish(
+        &self,
+        query: &SsoCallbackQuery,
+        client: &ClientInfo,
+    ) -> Result<Option<User>, AppError> {
+        // a state can only be used once
+        let login: Option<SsoLogin> = sqlx::query_as(
+            r#"
+            DELETE FROM sso_logins WHERE state_hash = $1
+            RETURNING ws_id, nonce, code_verifier, expires_at
+            "#,
+        )
+        .bind(hash_token(&query.state))
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(login) = login else {
+            return Err(AppError::InvalidToken("unknown sign in state".to_string()));
+        };
+        let throttle = self.throttle_ip(ThrottleAction::SsoLogin, client);
+        self.throttle_succeeded(&throttle, false).await?;
+        if login.expires_at < Utc::now() {
+            return Err(AppError::InvalidToken("sign in expired".to_string()));
+        }
+        let code = match (&query.code, &query.error) {
+            (_, Some(error)) => {
+                return Err(AppError::InvalidToken(format!(
+                    "provider rejected sign in: {}",
+                    error
+                )))
+            }
+            (Some(code), None) => code,
+            (None, None) => return Err(AppError::InvalidToken("missing code".to_string())),
+        };
+
+        let Some(sso) = self.workspace_sso(login.ws_id).await? else {
+            return Err(AppError::NotFound("single sign-on was removed".to_string()));
+        };
+        let metadata = self.oidc.discover(&sso.issuer).await?;
+        let id_token = self
+            .oidc
+            .exchange_code(
+                &metadata,
+                &sso.provider(),
+                &self.config.auth.sso.redirect_url,
+                code,
+                &login.code_verifier,
+                &login.nonce,
+            )
+            .await?;
+        self.sso_user(login.ws_id, id_token).await
+    }
+
+    /// Link the provider account of a mailed confirmation to its user, the
+    /// user signs in with it from now on
+    pub async fn sso_link_confirm(&self, token: &str) -> Result<User, AppError> {
+        let Some(user_id) = self
+            .user_token_consume(token, UserTokenPurpose::SsoLink)
+            .await?
+        else {
+            return Err(AppError::InvalidToken(
+                "invalid or expired confirmation token".to_string(),
+            ));
+        };
+        let mut tx = self.pool.begin().await?;
+        let linked = sqlx::query(
+            r#"
+            UPDATE user_identities SET confirmed_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND confirmed_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if linked == 0 {
+            return Err(AppError::InvalidToken(
+                "the account to link is gone".to_string(),
+            ));
+        }
+        // the mailed link proves the email too
+        let user: User = sqlx::query_as(
+            r#"
+            UPDATE users SET verified_at = COALESCE(verified_at, CURRENT_TIMESTAMP)
+            WHERE id = $1
+            RETURNING id, ws_id, fullname, email, verified_at, created_at, updated_at
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.users.invalidate(user_id);
+        info!("user {} confirmed linking a provider account", user_id);
+        Ok(user)
+    }
+
+    /// The user a provider account is linked to. Unlinked accounts with the
+    /// email of a user are only linked once the user confirmed it by mail,
+    /// otherwise a new user is created in the workspace.
+    async fn sso_user(&self, ws_id: i64, id_token: IdToken) -> Result<Option<User>, AppError> {
+        let user_id: Option<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT user_id FROM user_identities
+            WHERE issuer = $1 AND subject = $2 AND confirmed_at IS NOT NULL
+            "#,
+        )
+        .bind(&id_token.issuer)
+        .bind(&id_token.subject)
+        .fetch_optional(&self.pool)
+        .await?;
+        let user = match user_id {
+            Some((user_id,)) => self.user_fetch_by_id(user_id).await?,
+            None => None,
+        };
+        let user = match user {
+            Some(user) => user,
+            None => match self.sso_user_link(ws_id, &id_token).await? {
+                Some(user) => user,
+                None => return Ok(None),
+            },
+        };
+        if user.ws_id != ws_id {
+            return Err(AppError::PermissionDenied(
+                "the account belongs to another workspace".to_string(),
+            ));
+        }
+        Ok(Some(user))
+    }
+
+    async fn sso_user_link(
+        &self,
+        ws_id: i64,
+        id_token: &IdToken,
+    ) -> Result<Option<User>, AppError> {
+        let claims = &id_token.claims;
+        let Some(email) = claims.email.as_deref().filter(|_| claims.email_verified) else {
+            return Err(AppError::InvalidToken(
+                "the provider didn't confirm an email".to_string(),
+            ));
+        };
+        match self.user_find_by_email(email).await? {
+            Some(user) if user.ws_id != ws_id => Err(AppError::PermissionDenied(
+                "the account belongs to another workspace".to_string(),
+            )),
+            // any provider can claim an email, the user has to confirm
+            Some(user) => {
+                self.sso_link_request(&user, id_token).await?;
+                Ok(None)
+            }
+            None => {
+                let fullname = claims
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| email.split('@').next().unwrap_or(email).to_string());
+                let mut tx = self.pool.begin().await?;
+                let user: User = sqlx::query_as(
+                    r#"
+                    INSERT INTO users (ws_id, fullname, email, verified_at)
+                    VALUES ($1, $2, $3, CURRENT_TIMESTAMP)
+                    RETURNING id, ws_id, fullname, email, verified_at, created_at, updated_at
+                    "#,
+                )
+                .bind(ws_id)
+                .bind(fullname)
+                .bind(email)
+                .fetch_one(&mut *tx)
+                .await?;
+                sqlx::query(
+                    r#"
+                    INSERT INTO user_identities (user_id, issuer, subject, confirmed_at)
+                    VALUES ($1, $2, $3, CURRENT_TIMESTAMP)
+                    "#,
+                )
+                .bind(user.id)
+                .bind(&id_token.issuer)
+                .bind(&id_token.subject)
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                info!(
+                    "linked {} account {} to new user {}",
+                    id_token.issuer, id_token.subject, user.id
+                );
+                Ok(Some(user))
+            }
+        }
+    }
+
+    /// Keep the provider account as the user's pending link and mail them a
+    /// link to confirm it, earlier requests stop working
+    async fn sso_link_request(&self, user: &User, id_token: &IdToken) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM user_identities WHERE user_id = $1 AND confirmed_at IS NULL")
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO user_identities (user_id, issuer, subject) VALUES ($1, $2, $3)
+            ON CONFLICT (issuer, subject) DO UPDATE SET
+                user_id = excluded.user_id, created_at = CURRENT_TIMESTAMP
+            WHERE user_identities.confirmed_at IS NULL
+            "#,
+        )
+        .bind(user.id)
+        .bind(&id_token.issuer)
+        .bind(&id_token.subject)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        let ttl = self.config.auth.sso.link_ttl_secs;
+        let token = self
+            .user_token_create(user.id, UserTokenPurpose::SsoLink, ttl)
+            .await?;
+        let body = format!(
+            "Hi {},\n\n\
+             Someone signed in with the single sign-on account {} of {}, which \
+             has your email. If it was you, open the link below to sign in with \
+             it from now on, it expires in {}:\n\n\
+             {}\n\n\
+             If it wasn't you, ignore this mail and tell your workspace admin.\n",
+            user.fullname,
+            id_token.subject,
+            id_token.issuer,
+            expires_in(ttl),
+            self.mail_link("sso-link", &token)
+        );
+        self.mail_spawn(Mail {
+            to: user.email.clone(),
+            subject: "Confirm signing in with single sign-on".to_string(),
+            body,
+        });
+        info!(
+            "{} account {} waits for user {} to confirm the link",
+            id_token.issuer, id_token.subject, user.id
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mailer::tests::{mailed_token, wait_for_mail},
+        oidc::tests::{authorize, mock_sso, MockAccount},
+    };
+
+    fn account(subject: &str, email: &str) -> MockAccount {
+        MockAccount {
+            subject: subject.to_string(),
+            email: email.to_string(),
+            email_verified: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn sso_login_should_create_and_link_users() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let idp = mock_sso(&state, 1, account("alice", "alice@acme.org")).await;
+
+        let url = state
+            .sso_login_start("Test ws", &ClientInfo::default())
+            .await?;
+        assert!(url.starts_with(&format!("{}/authorize?", idp.issuer)));
+        let query = authorize(&url).await;
+        let user = state
+            .sso_login_finish(&query, &ClientInfo::default())
+            .await?
+            .unwrap();
+        assert_eq!(user.ws_id, 1);
+        assert_eq!(user.email, "alice@acme.org");
+        assert_eq!(user.fullname, "Mock User");
+        assert!(user.is_verified());
+        // the state is used up
+        assert!(state
+            .sso_login_finish(&query, &ClientInfo::default())
+            .await
+            .is_err());
+
+        // the same provider account signs in as the same user, even if its
+        // email changed
+        idp.set_account(account("alice", "alice@new.acme.org"));
+        let query = authorize(
+            &state
+                .sso_login_start("Test ws", &ClientInfo::default())
+                .await?,
+        )
+        .await;
+        assert_eq!(
+            state
+                .sso_login_finish(&query, &ClientInfo::default())
+                .await?
+                .unwrap()
+                .id,
+            user.id
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sso_login_should_link_existing_users_once_confirmed() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let idp = mock_sso(&state, 1, account("bob", "test@yahoo.com")).await;
+        let mail_dir = state.config.server.base_dir.join("mail");
+
+        // the provider claiming the email isn't enough
+        let query = authorize(
+            &state
+                .sso_login_start("Test ws", &ClientInfo::default())
+                .await?,
+        )
+        .await;
+        assert!(state
+            .sso_login_finish(&query, &ClientInfo::default())
+            .await?
+            .is_none());
+        let eml = wait_for_mail(&mail_dir).await;
+        assert!(eml.contains("To: test@yahoo.com"));
+        // long lines are quoted-printable encoded
+        let body = eml.replace("=\r\n", "").replace("=20", " ");
+        assert!(body.contains(&format!(
+            "Someone signed in with the single sign-on account bob of {}, which has your \
+             email. If it was you, open the link below to sign in with it from now on, it \
+             expires in 24 hours:\r\n",
+            idp.issuer
+        )));
+        let token = mailed_token(&eml);
+
+        // only the latest request can be confirmed
+        std::fs::remove_dir_all(&mail_dir)?;
+        idp.set_account(account("mallory", "test@yahoo.com"));
+        let query = authorize(
+            &state
+                .sso_login_start("Test ws", &ClientInfo::default())
+                .await?,
+        )
+        .await;
+        assert!(state
+            .sso_login_finish(&query, &ClientInfo::default())
+            .await?
+            .is_none());
+        wait_for_mail(&mail_dir).await;
+        let ret = state.sso_link_confirm(&token).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+
+        std::fs::remove_dir_all(&mail_dir)?;
+        idp.set_account(account("bob", "test@yahoo.com"));
+        let query = authorize(
+            &state
+                .sso_login_start("Test ws", &ClientInfo::default())
+                .await?,
+        )
+        .await;
+        assert!(state
+            .sso_login_finish(&query, &ClientInfo::default())
+            .await?
+            .is_none());
+        let eml = wait_for_mail(&mail_dir).await;
+        let user = state.sso_link_confirm(&mailed_token(&eml)).await?;
+        assert_eq!(user.id, 1);
+        assert!(user.is_verified());
+
+        let query = authorize(
+            &state
+                .sso_login_start("Test ws", &ClientInfo::default())
+                .await?,
+        )
+        .await;
+        assert_eq!(
+            state
+                .sso_login_finish(&query, &ClientInfo::default())
+                .await?
+                .unwrap()
+                .id,
+            1
+        );
+        // the other account stays unlinked
+        idp.set_account(account("mallory", "test@yahoo.com"));
+        let query = authorize(
+            &state
+                .sso_login_start("Test ws", &ClientInfo::default())
+                .await?,
+        )
+        .await;
+        assert!(state
+            .sso_login_finish(&query, &ClientInfo::default())
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sso_login_should_reject_unverified_and_foreign_emails() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let idp = mock_sso(&state, 1, account("mallory", "test@yahoo.com")).await;
+        idp.set_account(MockAccount {
+            email_verified: false,
+            ..account("mallory", "test@yahoo.com")
+        });
+        let query = authorize(
+            &state
+                .sso_login_start("Test ws", &ClientInfo::default())
+                .await?,
+        )
+        .await;
+        let ret = state.sso_login_finish(&query, &ClientInfo::default()).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+
+        // user 3 is a member of workspace 2
+        let user = state.user_fetch_by_id(3).await?.unwrap();
+        idp.set_account(account("mallory", &user.email));
+        let query = authorize(
+            &state
+                .sso_login_start("Test ws", &ClientInfo::default())
+                .await?,
+        )
+        .await;
+        let ret = state.sso_login_finish(&query, &ClientInfo::default()).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sso_login_start_should_be_throttled_and_purge_expired() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        mock_sso(&state, 1, account("alice", "alice@acme.org")).await;
+        let client = ClientInfo {
+            ip: Some("10.0.0.1".to_string()),
+            ..Default::default()
+        };
+        let first = authorize(&state.sso_login_start("Test ws", &client).await?).await;
+        let second = authorize(&state.sso_login_start("Test ws", &client).await?).await;
+        let ret = state.sso_login_start("Test ws", &client).await;
+        assert!(matches!(ret, Err(AppError::TooManyAttempts(_))));
+
+        // finished sign ins are given back
+        state.sso_login_finish(&first, &client).await?;
+        state.sso_login_finish(&second, &client).await?;
+        state.sso_login_start("Test ws", &client).await?;
+
+        sqlx::query("UPDATE sso_logins SET expires_at = CURRENT_TIMESTAMP - interval '1 hour'")
+            .execute(&state.pool)
+            .await?;
+        state
+            .sso_login_start("Test ws", &ClientInfo::default())
+            .await?;
+        let (count,): (i64,) = sqlx::query_as("SELECT count(*) FROM sso_logins")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(count, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sso_login_should_check_state_and_provider_errors() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        mock_sso(&state, 1, account("alice", "alice@acme.org")).await;
+        let ret = state
+            .sso_login_start("Test ws 2", &ClientInfo::default())
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        let mut query = authorize(
+            &state
+                .sso_login_start("Test ws", &ClientInfo::default())
+                .await?,
+        )
+        .await;
+        query.code = None;
+        query.error = Some("access_denied".to_string());
+        let ret = state.sso_login_finish(&query, &ClientInfo::default()).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(msg)) if msg.contains("access_denied")));
+
+        let query = SsoCallbackQuery {
+            code: Some("forged".to_string()),
+            state: "forged".to_string(),
+            error: None,
+        };
+        assert!(state
+            .sso_login_finish(&query, &ClientInfo::default())
+            .await
+            .is_err());
+        Ok(())
+    }
+}
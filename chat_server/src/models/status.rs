@@ -0,0 +1,284 @@
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+
+use crate::{AppError, AppState};
+
+const MAX_EMOJI_LEN: usize = 32;
+const MAX_STATUS_LEN: usize = 128;
+
+#[derive(Debug, Clone, Default, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct UserStatus {
+    pub emoji: Option<String>,
+    pub text: Option<String>,
+    // cleared automatically after this
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Daily quiet hours in the user's timezone, `start` after `end` spans
+/// midnight
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+/// Do not disturb, notifications other than urgent ones are held while
+/// `active`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DndSettings {
+    // one-off, until this point in time
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    #[serde(default, skip_deserializing)]
+    pub active: bool,
+}
+
+#[derive(Debug, FromRow)]
+struct DndRow {
+    dnd_until: Option<DateTime<Utc>>,
+    quiet_hours_start: Option<NaiveTime>,
+    quiet_hours_end: Option<NaiveTime>,
+    active: bool,
+}
+
+impl From<DndRow> for DndSettings {
+    fn from(row: DndRow) -> Self {
+        let quiet_hours = match (row.quiet_hours_start, row.quiet_hours_end) {
+            (Some(start), Some(end)) => Some(QuietHours { start, end }),
+            _ => None,
+        };
+        Self {
+            until: row.dnd_until,
+            quiet_hours,
+            active: row.active,
+        }
+    }
+}
+
+impl AppState {
+    pub async fn user_status(&self, user_id: i64) -> Result<UserStatus, AppError> {
+        let status = sqlx::query_as(
+            r#"
+            SELECT status_emoji AS emoji, status_text AS text, status_expires_at AS expires_at
+            FROM chat_users WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(status.unwrap_or_default())
+    }
+
+    /// Set the custom status, an empty status clears it
+    pub async fn user_status_update(
+        &self,
+        user_id: i64,
+        input: UserStatus,
+    ) -> Result<UserStatus, AppError> {
+        let emoji = trimmed(&input.emoji);
+        let text = trimmed(&input.text);
+        if emoji.is_some_and(|emoji| emoji.chars().count() > MAX_EMOJI_LEN) {
+            return Err(AppError::InvalidStatus(format!(
+                "status emoji must be at most {} characters",
+                MAX_EMOJI_LEN
+            )));
+        }
+        if text.is_some_and(|text| text.chars().count() > MAX_STATUS_LEN) {
+            return Err(AppError::InvalidStatus(format!(
+                "status must be at most {} characters",
+                MAX_STATUS_LEN
+            )));
+        }
+        if input.expires_at.is_some_and(|at| at <= Utc::now()) {
+            return Err(AppError::InvalidStatus(
+                "status must expire in the future".to_string(),
+            ));
+        }
+        let expires_at = (emoji.is_some() || text.is_some())
+            .then_some(input.expires_at)
+            .flatten();
+        sqlx::query(
+            r#"
+            UPDATE users SET status_emoji = $2, status_text = $3, status_expires_at = $4
+            WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .bind(emoji)
+        .bind(text)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        self.user_status(user_id).await
+    }
+
+    pub async fn user_dnd(&self, user_id: i64) -> Result<DndSettings, AppError> {
+        let row: Option<DndRow> = sqlx::query_as(
+            r#"
+            SELECT dnd_until, quiet_hours_start, quiet_hours_end,
+                user_dnd_active(id, now()) AS active
+            FROM users WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Into::into).unwrap_or_default())
+    }
+
+    /// Replace the do not disturb settings, empty settings turn it off
+    pub async fn user_dnd_update(
+        &self,
+        user_id: i64,
+        input: DndSettings,
+    ) -> Result<DndSettings, AppError> {
+        if input
+            .quiet_hours
+            .is_some_and(|hours| hours.start == hours.end)
+        {
+            return Err(AppError::InvalidStatus(
+                "quiet hours must not start and end at the same time".to_string(),
+            ));
+        }
+        sqlx::query(
+            r#"
+            UPDATE users SET dnd_until = $2, quiet_hours_start = $3, quiet_hours_end = $4
+            WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .bind(input.until)
+        .bind(input.quiet_hours.map(|hours| hours.start))
+        .bind(input.quiet_hours.map(|hours| hours.end))
+        .execute(&self.pool)
+        .await?;
+        self.user_dnd(user_id).await
+    }
+
+    /// Whether notifications to the user are held at `at`
+    pub async fn user_dnd_active(&self, user_id: i64, at: DateTime<Utc>) -> Result<bool, AppError> {
+        let (active,): (bool,) = sqlx::query_as("SELECT user_dnd_active($1, $2)")
+            .bind(user_id)
+            .bind(at)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(active)
+    }
+}
+
+fn trimmed(value: &Option<String>) -> Option<&str> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn user_status_should_show_until_it_expires() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let status = UserStatus {
+            emoji: Some("🌴".to_string()),
+            text: Some(" On vacation ".to_string()),
+            expires_at: Some(Utc::now() + chrono::Duration::days(1)),
+        };
+        let status = state.user_status_update(1, status).await?;
+        assert_eq!(status.text.as_deref(), Some("On vacation"));
+
+        let users = state.user_fetched_all_by_ws_id(1).await?;
+        let user = users.iter().find(|user| user.id == 1).unwrap();
+        assert_eq!(user.status_emoji.as_deref(), Some("🌴"));
+        assert!(!user.dnd);
+
+        sqlx::query(
+            "UPDATE users SET status_expires_at = now() - interval '1 minute' WHERE id = 1",
+        )
+        .execute(&state.pool)
+        .await?;
+        assert_eq!(state.user_status(1).await?, UserStatus::default());
+        let users = state.user_fetched_by_ids(&[1]).await?;
+        assert_eq!(users[0].status_text, None);
+
+        let status = UserStatus {
+            text: Some("Back soon".to_string()),
+            expires_at: Some(Utc::now() - chrono::Duration::minutes(1)),
+            ..Default::default()
+        };
+        let ret = state.user_status_update(1, status).await;
+        assert!(matches!(ret, Err(AppError::InvalidStatus(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_dnd_should_follow_quiet_hours_in_the_timezone() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE users SET timezone = 'Asia/Shanghai' WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let settings = DndSettings {
+            quiet_hours: Some(QuietHours {
+                start: time("22:00"),
+                end: time("07:00"),
+            }),
+            ..Default::default()
+        };
+        state.user_dnd_update(1, settings).await?;
+
+        // 23:30 and 06:59 in Shanghai are quiet, 07:00 and noon aren't
+        let at = |h, m| Utc.with_ymd_and_hms(2025, 4, 18, h, m, 0).unwrap();
+        assert!(state.user_dnd_active(1, at(15, 30)).await?);
+        assert!(state.user_dnd_active(1, at(22, 59)).await?);
+        assert!(!state.user_dnd_active(1, at(23, 0)).await?);
+        assert!(!state.user_dnd_active(1, at(4, 0)).await?);
+        // other users aren't affected
+        assert!(!state.user_dnd_active(2, at(15, 30)).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_dnd_should_end_after_until() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let settings = DndSettings {
+            until: Some(Utc::now() + chrono::Duration::hours(1)),
+            ..Default::default()
+        };
+        let settings = state.user_dnd_update(1, settings).await?;
+        assert!(settings.active);
+        let users = state.user_fetched_by_ids(&[1]).await?;
+        assert!(users[0].dnd);
+        assert!(
+            !state
+                .user_dnd_active(1, Utc::now() + chrono::Duration::hours(2))
+                .await?
+        );
+
+        let settings = state.user_dnd_update(1, DndSettings::default()).await?;
+        assert!(!settings.active);
+        let ret = state
+            .user_dnd_update(
+                1,
+                DndSettings {
+                    quiet_hours: Some(QuietHours {
+                        start: time("09:00"),
+                        end: time("09:00"),
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidStatus(_))));
+        Ok(())
+    }
+}
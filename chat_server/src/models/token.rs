@@ -0,0 +1,272 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::{Scope, SessionId, TokenClaims};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::prelude::FromRow;
+use tracing::warn;
+use uuid::Uuid;
+
+use super::ClientInfo;
+use crate::{AppError, AppState, User};
+
+/// Returned on sign in and on refresh
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthOutput {
+    pub token: String,
+    pub refresh_token: String,
+    // lifetime of the access token in seconds
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshTokenPayload {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct RefreshToken {
+    id: i64,
+    user_id: i64,
+    family_id: Uuid,
+    expires_at: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+impl AppState {
+    /// Start a session: issue an access token and the first refresh token of
+    /// a new family, the family id is the session id.
+    pub async fn token_issue(
+        &self,
+        user: User,
+        client: &ClientInfo,
+    ) -> Result<AuthOutput, AppError> {
+        let session_id = self.session_create(user.id, client).await?;
+        let refresh_token = self.refresh_token_create(user.id, session_id.0).await?;
+        self.token_output(user, session_id, refresh_token).await
+    }
+
+    /// Exchange a refresh token for a new access token and refresh token. A
+    /// refresh token can only be used once, presenting it again means it
+    /// leaked and every token of its family is revoked.
+    pub async fn token_refresh(&self, refresh_token: &str) -> Result<AuthOutput, AppError> {
+        let token: Option<RefreshToken> = sqlx::query_as(
+            r#"
+            SELECT id, user_id, family_id, expires_at, used_at, revoked_at
+            FROM refresh_tokens WHERE token_hash = $1
+            "#,
+        )
+        .bind(hash_token(refresh_token))
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(token) = token else {
+            return Err(AppError::InvalidToken("unknown refresh token".to_string()));
+        };
+        if token.revoked_at.is_some() {
+            return Err(AppError::InvalidToken("refresh token revoked".to_string()));
+        }
+        if token.used_at.is_some() {
+            return Err(self.refresh_token_reused(&token).await);
+        }
+        if token.expires_at < Utc::now() {
+            return Err(AppError::InvalidToken("refresh token expired".to_string()));
+        }
+
+        // only one of concurrent requests with the same token gets through
+        let claimed = sqlx::query(
+            "UPDATE refresh_tokens SET used_at = CURRENT_TIMESTAMP WHERE id = $1 AND used_at IS NULL",
+        )
+        .bind(token.id)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if claimed == 0 {
+            return Err(self.refresh_token_reused(&token).await);
+        }
+
+        let Some(user) = self.user_fetch_by_id(token.user_id).await? else {
+            return Err(AppError::InvalidToken("user no longer exists".to_string()));
+        };
+        let refresh_token = self
+            .refresh_token_create(token.user_id, token.family_id)
+            .await?;
+        self.token_output(user, SessionId(token.family_id), refresh_token)
+            .await
+    }
+
+    /// Revoke every refresh token of a family
+    pub async fn refresh_token_revoke_family(&self, family_id: Uuid) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP
+            WHERE family_id = $1 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(family_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn refresh_token_reused(&self, token: &RefreshToken) -> AppError {
+        warn!(
+            "refresh token {} of user {} reused, revoking family {}",
+            token.id, token.user_id, token.family_id
+        );
+        let session_id = SessionId(token.family_id);
+        if let Err(e) = self.session_revoke(token.user_id, session_id).await {
+            return e;
+        }
+        AppError::InvalidToken("refresh token already used".to_string())
+    }
+
+    async fn refresh_token_create(
+        &self,
+        user_id: i64,
+        family_id: Uuid,
+    ) -> Result<String, AppError> {
+        let token = generate_token();
+        let expires_at =
+            Utc::now() + chrono::Duration::seconds(self.config.auth.refresh_token_ttl_secs as _);
+        sqlx::query(
+            r#"
+            INSERT INTO refresh_tokens (user_id, family_id, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(user_id)
+        .bind(family_id)
+        .bind(hash_token(&token))
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(token)
+    }
+
+    /// Full access once the email is verified, the unverified policy before.
+    /// Members of a workspace requiring 2FA who haven't set it up can only
+    /// manage their account.
+    async fn token_claims(
+        &self,
+        user: &User,
+        session_id: SessionId,
+    ) -> Result<TokenClaims, AppError> {
+        let mut claims = TokenClaims::new(user.id, user.ws_id, Some(session_id));
+        if !user.is_verified() {
+            claims.scopes = self.config.auth.unverified.scopes.clone();
+        }
+        if self.mfa_missing(user).await? {
+            claims.scopes.retain(|scope| *scope == Scope::Account);
+        }
+        Ok(claims)
+    }
+
+    async fn token_output(
+        &self,
+        user: User,
+        session_id: SessionId,
+        refresh_token: String,
+    ) -> Result<AuthOutput, AppError> {
+        let ttl = self.config.auth.access_token_ttl_secs;
+        Ok(AuthOutput {
+            token: self
+                .sk
+                .sign(&self.token_claims(&user, session_id).await?, ttl)?,
+            refresh_token,
+            expires_in: ttl,
+        })
+    }
+}
+
+/// A random token handed to the client, only its hash is stored
+pub(super) fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub(super) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use chat_core::TokenVerify;
+
+    use super::*;
+
+    async fn issue(state: &AppState) -> Result<AuthOutput, AppError> {
+        let user = state.user_fetch_by_id(1).await?.unwrap();
+        state.token_issue(user, &ClientInfo::default()).await
+    }
+
+    #[tokio::test]
+    async fn token_refresh_should_rotate() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let output = issue(&state).await?;
+        assert_eq!(state.pk.verify(&output.token)?.user_id, 1);
+        assert_eq!(output.expires_in, state.config.auth.access_token_ttl_secs);
+
+        let refreshed = state.token_refresh(&output.refresh_token).await?;
+        assert_ne!(refreshed.refresh_token, output.refresh_token);
+        assert_eq!(state.pk.verify(&refreshed.token)?.user_id, 1);
+
+        // rotated tokens pick up the user's current workspace
+        state.user_added_to_workspace(1, 2).await?;
+        let refreshed = state.token_refresh(&refreshed.refresh_token).await?;
+        assert_eq!(state.pk.verify(&refreshed.token)?.ws_id, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn token_user_should_be_resolved_from_claims() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let output = issue(&state).await?;
+        let claims = state.verify(&output.token).await?;
+        assert!(claims.has_scope(chat_core::Scope::ChatsWrite));
+        assert_eq!(state.user(&claims).await?.email, "test@yahoo.com");
+
+        // tokens issued for the old workspace stop working once the user moved
+        state.user_added_to_workspace(1, 2).await?;
+        let ret = state.user(&claims).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(msg)) if msg == "workspace changed"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn token_refresh_reuse_should_revoke_family() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let output = issue(&state).await?;
+        let other = issue(&state).await?;
+        let refreshed = state.token_refresh(&output.refresh_token).await?;
+
+        // the first token is replayed, e.g. by an attacker who stole it
+        let ret = state.token_refresh(&output.refresh_token).await;
+        assert!(
+            matches!(ret, Err(AppError::InvalidToken(msg)) if msg == "refresh token already used")
+        );
+        // the legitimate client's rotated token is revoked as well
+        let ret = state.token_refresh(&refreshed.refresh_token).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(msg)) if msg == "refresh token revoked"));
+        // other sign ins are not affected
+        assert!(state.token_refresh(&other.refresh_token).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn token_refresh_should_reject_unknown_and_expired() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert!(state.token_refresh("not-a-token").await.is_err());
+
+        let output = issue(&state).await?;
+        sqlx::query(
+            "UPDATE refresh_tokens SET expires_at = CURRENT_TIMESTAMP - INTERVAL '1 second'",
+        )
+        .execute(&state.pool)
+        .await?;
+        let ret = state.token_refresh(&output.refresh_token).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(msg)) if msg == "refresh token expired"));
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
 use std::mem;
 
-use crate::{AppError, AppState, User};
+use crate::{config::Argon2Config, AppError, AppState, User};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Algorithm, Argon2, Params, Version,
 };
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 
@@ -15,6 +16,12 @@ pub struct ChatUser {
     pub fullname: String,
     pub email: String,
     pub id: i64,
+    // custom status, unset once it expired
+    pub status_emoji: Option<String>,
+    pub status_text: Option<String>,
+    pub status_expires_at: Option<DateTime<Utc>>,
+    // notifications to the user are held
+    pub dnd: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,7 +43,7 @@ impl AppState {
     #[allow(unused)]
     pub async fn user_find_by_email(&self, email: &str) -> Result<Option<User>, AppError> {
         let user = sqlx::query_as(
-            "select id, ws_id, fullname, email, created_at, updated_at from users where email = $1",
+            "select id, ws_id, fullname, email, verified_at, created_at, updated_at from users where email = $1",
         )
         .bind(email)
         .fetch_optional(&self.pool)
@@ -44,10 +51,20 @@ impl AppState {
         Ok(user)
     }
 
+    pub async fn user_fetch_by_id(&self, id: i64) -> Result<Option<User>, AppError> {
+        let user = sqlx::query_as(
+            "select id, ws_id, fullname, email, verified_at, created_at, updated_at from users where id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
     // verify email and password
     pub async fn user_verify(&self, email: &str, password: &str) -> Result<Option<User>, AppError> {
         let user: Option<User> = sqlx::query_as(
-            "select id, ws_id, fullname, email, password_hash, created_at, updated_at from users where email = $1",
+            "select id, ws_id, fullname, email, password_hash, verified_at, created_at, updated_at from users where email = $1",
         )
         .bind(email)
         .fetch_optional(&self.pool)
@@ -57,16 +74,51 @@ impl AppState {
             Some(mut user) => {
                 // take the password hash from the user
                 let password_hash = mem::take(&mut user.password_hash);
-                let is_valid = verify_password(password, &password_hash.unwrap_or_default())?;
-                if is_valid {
-                    Ok(Some(user))
-                } else {
-                    Err(AppError::InvalidCredentials)
+                // users created by single sign-on have no password
+                let Some(password_hash) = password_hash else {
+                    self.password_hash(password)?;
+                    return Err(AppError::InvalidCredentials);
+                };
+                let is_valid = verify_password(password, &password_hash)?;
+                if !is_valid {
+                    return Err(AppError::InvalidCredentials);
                 }
+                if password_needs_rehash(&password_hash, &self.config.auth.password.argon2)? {
+                    self.user_password_rehash(user.id, &password_hash, password)
+                        .await?;
+                }
+                Ok(Some(user))
+            }
+            None => {
+                // takes as long as checking a password
+                self.password_hash(password)?;
+                Ok(None)
             }
-            None => Ok(None),
         }
     }
+
+    /// Hash a password with the configured argon2 costs
+    pub fn password_hash(&self, password: &str) -> Result<String, AppError> {
+        hash_password(password, &self.config.auth.password.argon2)
+    }
+
+    // replace a hash made with outdated costs, unless the password changed
+    // in the meantime
+    async fn user_password_rehash(
+        &self,
+        user_id: i64,
+        old_hash: &str,
+        password: &str,
+    ) -> Result<(), AppError> {
+        let password_hash = self.password_hash(password)?;
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2 AND password_hash = $3")
+            .bind(password_hash)
+            .bind(user_id)
+            .bind(old_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
     /// 创建用户的方法逻辑：
     /// 1. 检查用户是否已存在，如果存在则返回错误。
     /// 2. 检查工作区是否存在，如果不存在则创建一个新的工作区。
@@ -83,16 +135,19 @@ impl AppState {
             return Err(AppError::UserAlreadyExists);
         }
 
+        // before the workspace is created, a rejected password mustn't leave
+        // it behind
+        self.password_check(&input.email, &input.password)?;
+        let password_hash = self.password_hash(&input.password)?;
+
         // if workspace is not provided, use the default workspace
         let ws = match self.workspace_fetch_by_name(&input.workspace).await? {
             Some(ws) => ws,
             None => self.workspace_create(&input.workspace).await?,
         };
 
-        let password_hash = hash_password(&input.password)?;
-
         let user: User = sqlx::query_as(
-            "insert into users (fullname, email, password_hash, ws_id) values ($1, $2, $3, $4) returning id, ws_id, fullname, email, created_at, updated_at",
+            "insert into users (fullname, email, password_hash, ws_id) values ($1, $2, $3, $4) returning id, ws_id, fullname, email, verified_at, created_at, updated_at",
         )
         .bind(input.fullname)
         .bind(input.email)
@@ -116,18 +171,27 @@ impl AppState {
         user_id: i64,
         workspace_id: i64,
     ) -> Result<User, AppError> {
+        if !self.config.auth.unverified.join_workspaces {
+            let user = self.user_fetch_by_id(user_id).await?;
+            if user.is_some_and(|user| !user.is_verified()) {
+                return Err(AppError::PermissionDenied(
+                    "verify your email before joining another workspace".to_string(),
+                ));
+            }
+        }
         let user = sqlx::query_as(
             r#"
             update users
             set ws_id = $1
             where id = $2
-            returning id, ws_id, fullname, email, created_at, updated_at
+            returning id, ws_id, fullname, email, verified_at, created_at, updated_at
             "#,
         )
         .bind(workspace_id)
         .bind(user_id)
         .fetch_one(&self.pool)
         .await?;
+        self.users.invalidate(user_id);
         Ok(user)
     }
 
@@ -135,8 +199,8 @@ impl AppState {
     pub async fn user_fetched_by_ids(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
         let users: Vec<ChatUser> = sqlx::query_as(
             r#"
-            select id, fullname, email
-            from users
+            select id, fullname, email, status_emoji, status_text, status_expires_at, dnd
+            from chat_users
             where id = any($1)
             "#,
         )
@@ -149,8 +213,8 @@ impl AppState {
     pub async fn user_fetched_all_by_ws_id(&self, ws_id: u64) -> Result<Vec<ChatUser>, AppError> {
         let users: Vec<ChatUser> = sqlx::query_as(
             r#"
-            select id, fullname, email
-            from users
+            select id, fullname, email, status_emoji, status_text, status_expires_at, dnd
+            from chat_users
             where ws_id = $1
             "#,
         )
@@ -162,10 +226,10 @@ impl AppState {
     }
 }
 
-pub fn hash_password(password: &str) -> Result<String, AppError> {
+pub fn hash_password(password: &str, config: &Argon2Config) -> Result<String, AppError> {
     let salt = SaltString::generate(&mut OsRng);
 
-    let argon2 = Argon2::default();
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params(config)?);
     let password_hash = argon2
         .hash_password(password.as_bytes(), &salt)?
         .to_string();
@@ -182,14 +246,40 @@ pub fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppE
     Ok(password_verified)
 }
 
+/// Whether a hash was made with other argon2 costs than configured
+pub fn password_needs_rehash(password_hash: &str, config: &Argon2Config) -> Result<bool, AppError> {
+    let parsed_hash = PasswordHash::new(password_hash)?;
+    if parsed_hash.algorithm != Algorithm::Argon2id.ident()
+        || parsed_hash.version != Some(Version::V0x13.into())
+    {
+        return Ok(true);
+    }
+    let params = Params::try_from(&parsed_hash)?;
+    let wanted = argon2_params(config)?;
+    Ok(params.m_cost() != wanted.m_cost()
+        || params.t_cost() != wanted.t_cost()
+        || params.p_cost() != wanted.p_cost())
+}
+
+fn argon2_params(config: &Argon2Config) -> Result<Params, AppError> {
+    let params = Params::new(
+        config.memory_kib,
+        config.iterations,
+        config.parallelism,
+        None,
+    )
+    .map_err(argon2::password_hash::Error::from)?;
+    Ok(params)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[tokio::test]
     async fn hash_password_and_verify_should_work() -> Result<(), AppError> {
-        let password = "test";
-        let password_hash = hash_password(password)?;
+        let password = "hunter42";
+        let password_hash = hash_password(password, &Argon2Config::default())?;
         assert_eq!(password_hash.len(), 97);
         assert_ne!(password_hash, password);
         let is_valid = verify_password(password, &password_hash)?;
@@ -197,6 +287,44 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn user_verify_should_rehash_outdated_passwords() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let config = &state.config.auth.password.argon2;
+        let weak = Argon2Config {
+            memory_kib: 8 * 1024,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let old_hash = hash_password("hunter42", &weak)?;
+        assert!(password_needs_rehash(&old_hash, config)?);
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = 1")
+            .bind(&old_hash)
+            .execute(&state.pool)
+            .await?;
+
+        let user = state.user_verify("test@yahoo.com", "hunter42").await?;
+        assert_eq!(user.map(|user| user.id), Some(1));
+        let (new_hash,): (String,) = sqlx::query_as("SELECT password_hash FROM users WHERE id = 1")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_ne!(new_hash, old_hash);
+        assert!(!password_needs_rehash(&new_hash, config)?);
+        assert!(verify_password("hunter42", &new_hash)?);
+
+        // the fixture hashes are current
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = 1")
+            .bind(&new_hash)
+            .execute(&state.pool)
+            .await?;
+        state.user_verify("test@yahoo.com", "hunter42").await?;
+        let (hash,): (String,) = sqlx::query_as("SELECT password_hash FROM users WHERE id = 1")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(hash, new_hash);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn create_user_should_work() -> Result<(), AppError> {
         // test by sqlx-db-tester
@@ -204,7 +332,7 @@ mod tests {
 
         let email = "test@test.com";
         let fullname = "test";
-        let password = "test";
+        let password = "hunter42";
 
         let user = state
             .user_create(CreateUserPayload {
@@ -221,6 +349,23 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn create_user_should_reject_weak_password() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = state
+            .user_create(CreateUserPayload {
+                email: "test@test.com".to_string(),
+                fullname: "test".to_string(),
+                workspace: "New ws".to_string(),
+                password: "12345678".to_string(),
+            })
+            .await;
+        assert!(matches!(ret, Err(AppError::WeakPassword(_))));
+        // no workspace is left behind
+        assert!(state.workspace_fetch_by_name("New ws").await?.is_none());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn create_user_and_update_owner_should_work() -> Result<(), AppError> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -231,7 +376,7 @@ mod tests {
                 email: "test@test.com".to_string(),
                 fullname: "test".to_string(),
                 workspace: ws.name.clone(),
-                password: "test".to_string(),
+                password: "hunter42".to_string(),
             })
             .await?;
 
@@ -249,7 +394,7 @@ mod tests {
 
         let email = "test@test.com";
         let fullname = "test";
-        let password = "test";
+        let password = "hunter42";
 
         let user = state
             .user_create(CreateUserPayload {
@@ -273,7 +418,7 @@ mod tests {
 
         let email = "test@test.com";
         let fullname = "test";
-        let password = "test";
+        let password = "hunter42";
         let wrong_password = "wrong";
 
         let _user = state
@@ -296,7 +441,7 @@ mod tests {
         let (_tdb, state) = AppState::new_for_test().await?;
 
         let email = "test@test.com";
-        let password = "test";
+        let password = "hunter42";
 
         let _user = state
             .user_create(CreateUserPayload {
@@ -319,7 +464,7 @@ mod tests {
 
         let email = "test@test.com";
         let fullname = "test";
-        let password = "test";
+        let password = "hunter42";
 
         let _user = state
             .user_create(CreateUserPayload {
@@ -338,6 +483,15 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn fetch_user_by_id_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.user_fetch_by_id(1).await?;
+        assert_eq!(user.unwrap().email, "test@yahoo.com");
+        assert!(state.user_fetch_by_id(100).await?.is_none());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn add_to_workspace_should_work() -> Result<(), AppError> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -347,7 +501,7 @@ mod tests {
                 email: "test@test.com".to_string(),
                 fullname: "test".to_string(),
                 workspace: "Test ws".to_string(),
-                password: "test".to_string(),
+                password: "hunter42".to_string(),
             })
             .await?;
 
@@ -364,6 +518,13 @@ mod tests {
             .await?;
         assert_eq!(users.len(), 7);
 
+        // new users have to confirm their email before joining other workspaces
+        let ret = state.user_added_to_workspace(user.id, 2).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        sqlx::query("UPDATE users SET verified_at = now() WHERE id = $1")
+            .bind(user.id)
+            .execute(&state.pool)
+            .await?;
         let user = state.user_added_to_workspace(user.id, 2).await?;
 
         assert_eq!(user.ws_id, 2);
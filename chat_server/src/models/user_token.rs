@@ -0,0 +1,187 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use super::token::{generate_token, hash_token};
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "user_token_purpose", rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum UserTokenPurpose {
+    PasswordReset,
+    EmailVerification,
+    MfaPending,
+    MagicLink,
+    SsoLink,
+}
+
+impl AppState {
+    /// Create a single use token for the user, earlier unused tokens of the
+    /// same purpose stop working.
+    pub async fn user_token_create(
+        &self,
+        user_id: i64,
+        purpose: UserTokenPurpose,
+        ttl_secs: u64,
+    ) -> Result<String, AppError> {
+        self.user_token_insert(user_id, purpose, ttl_secs, None)
+            .await
+    }
+
+    /// Create a token that only works together with `nonce`, see
+    /// `user_token_consume_bound`
+    pub async fn user_token_create_bound(
+        &self,
+        user_id: i64,
+        purpose: UserTokenPurpose,
+        ttl_secs: u64,
+        nonce: &str,
+    ) -> Result<String, AppError> {
+        self.user_token_insert(user_id, purpose, ttl_secs, Some(nonce))
+            .await
+    }
+
+    async fn user_token_insert(
+        &self,
+        user_id: i64,
+        purpose: UserTokenPurpose,
+        ttl_secs: u64,
+        nonce: Option<&str>,
+    ) -> Result<String, AppError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE user_tokens SET used_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .execute(&mut *tx)
+        .await?;
+
+        let token = generate_token();
+        let expires_at = Utc::now() + chrono::Duration::seconds(ttl_secs as _);
+        sqlx::query(
+            r#"
+            INSERT INTO user_tokens (user_id, purpose, token_hash, expires_at, nonce_hash)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .bind(hash_token(&token))
+        .bind(expires_at)
+        .bind(nonce.map(hash_token))
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    /// Use up a token, returns the user it was issued to if it was valid
+    pub async fn user_token_consume(
+        &self,
+        token: &str,
+        purpose: UserTokenPurpose,
+    ) -> Result<Option<i64>, AppError> {
+        self.user_token_use(token, purpose, None).await
+    }
+
+    /// Use up a token created with `user_token_create_bound`, a wrong nonce
+    /// leaves it unused
+    pub async fn user_token_consume_bound(
+        &self,
+        token: &str,
+        purpose: UserTokenPurpose,
+        nonce: &str,
+    ) -> Result<Option<i64>, AppError> {
+        self.user_token_use(token, purpose, Some(nonce)).await
+    }
+
+    async fn user_token_use(
+        &self,
+        token: &str,
+        purpose: UserTokenPurpose,
+        nonce: Option<&str>,
+    ) -> Result<Option<i64>, AppError> {
+        let user_id: Option<(i64,)> = sqlx::query_as(
+            r#"
+            UPDATE user_tokens SET used_at = CURRENT_TIMESTAMP
+            WHERE token_hash = $1 AND purpose = $2
+            AND nonce_hash IS NOT DISTINCT FROM $3
+            AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+            RETURNING user_id
+            "#,
+        )
+        .bind(hash_token(token))
+        .bind(purpose)
+        .bind(nonce.map(hash_token))
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user_id.map(|(id,)| id))
+    }
+
+    /// The user a valid token was issued to, without using it up
+    pub async fn user_token_peek(
+        &self,
+        token: &str,
+        purpose: UserTokenPurpose,
+    ) -> Result<Option<i64>, AppError> {
+        let user_id: Option<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT user_id FROM user_tokens
+            WHERE token_hash = $1 AND purpose = $2
+            AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(hash_token(token))
+        .bind(purpose)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user_id.map(|(id,)| id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn user_token_should_be_single_use() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let purpose = UserTokenPurpose::PasswordReset;
+        let first = state.user_token_create(1, purpose, 60).await?;
+        let token = state.user_token_create(1, purpose, 60).await?;
+
+        // issuing a new token invalidates the previous one
+        assert_eq!(state.user_token_consume(&first, purpose).await?, None);
+        assert_eq!(state.user_token_peek(&token, purpose).await?, Some(1));
+        assert_eq!(state.user_token_consume(&token, purpose).await?, Some(1));
+        assert_eq!(state.user_token_consume(&token, purpose).await?, None);
+
+        let expired = state.user_token_create(2, purpose, 0).await?;
+        assert_eq!(state.user_token_consume(&expired, purpose).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bound_user_token_should_need_its_nonce() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let purpose = UserTokenPurpose::MagicLink;
+        let token = state
+            .user_token_create_bound(1, purpose, 60, "nonce")
+            .await?;
+
+        assert_eq!(state.user_token_consume(&token, purpose).await?, None);
+        let ret = state
+            .user_token_consume_bound(&token, purpose, "other")
+            .await?;
+        assert_eq!(ret, None);
+        let ret = state
+            .user_token_consume_bound(&token, purpose, "nonce")
+            .await?;
+        assert_eq!(ret, Some(1));
+        Ok(())
+    }
+}
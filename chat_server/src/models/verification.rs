@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+
+use super::{mail::expires_in, UserTokenPurpose};
+use crate::{AppError, AppState, Mail, User};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyEmailPayload {
+    pub token: String,
+}
+
+impl AppState {
+    /// Mail the user a link confirming their email, links sent earlier stop
+    /// working.
+    pub async fn email_verification_send(&self, user: &User) -> Result<(), AppError> {
+        if user.is_verified() {
+            return Err(AppError::EmailAlreadyVerified);
+        }
+        let ttl = self.config.auth.email_verification_ttl_secs;
+        let token = self
+            .user_token_create(user.id, UserTokenPurpose::EmailVerification, ttl)
+            .await?;
+        let body = format!(
+            "Hi {},\n\n\
+             Open the link below to confirm your email, it expires in {}:\n\n\
+             {}\n",
+            user.fullname,
+            expires_in(ttl),
+            self.mail_link("verify-email", &token)
+        );
+        self.mail_spawn(Mail {
+            to: user.email.clone(),
+            subject: "Confirm your email".to_string(),
+            body,
+        });
+        Ok(())
+    }
+
+    /// Mark the email of the token's user verified. Tokens issued before
+    /// keep their restricted scopes until they are refreshed.
+    pub async fn email_verify(&self, token: &str) -> Result<User, AppError> {
+        let Some(user_id) = self
+            .user_token_consume(token, UserTokenPurpose::EmailVerification)
+            .await?
+        else {
+            return Err(AppError::InvalidToken(
+                "invalid or expired verification token".to_string(),
+            ));
+        };
+        let user = sqlx::query_as(
+            r#"
+            UPDATE users SET verified_at = COALESCE(verified_at, CURRENT_TIMESTAMP)
+            WHERE id = $1
+            RETURNING id, ws_id, fullname, email, verified_at, created_at, updated_at
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        self.users.invalidate(user_id);
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat_core::{Scope, TokenVerify};
+
+    use super::*;
+    use crate::{
+        mailer::tests::{mailed_token, wait_for_mail},
+        models::{ClientInfo, CreateUserPayload},
+    };
+
+    async fn signup(state: &AppState) -> Result<User, AppError> {
+        state
+            .user_create(CreateUserPayload {
+                fullname: "Alice".to_string(),
+                workspace: "acme".to_string(),
+                email: "alice@acme.org".to_string(),
+                password: "hunter42".to_string(),
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn email_verify_should_lift_restrictions() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = signup(&state).await?;
+        assert!(!user.is_verified());
+
+        let output = state
+            .token_issue(user.clone(), &ClientInfo::default())
+            .await?;
+        let claims = state.verify(&output.token).await?;
+        assert!(claims.has_scope(Scope::ChatsRead));
+        assert!(!claims.has_scope(Scope::ChatsWrite));
+        let ret = state.user_added_to_workspace(user.id, 1).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        state.email_verification_send(&user).await?;
+        let token = mailed_token(&wait_for_mail(&state.config.server.base_dir.join("mail")).await);
+        let user = state.email_verify(&token).await?;
+        assert!(user.is_verified());
+        assert!(state.email_verify(&token).await.is_err());
+
+        let refreshed = state.token_refresh(&output.refresh_token).await?;
+        let claims = state.verify(&refreshed.token).await?;
+        assert!(claims.has_scope(Scope::ChatsWrite));
+        assert!(matches!(
+            state.email_verification_send(&user).await,
+            Err(AppError::EmailAlreadyVerified)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn email_verification_resend_should_expire_earlier_links() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = signup(&state).await?;
+        let mail_dir = state.config.server.base_dir.join("mail");
+
+        state.email_verification_send(&user).await?;
+        let first = mailed_token(&wait_for_mail(&mail_dir).await);
+        std::fs::remove_dir_all(&mail_dir)?;
+        state.email_verification_send(&user).await?;
+        let second = mailed_token(&wait_for_mail(&mail_dir).await);
+
+        assert!(state.email_verify(&first).await.is_err());
+        assert!(state.email_verify(&second).await?.is_verified());
+        Ok(())
+    }
+}
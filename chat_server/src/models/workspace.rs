@@ -1,8 +1,48 @@
 use chat_core::Workspace;
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::prelude::FromRow;
 
 use crate::{AppError, AppState};
 
-use super::DEFAULT_OWNER_ID;
+use super::{is_valid_ext, DEFAULT_OWNER_ID};
+
+/// per workspace settings, `None` means the server default applies
+#[derive(Debug, Clone, Default, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceSettings {
+    pub allowed_file_exts: Option<Vec<String>>,
+    // image types uploaded with their metadata intact
+    pub keep_metadata_exts: Option<Vec<String>>,
+    // members have to sign in with a second factor
+    pub require_mfa: Option<bool>,
+    // `false` once members sign in with single sign-on only
+    pub password_login: Option<bool>,
+    // whether members can sign in with a mailed link, never once they sign
+    // in with single sign-on only
+    pub magic_link_login: Option<bool>,
+}
+
+/// A missing field is left unchanged, `null` resets it to the server default
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateWorkspaceSettings {
+    #[serde(default, deserialize_with = "nullable")]
+    pub allowed_file_exts: Option<Option<Vec<String>>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub keep_metadata_exts: Option<Option<Vec<String>>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub require_mfa: Option<Option<bool>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub password_login: Option<Option<bool>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub magic_link_login: Option<Option<bool>>,
+}
+
+pub(super) fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
 
 impl AppState {
     pub async fn workspace_create(&self, name: &str) -> Result<Workspace, AppError> {
@@ -48,6 +88,107 @@ impl AppState {
         Ok(workspace)
     }
 
+    pub async fn workspace_fetch_by_id(&self, id: u64) -> Result<Option<Workspace>, AppError> {
+        let workspace = sqlx::query_as(
+            r#"
+            select id, name, owner_id, created_at, updated_at from workspaces where id = $1
+            "#,
+        )
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(workspace)
+    }
+
+    pub async fn workspace_settings(&self, ws_id: u64) -> Result<WorkspaceSettings, AppError> {
+        let settings = sqlx::query_as(
+            r#"
+            select allowed_file_exts, keep_metadata_exts, require_mfa, password_login,
+                magic_link_login
+            from workspace_settings where ws_id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(settings.unwrap_or_default())
+    }
+
+    pub async fn workspace_settings_update(
+        &self,
+        ws_id: u64,
+        input: UpdateWorkspaceSettings,
+    ) -> Result<WorkspaceSettings, AppError> {
+        let exts = [&input.allowed_file_exts, &input.keep_metadata_exts];
+        let invalid = exts.into_iter().flatten().flatten().flatten();
+        if let Some(ext) = invalid.into_iter().find(|ext| !is_valid_ext(ext)) {
+            return Err(AppError::InvalidChatFilePath(format!(
+                "Invalid file extension {}",
+                ext
+            )));
+        }
+        if input.password_login == Some(Some(false))
+            && self.workspace_sso(ws_id as _).await?.is_none()
+        {
+            return Err(AppError::PermissionDenied(
+                "configure single sign-on before turning off password sign in".to_string(),
+            ));
+        }
+        let settings = sqlx::query_as(
+            r#"
+            insert into workspace_settings
+                (ws_id, allowed_file_exts, keep_metadata_exts, require_mfa, password_login,
+                magic_link_login)
+            values ($1, $2, $4, $6, $8, $10)
+            on conflict (ws_id) do update set
+                allowed_file_exts = case when $3 then excluded.allowed_file_exts
+                    else workspace_settings.allowed_file_exts end,
+                keep_metadata_exts = case when $5 then excluded.keep_metadata_exts
+                    else workspace_settings.keep_metadata_exts end,
+                require_mfa = case when $7 then excluded.require_mfa
+                    else workspace_settings.require_mfa end,
+                password_login = case when $9 then excluded.password_login
+                    else workspace_settings.password_login end,
+                magic_link_login = case when $11 then excluded.magic_link_login
+                    else workspace_settings.magic_link_login end,
+                updated_at = current_timestamp
+            returning allowed_file_exts, keep_metadata_exts, require_mfa, password_login,
+                magic_link_login
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.allowed_file_exts.clone().flatten())
+        .bind(input.allowed_file_exts.is_some())
+        .bind(input.keep_metadata_exts.clone().flatten())
+        .bind(input.keep_metadata_exts.is_some())
+        .bind(input.require_mfa.flatten())
+        .bind(input.require_mfa.is_some())
+        .bind(input.password_login.flatten())
+        .bind(input.password_login.is_some())
+        .bind(input.magic_link_login.flatten())
+        .bind(input.magic_link_login.is_some())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(settings)
+    }
+
+    pub async fn workspace_requires_mfa(&self, ws_id: i64) -> Result<bool, AppError> {
+        let settings = self.workspace_settings(ws_id as _).await?;
+        Ok(settings.require_mfa.unwrap_or(false))
+    }
+
+    pub async fn workspace_password_login(&self, ws_id: i64) -> Result<bool, AppError> {
+        let settings = self.workspace_settings(ws_id as _).await?;
+        Ok(settings.password_login.unwrap_or(true))
+    }
+
+    /// Mailed links would get around the identity provider of a workspace
+    /// that signs in with single sign-on only
+    pub async fn workspace_magic_link_login(&self, ws_id: i64) -> Result<bool, AppError> {
+        let settings = self.workspace_settings(ws_id as _).await?;
+        Ok(settings.password_login.unwrap_or(true) && settings.magic_link_login.unwrap_or(true))
+    }
+
     pub async fn workspace_fetch_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
         let workspace = sqlx::query_as(
             r#"
@@ -84,6 +225,68 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_get_by_id() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ws = state.workspace_fetch_by_id(2).await?;
+        assert_eq!(ws.unwrap().name, "Test ws 2");
+        assert!(state.workspace_fetch_by_id(10).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_workspace_settings_should_work() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let settings = state.workspace_settings(1).await?;
+        assert_eq!(settings, WorkspaceSettings::default());
+
+        let settings = state
+            .workspace_settings_update(
+                1,
+                UpdateWorkspaceSettings {
+                    allowed_file_exts: Some(Some(vec!["png".to_string(), "pdf".to_string()])),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        assert_eq!(
+            settings.allowed_file_exts,
+            Some(vec!["png".to_string(), "pdf".to_string()])
+        );
+        assert_eq!(state.workspace_settings(1).await?, settings);
+
+        // fields not in the update are left alone
+        let input: UpdateWorkspaceSettings =
+            serde_json::from_str(r#"{"keep_metadata_exts": ["png"]}"#).unwrap();
+        let settings = state.workspace_settings_update(1, input).await?;
+        assert_eq!(settings.keep_metadata_exts, Some(vec!["png".to_string()]));
+        assert!(settings.allowed_file_exts.is_some());
+
+        let input: UpdateWorkspaceSettings =
+            serde_json::from_str(r#"{"allowed_file_exts": null}"#).unwrap();
+        let settings = state.workspace_settings_update(1, input).await?;
+        assert_eq!(settings.allowed_file_exts, None);
+        assert_eq!(settings.keep_metadata_exts, Some(vec!["png".to_string()]));
+
+        let input: UpdateWorkspaceSettings =
+            serde_json::from_str(r#"{"require_mfa": true}"#).unwrap();
+        state.workspace_settings_update(1, input).await?;
+        assert!(state.workspace_requires_mfa(1).await?);
+        assert!(!state.workspace_requires_mfa(2).await?);
+
+        let ret = state
+            .workspace_settings_update(
+                1,
+                UpdateWorkspaceSettings {
+                    keep_metadata_exts: Some(Some(vec!["../".to_string()])),
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert!(ret.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_get_by_name_should_fail_when_workspace_does_not_exist() -> Result<(), AppError> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -103,7 +306,7 @@ mod tests {
                 email: "test@test.com".to_string(),
                 fullname: "test".to_string(),
                 workspace: "Test Workspace".to_string(),
-                password: "test".to_string(),
+                password: "hunter42".to_string(),
             })
             .await?;
         assert_eq!(user.ws_id, workspace.id);
@@ -113,7 +316,7 @@ mod tests {
                 email: "test1@test.com".to_string(),
                 fullname: "test1".to_string(),
                 workspace: "Test Workspace".to_string(),
-                password: "test".to_string(),
+                password: "hunter42".to_string(),
             })
             .await?;
         assert_eq!(user_1.ws_id, workspace.id);
@@ -0,0 +1,594 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jwt_simple::prelude::*;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+    Url,
+};
+use sha2::{Digest, Sha256};
+
+use crate::AppError;
+
+/// What an identity provider publishes at `/.well-known/openid-configuration`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// The client a workspace registered with its identity provider
+#[derive(Debug, Clone)]
+pub struct OidcProvider<'a> {
+    pub client_id: &'a str,
+    pub client_secret: &'a str,
+}
+
+/// The claims of an ID token we use, the standard ones are checked by
+/// jwt-simple
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IdToken {
+    pub issuer: String,
+    pub subject: String,
+    pub claims: IdTokenClaims,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderJwks {
+    keys: Vec<ProviderJwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderJwk {
+    kty: String,
+    kid: Option<String>,
+    // RSA
+    n: Option<String>,
+    e: Option<String>,
+    // OKP
+    crv: Option<String>,
+    x: Option<String>,
+}
+
+/// OpenID Connect relying party for the authorization code flow with PKCE.
+/// Provider metadata and keys are fetched on every sign in, sign ins are
+/// rare enough.
+#[derive(Debug, Clone)]
+pub struct OidcClient {
+    http: reqwest::Client,
+    // providers are set by workspace admins, unless allowed they can't make
+    // the server call addresses of its own network
+    allow_private: bool,
+}
+
+/// Resolves hosts to their public addresses only, so a provider can't point
+/// its endpoints at internal services, also not by changing its DNS later
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+impl OidcClient {
+    pub fn new(allow_private: bool) -> Self {
+        let mut builder = reqwest::Client::builder();
+        if !allow_private {
+            // a redirect could lead to an address literal
+            builder = builder
+                .dns_resolver(Arc::new(PublicResolver))
+                .redirect(Policy::none());
+        }
+        Self {
+            http: builder.build().expect("build http client failed"),
+            allow_private,
+        }
+    }
+
+    pub async fn discover(&self, issuer: &str) -> Result<ProviderMetadata, AppError> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = self.get_json(&url).await?;
+        if metadata.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+            return Err(AppError::SsoError(format!(
+                "provider metadata is for issuer {}",
+                metadata.issuer
+            )));
+        }
+        Ok(metadata)
+    }
+
+    /// Where to send the browser to sign in at the provider
+    pub fn authorization_url(
+        &self,
+        metadata: &ProviderMetadata,
+        provider: &OidcProvider<'_>,
+        redirect_url: &str,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<String, AppError> {
+        let url = Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", provider.client_id),
+                ("redirect_uri", redirect_url),
+                ("scope", "openid email profile"),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", &pkce_challenge(code_verifier)),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| AppError::SsoError(format!("invalid authorization endpoint: {}", e)))?;
+        Ok(url.into())
+    }
+
+    /// Redeem the authorization code and verify the ID token it returns
+    pub async fn exchange_code(
+        &self,
+        metadata: &ProviderMetadata,
+        provider: &OidcProvider<'_>,
+        redirect_url: &str,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdToken, AppError> {
+        let res = self
+            .http
+            .post(self.check_url(&metadata.token_endpoint)?)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_url),
+                ("client_id", provider.client_id),
+                ("client_secret", provider.client_secret),
+                ("code_verifier", code_verifier),
+            ])
+            .send()
+            .await
+            .map_err(sso_error)?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            return Err(AppError::InvalidToken(format!(
+                "code exchange failed with {}: {}",
+                status, body
+            )));
+        }
+        let token: TokenResponse = res.json().await.map_err(sso_error)?;
+        self.verify_id_token(metadata, provider, &token.id_token, nonce)
+            .await
+    }
+
+    async fn verify_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        provider: &OidcProvider<'_>,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdToken, AppError> {
+        let token_meta = Token::decode_metadata(id_token)
+            .map_err(|e| AppError::InvalidToken(format!("malformed id token: {}", e)))?;
+        let jwks: ProviderJwks = self.get_json(&metadata.jwks_uri).await?;
+        let jwk = jwks
+            .keys
+            .iter()
+            .find(|key| token_meta.key_id().is_none() || key.kid.as_deref() == token_meta.key_id())
+            .ok_or_else(|| {
+                AppError::InvalidToken("id token signed with unknown key".to_string())
+            })?;
+
+        let options = VerificationOptions {
+            required_nonce: Some(nonce.to_string()),
+            allowed_issuers: Some(HashSet::from([metadata.issuer.clone()])),
+            allowed_audiences: Some(HashSet::from([provider.client_id.to_string()])),
+            ..Default::default()
+        };
+        let claims = match (token_meta.algorithm(), jwk.kty.as_str()) {
+            ("RS256", "RSA") => {
+                let (n, e) = (decode_field(&jwk.n)?, decode_field(&jwk.e)?);
+                let key = RS256PublicKey::from_components(&n, &e).map_err(invalid_key)?;
+                key.verify_token::<IdTokenClaims>(id_token, Some(options))
+            }
+            ("EdDSA", "OKP") if jwk.crv.as_deref() == Some("Ed25519") => {
+                let key =
+                    Ed25519PublicKey::from_bytes(&decode_field(&jwk.x)?).map_err(invalid_key)?;
+                key.verify_token::<IdTokenClaims>(id_token, Some(options))
+            }
+            (alg, kty) => {
+                return Err(AppError::InvalidToken(format!(
+                    "unsupported id token algorithm {} with {} key",
+                    alg, kty
+                )))
+            }
+        }
+        .map_err(|e| AppError::InvalidToken(format!("invalid id token: {}", e)))?;
+
+        let subject = claims
+            .subject
+            .ok_or_else(|| AppError::InvalidToken("id token has no subject".to_string()))?;
+        Ok(IdToken {
+            issuer: metadata.issuer.clone(),
+            subject,
+            claims: claims.custom,
+        })
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, AppError> {
+        self.http
+            .get(self.check_url(url)?)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(sso_error)?
+            .json()
+            .await
+            .map_err(sso_error)
+    }
+
+    /// Address literals skip the resolver, they are checked here
+    fn check_url(&self, url: &str) -> Result<Url, AppError> {
+        let url = Url::parse(url)
+            .map_err(|e| AppError::SsoError(format!("invalid provider url {}: {}", url, e)))?;
+        if !matches!(url.scheme(), "https" | "http") {
+            return Err(AppError::SsoError(format!(
+                "unsupported provider url {}",
+                url
+            )));
+        }
+        let ip = url
+            .host_str()
+            .and_then(|host| host.trim_matches(['[', ']']).parse::<IpAddr>().ok());
+        if !self.allow_private && ip.is_some_and(|ip| !is_public(ip)) {
+            return Err(AppError::SsoError(format!(
+                "provider url {} is not a public address",
+                url
+            )));
+        }
+        Ok(url)
+    }
+}
+
+// addresses reachable on the internet, `IpAddr::is_global` isn't stable
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // shared address space of carrier-grade NAT
+        || (a == 100 && (64..128).contains(&b))
+        // reserved
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local
+        || (first & 0xfe00) == 0xfc00
+        // link local
+        || (first & 0xffc0) == 0xfe80
+        // documentation
+        || first == 0x2001 && ip.segments()[1] == 0xdb8)
+}
+
+/// RFC 7636 S256 code challenge
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn decode_field(field: &Option<String>) -> Result<Vec<u8>, AppError> {
+    let field = field
+        .as_deref()
+        .ok_or_else(|| AppError::InvalidToken("incomplete provider key".to_string()))?;
+    URL_SAFE_NO_PAD.decode(field).map_err(invalid_key)
+}
+
+fn invalid_key(e: impl std::fmt::Display) -> AppError {
+    AppError::InvalidToken(format!("invalid provider key: {}", e))
+}
+
+fn sso_error(e: reqwest::Error) -> AppError {
+    AppError::SsoError(e.to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        extract::{Query, State},
+        http::StatusCode,
+        response::{IntoResponse, Redirect},
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use reqwest::redirect::Policy;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{models::SsoCallbackQuery, models::WorkspaceSso, AppState};
+
+    pub(crate) const CLIENT_ID: &str = "chat";
+    pub(crate) const CLIENT_SECRET: &str = "chat-secret";
+
+    /// The account that signs in at the mock provider
+    #[derive(Debug, Clone)]
+    pub(crate) struct MockAccount {
+        pub subject: String,
+        pub email: String,
+        pub email_verified: bool,
+    }
+
+    /// A local identity provider with an RS256 key that signs in whatever
+    /// account is set without asking
+    #[derive(Clone)]
+    pub(crate) struct MockIdp {
+        pub issuer: String,
+        inner: Arc<MockIdpInner>,
+    }
+
+    struct MockIdpInner {
+        key: RS256KeyPair,
+        account: Mutex<MockAccount>,
+        // code -> (nonce, code challenge)
+        codes: Mutex<HashMap<String, (String, String)>>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct AuthorizeQuery {
+        redirect_uri: String,
+        state: String,
+        nonce: String,
+        code_challenge: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct TokenForm {
+        code: String,
+        client_id: String,
+        client_secret: String,
+        code_verifier: String,
+    }
+
+    impl MockIdp {
+        pub(crate) async fn start(account: MockAccount) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let key = RS256KeyPair::from_pem(include_str!("../fixtures/idp_rsa.pem"))
+                .unwrap()
+                .with_key_id("mock-1");
+            let idp = Self {
+                issuer,
+                inner: Arc::new(MockIdpInner {
+                    key,
+                    account: Mutex::new(account),
+                    codes: Mutex::new(HashMap::new()),
+                }),
+            };
+            let app = Router::new()
+                .route("/.well-known/openid-configuration", get(discovery))
+                .route("/jwks", get(jwks))
+                .route("/authorize", get(authorize_endpoint))
+                .route("/token", post(token_endpoint))
+                .with_state(idp.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            idp
+        }
+
+        pub(crate) fn set_account(&self, account: MockAccount) {
+            *self.inner.account.lock().unwrap() = account;
+        }
+    }
+
+    async fn discovery(State(idp): State<MockIdp>) -> impl IntoResponse {
+        Json(ProviderMetadata {
+            issuer: idp.issuer.clone(),
+            authorization_endpoint: format!("{}/authorize", idp.issuer),
+            token_endpoint: format!("{}/token", idp.issuer),
+            jwks_uri: format!("{}/jwks", idp.issuer),
+        })
+    }
+
+    async fn jwks(State(idp): State<MockIdp>) -> impl IntoResponse {
+        let components = idp.inner.key.public_key().to_components();
+        Json(json!({
+            "keys": [{
+                "kty": "RSA",
+                "kid": "mock-1",
+                "alg": "RS256",
+                "use": "sig",
+                "n": URL_SAFE_NO_PAD.encode(components.n),
+                "e": URL_SAFE_NO_PAD.encode(components.e),
+            }]
+        }))
+    }
+
+    async fn authorize_endpoint(
+        State(idp): State<MockIdp>,
+        Query(query): Query<AuthorizeQuery>,
+    ) -> impl IntoResponse {
+        let code = uuid::Uuid::now_v7().to_string();
+        idp.inner
+            .codes
+            .lock()
+            .unwrap()
+            .insert(code.clone(), (query.nonce, query.code_challenge));
+        let url = Url::parse_with_params(
+            &query.redirect_uri,
+            &[("code", code.as_str()), ("state", query.state.as_str())],
+        )
+        .unwrap();
+        Redirect::to(url.as_str())
+    }
+
+    async fn token_endpoint(
+        State(idp): State<MockIdp>,
+        Form(form): Form<TokenForm>,
+    ) -> impl IntoResponse {
+        let Some((nonce, challenge)) = idp.inner.codes.lock().unwrap().remove(&form.code) else {
+            return (StatusCode::BAD_REQUEST, "invalid_grant").into_response();
+        };
+        if form.client_id != CLIENT_ID || form.client_secret != CLIENT_SECRET {
+            return (StatusCode::UNAUTHORIZED, "invalid_client").into_response();
+        }
+        if pkce_challenge(&form.code_verifier) != challenge {
+            return (StatusCode::BAD_REQUEST, "invalid_grant").into_response();
+        }
+        let account = idp.inner.account.lock().unwrap().clone();
+        let custom = IdTokenClaims {
+            email: Some(account.email),
+            email_verified: account.email_verified,
+            name: Some("Mock User".to_string()),
+        };
+        let claims = Claims::with_custom_claims(custom, Duration::from_mins(5))
+            .with_issuer(&idp.issuer)
+            .with_audience(CLIENT_ID)
+            .with_subject(account.subject)
+            .with_nonce(nonce);
+        let id_token = idp.inner.key.sign(claims).unwrap();
+        Json(json!({
+            "access_token": "mock-access-token",
+            "token_type": "Bearer",
+            "id_token": id_token,
+        }))
+        .into_response()
+    }
+
+    pub(crate) async fn mock_sso(state: &AppState, ws_id: i64, account: MockAccount) -> MockIdp {
+        let idp = MockIdp::start(account).await;
+        state
+            .workspace_sso_update(
+                ws_id,
+                WorkspaceSso {
+                    issuer: idp.issuer.clone(),
+                    client_id: CLIENT_ID.to_string(),
+                    client_secret: CLIENT_SECRET.to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        idp
+    }
+
+    /// Follow the authorization URL through the provider, returns the query
+    /// the provider redirects back with
+    pub(crate) async fn authorize(url: &str) -> SsoCallbackQuery {
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .build()
+            .unwrap();
+        let res = client.get(url).send().await.unwrap();
+        let location = res.headers()["location"].to_str().unwrap();
+        let location = Url::parse(location).unwrap();
+        let param = |name: &str| {
+            location
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.to_string())
+        };
+        SsoCallbackQuery {
+            code: param("code"),
+            state: param("state").unwrap(),
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn oidc_client_should_only_call_public_addresses() {
+        let idp = MockIdp::start(MockAccount {
+            subject: "alice".to_string(),
+            email: "alice@acme.org".to_string(),
+            email_verified: true,
+        })
+        .await;
+        let ret = OidcClient::new(false).discover(&idp.issuer).await;
+        assert!(matches!(ret, Err(AppError::SsoError(msg)) if msg.contains("public")));
+        OidcClient::new(true).discover(&idp.issuer).await.unwrap();
+
+        let ret = PublicResolver.resolve("localhost".parse().unwrap()).await;
+        assert!(ret.is_err());
+
+        for ip in ["93.184.216.34", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::ffff:10.0.0.1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn pkce_challenge_should_match_rfc7636() {
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use std::{future::Future, pin::Pin, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{config::ScannerConfig, AppError};
+
+// clamd rejects streams larger than StreamMaxLength anyway, keep chunks small
+const CHUNK_SIZE: usize = 64 * 1024;
+const MAX_REPLY_LEN: usize = 4096;
+
+pub type ScanFuture<'a> = Pin<Box<dyn Future<Output = Result<ScanVerdict, AppError>> + Send + 'a>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    // name of the matched signature
+    Infected(String),
+}
+
+/// Scans uploaded content before it is made available to other users.
+pub trait FileScanner: Send + Sync {
+    fn scan<'a>(&'a self, data: &'a [u8]) -> ScanFuture<'a>;
+}
+
+/// Scanner talking to a ClamAV daemon with the `INSTREAM` command.
+#[derive(Debug, Clone)]
+pub struct ClamdScanner {
+    addr: String,
+    timeout: Duration,
+}
+
+impl ClamdScanner {
+    pub fn new(config: &ScannerConfig) -> Self {
+        Self {
+            addr: config.clamd_addr.clone(),
+            timeout: Duration::from_secs(config.timeout_secs),
+        }
+    }
+
+    async fn instream(&self, data: &[u8]) -> Result<ScanVerdict, AppError> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in data.chunks(CHUNK_SIZE) {
+            stream
+                .write_all(&(chunk.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(chunk).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        stream.flush().await?;
+
+        // the reply is terminated by \0 for z-prefixed commands
+        let mut reply = Vec::new();
+        let mut buf = [0u8; 512];
+        loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            reply.extend_from_slice(&buf[..n]);
+            if reply.contains(&0) || reply.len() > MAX_REPLY_LEN {
+                break;
+            }
+        }
+        let reply = String::from_utf8_lossy(&reply);
+        parse_reply(reply.trim_end_matches('\0').trim())
+    }
+}
+
+impl FileScanner for ClamdScanner {
+    fn scan<'a>(&'a self, data: &'a [u8]) -> ScanFuture<'a> {
+        Box::pin(async move {
+            match timeout(self.timeout, self.instream(data)).await {
+                Ok(ret) => ret,
+                Err(_) => Err(AppError::FileScanError("clamd timed out".to_string())),
+            }
+        })
+    }
+}
+
+// replies look like `stream: OK`, `stream: Eicar-Signature FOUND` or `... ERROR`
+fn parse_reply(reply: &str) -> Result<ScanVerdict, AppError> {
+    let status = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if status == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = status.strip_suffix(" FOUND") {
+        Ok(ScanVerdict::Infected(signature.to_string()))
+    } else {
+        Err(AppError::FileScanError(format!(
+            "unexpected clamd reply: {}",
+            reply
+        )))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    pub(crate) const EICAR: &[u8] =
+        br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    /// A fake clamd that understands `zINSTREAM` and flags the EICAR test file.
+    pub(crate) async fn fake_clamd() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut cmd = [0u8; 10];
+                    stream.read_exact(&mut cmd).await?;
+                    if &cmd != b"zINSTREAM\0" {
+                        stream.write_all(b"UNKNOWN COMMAND\0").await?;
+                        return Ok::<_, std::io::Error>(());
+                    }
+                    let mut data = Vec::new();
+                    loop {
+                        let len = stream.read_u32().await? as usize;
+                        if len == 0 {
+                            break;
+                        }
+                        let mut chunk = vec![0u8; len];
+                        stream.read_exact(&mut chunk).await?;
+                        data.extend_from_slice(&chunk);
+                    }
+                    let infected = data.windows(EICAR.len()).any(|w| w == EICAR);
+                    let reply: &[u8] = if infected {
+                        b"stream: Eicar-Test-Signature FOUND\0"
+                    } else {
+                        b"stream: OK\0"
+                    };
+                    stream.write_all(reply).await?;
+                    Ok(())
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    pub(crate) fn scanner_for(addr: SocketAddr) -> ClamdScanner {
+        ClamdScanner::new(&ScannerConfig {
+            clamd_addr: addr.to_string(),
+            quarantine: false,
+            timeout_secs: 5,
+        })
+    }
+
+    #[tokio::test]
+    async fn clamd_scanner_should_pass_clean_files() -> Result<()> {
+        let scanner = scanner_for(fake_clamd().await?);
+        let data = vec![b'a'; CHUNK_SIZE * 2 + 1];
+        assert_eq!(scanner.scan(&data).await?, ScanVerdict::Clean);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clamd_scanner_should_detect_infected_files() -> Result<()> {
+        let scanner = scanner_for(fake_clamd().await?);
+        assert_eq!(
+            scanner.scan(EICAR).await?,
+            ScanVerdict::Infected("Eicar-Test-Signature".to_string())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clamd_scanner_should_fail_when_daemon_is_down() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        drop(listener);
+        let scanner = scanner_for(addr);
+        assert!(scanner.scan(b"hello").await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_reply_should_work() {
+        assert_eq!(parse_reply("stream: OK").unwrap(), ScanVerdict::Clean);
+        assert_eq!(
+            parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND").unwrap(),
+            ScanVerdict::Infected("Win.Test.EICAR_HDB-1".to_string())
+        );
+        assert!(parse_reply("INSTREAM size limit exceeded. ERROR").is_err());
+    }
+}
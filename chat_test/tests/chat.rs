@@ -35,7 +35,8 @@ async fn chat_server_should_work() -> anyhow::Result<()> {
     let token = server.token.rsplit(" ").next().unwrap();
     dbg!(format!("token: {}", token));
 
-    let _notify_server = NotifyServer::new(&db_url, token).await?;
+    let jwks_url = format!("http://{}/.well-known/jwks.json", server.addr);
+    let _notify_server = NotifyServer::new(&db_url, &jwks_url, token).await?;
 
     server.create_chat().await?;
     server.create_message().await?;
@@ -45,9 +46,10 @@ async fn chat_server_should_work() -> anyhow::Result<()> {
 }
 
 impl NotifyServer {
-    pub async fn new(db_url: &str, token: &str) -> anyhow::Result<Self> {
+    pub async fn new(db_url: &str, jwks_url: &str, token: &str) -> anyhow::Result<Self> {
         let mut config = notify_server::AppConfig::load()?;
         config.server.db_url = db_url.to_string();
+        config.auth.jwks_url = jwks_url.to_string();
 
         let app = notify_server::get_router(config).await?;
 
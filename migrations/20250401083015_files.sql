@@ -0,0 +1,51 @@
+-- Add migration script here
+-- create table for uploaded files, one row per stored blob
+CREATE TABLE IF NOT EXISTS files (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+    -- file url, e.g. /files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt
+    path VARCHAR(255) NOT NULL UNIQUE,
+    hash VARCHAR(64) NOT NULL,
+    ext VARCHAR(32) NOT NULL,
+    size BIGINT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    -- bumped on every (deduplicated) upload, used as the gc grace period start
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_files_ws_id ON files (ws_id);
+
+-- create table for file references from messages
+CREATE TABLE IF NOT EXISTS message_files (
+    message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    path VARCHAR(255) NOT NULL,
+    PRIMARY KEY (message_id, path)
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_files_path ON message_files (path);
+
+-- keep message_files in sync with messages.files
+CREATE OR REPLACE FUNCTION sync_message_files()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' THEN
+        DELETE FROM message_files WHERE message_id = NEW.id;
+    END IF;
+    IF NEW.files IS NOT NULL THEN
+        INSERT INTO message_files (message_id, path)
+        SELECT DISTINCT NEW.id, f FROM unnest(NEW.files) AS f
+        ON CONFLICT DO NOTHING;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER sync_message_files_trigger
+    AFTER INSERT OR UPDATE OF files ON messages
+    FOR EACH ROW
+    EXECUTE PROCEDURE sync_message_files();
+
+-- backfill references of existing messages
+INSERT INTO message_files (message_id, path)
+SELECT DISTINCT id, unnest(files) FROM messages WHERE files IS NOT NULL
+ON CONFLICT DO NOTHING;
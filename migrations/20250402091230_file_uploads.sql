@@ -0,0 +1,10 @@
+-- Add migration script here
+-- who uploaded a file, the same blob can be uploaded by several users
+CREATE TABLE IF NOT EXISTS file_uploads (
+    file_id BIGINT NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (file_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_uploads_user_id ON file_uploads (user_id);
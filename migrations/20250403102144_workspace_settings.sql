@@ -0,0 +1,11 @@
+-- Add migration script here
+-- per workspace settings, a missing row or NULL column means the server default
+CREATE TABLE IF NOT EXISTS workspace_settings (
+    ws_id BIGINT PRIMARY KEY REFERENCES workspaces(id),
+    -- file extensions allowed for upload and download
+    allowed_file_exts TEXT[],
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- content type sniffed from the file content on upload
+ALTER TABLE files ADD COLUMN content_type VARCHAR(128) NOT NULL DEFAULT 'application/octet-stream';
@@ -0,0 +1,14 @@
+-- Add migration script here
+-- malware scan state of uploaded files
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'file_scan_status') THEN
+        CREATE TYPE file_scan_status AS ENUM ('unscanned', 'pending', 'clean', 'infected');
+    END IF;
+END $$;
+
+ALTER TABLE files
+    ADD COLUMN scan_status file_scan_status NOT NULL DEFAULT 'unscanned',
+    -- matched signature or scanner error
+    ADD COLUMN scan_result TEXT,
+    ADD COLUMN scanned_at TIMESTAMPTZ;
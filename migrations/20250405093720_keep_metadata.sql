@@ -0,0 +1,3 @@
+-- Add migration script here
+-- image types whose metadata (EXIF, XMP, ...) is kept on upload, NULL strips all
+ALTER TABLE workspace_settings ADD COLUMN keep_metadata_exts TEXT[];
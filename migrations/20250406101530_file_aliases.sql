@@ -0,0 +1,7 @@
+-- Add migration script here
+-- old urls of re-addressed files (sha1 to sha256), so links held by clients keep resolving
+CREATE TABLE IF NOT EXISTS file_aliases (
+    path VARCHAR(255) PRIMARY KEY,
+    target VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
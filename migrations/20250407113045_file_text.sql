@@ -0,0 +1,11 @@
+-- Add migration script here
+-- text extracted from the file content for search, NULL if the file has none
+ALTER TABLE files ADD COLUMN text_content TEXT;
+-- set once extraction ran, successful or not
+ALTER TABLE files ADD COLUMN text_extracted_at TIMESTAMPTZ;
+ALTER TABLE files ADD COLUMN text_tsv TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector('simple', COALESCE(text_content, ''))) STORED;
+
+CREATE INDEX IF NOT EXISTS idx_files_text_tsv ON files USING GIN (text_tsv);
+CREATE INDEX IF NOT EXISTS idx_messages_content_tsv
+    ON messages USING GIN (to_tsvector('simple', content));
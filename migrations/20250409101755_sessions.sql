@@ -0,0 +1,41 @@
+-- Add migration script here
+-- one row per sign in, access tokens carry its id as the jti claim
+CREATE TABLE IF NOT EXISTS sessions (
+    id UUID PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    user_agent TEXT,
+    ip VARCHAR(64),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_user_id ON sessions (user_id);
+
+-- a refresh token family belongs to the session of the sign in
+INSERT INTO sessions (id, user_id, created_at, revoked_at)
+SELECT family_id, MIN(user_id), MIN(created_at),
+    CASE WHEN bool_and(revoked_at IS NOT NULL) THEN MAX(revoked_at) END
+FROM refresh_tokens
+GROUP BY family_id
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE refresh_tokens
+    ADD CONSTRAINT refresh_tokens_family_id_fkey
+    FOREIGN KEY (family_id) REFERENCES sessions(id) ON DELETE CASCADE;
+
+-- tell notify_server to close the streams of revoked sessions
+CREATE OR REPLACE FUNCTION session_revoked()
+RETURNS TRIGGER AS $$
+BEGIN
+    RAISE NOTICE 'session_revoked: %', NEW.id;
+    PERFORM pg_notify('session_revoked', json_build_object('id', NEW.id, 'user_id', NEW.user_id)::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER session_revoked_trigger
+    AFTER UPDATE OF revoked_at ON sessions
+    FOR EACH ROW
+    WHEN (OLD.revoked_at IS NULL AND NEW.revoked_at IS NOT NULL)
+    EXECUTE PROCEDURE session_revoked();
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- new accounts confirm their email with a mailed link
+ALTER TYPE user_token_purpose ADD VALUE IF NOT EXISTS 'email_verification';
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS verified_at TIMESTAMPTZ;
+
+-- accounts created before verification existed keep working
+UPDATE users SET verified_at = created_at WHERE verified_at IS NULL;
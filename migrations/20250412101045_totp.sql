@@ -0,0 +1,27 @@
+-- Add migration script here
+-- TOTP second factor, the secret is confirmed with a first code
+CREATE TABLE IF NOT EXISTS user_totp (
+    user_id BIGINT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    secret BYTEA NOT NULL,
+    confirmed_at TIMESTAMPTZ,
+    -- time step of the last accepted code, a code is only accepted once
+    last_step BIGINT,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- one-time codes for users who lost their authenticator
+CREATE TABLE IF NOT EXISTS user_recovery_codes (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- sha256 of the code
+    code_hash VARCHAR(64) NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_recovery_codes_user_id ON user_recovery_codes (user_id);
+
+-- handed out after the password checked out, exchanged with a code for a session
+ALTER TYPE user_token_purpose ADD VALUE IF NOT EXISTS 'mfa_pending';
+
+ALTER TABLE workspace_settings ADD COLUMN IF NOT EXISTS require_mfa BOOLEAN;
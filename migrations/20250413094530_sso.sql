@@ -0,0 +1,36 @@
+-- Add migration script here
+-- OpenID Connect provider of a workspace
+CREATE TABLE IF NOT EXISTS workspace_sso (
+    ws_id BIGINT PRIMARY KEY REFERENCES workspaces(id),
+    issuer TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    client_secret TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- sign ins waiting for the provider to redirect back
+CREATE TABLE IF NOT EXISTS sso_logins (
+    -- sha256 of the state parameter
+    state_hash VARCHAR(64) PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- provider accounts linked to users
+CREATE TABLE IF NOT EXISTS user_identities (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (issuer, subject)
+);
+
+-- users created by single sign-on have no password
+ALTER TABLE users ALTER COLUMN password_hash DROP NOT NULL;
+
+ALTER TABLE workspace_settings ADD COLUMN IF NOT EXISTS password_login BOOLEAN;
@@ -0,0 +1,20 @@
+-- Add migration script here
+-- long lived tokens for scripts and integrations
+CREATE TABLE IF NOT EXISTS personal_access_tokens (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- the workspace the token was created in, it stops working if the user moves
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+    name VARCHAR(64) NOT NULL,
+    -- sha256 of the token, the token itself is only shown once
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    scopes TEXT[] NOT NULL,
+    -- chats the token is limited to, NULL for every chat
+    chat_ids BIGINT[],
+    expires_at TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_personal_access_tokens_user_id ON personal_access_tokens (user_id);
@@ -0,0 +1,10 @@
+-- Add migration script here
+-- failed password sign ins per account and per IP
+CREATE TABLE IF NOT EXISTS signin_throttles (
+    -- `email:<address>` or `ip:<address>`
+    key VARCHAR(320) PRIMARY KEY,
+    failures INT NOT NULL,
+    -- no further attempts are checked before this
+    blocked_until TIMESTAMPTZ,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+-- passwordless sign in with a mailed link
+ALTER TYPE user_token_purpose ADD VALUE IF NOT EXISTS 'magic_link';
+
+-- sha256 of a nonce kept by the browser that asked for the token, the token
+-- only works together with it
+ALTER TABLE user_tokens ADD COLUMN IF NOT EXISTS nonce_hash VARCHAR(64);
+
+ALTER TABLE workspace_settings ADD COLUMN IF NOT EXISTS magic_link_login BOOLEAN;
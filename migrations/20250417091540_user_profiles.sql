@@ -0,0 +1,41 @@
+-- Add migration script here
+-- profile fields users edit at /api/me
+ALTER TABLE users ADD COLUMN IF NOT EXISTS display_name VARCHAR(64);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS title VARCHAR(128);
+-- IANA time zone, e.g. Asia/Shanghai
+ALTER TABLE users ADD COLUMN IF NOT EXISTS timezone VARCHAR(64);
+-- BCP 47 language tag, e.g. zh-CN
+ALTER TABLE users ADD COLUMN IF NOT EXISTS locale VARCHAR(35);
+-- url of an uploaded image, kept by file gc while set
+ALTER TABLE users ADD COLUMN IF NOT EXISTS avatar_url VARCHAR(255);
+
+-- if a profile changed, notify with the profile
+CREATE OR REPLACE FUNCTION user_updated()
+RETURNS TRIGGER AS $$
+BEGIN
+    RAISE NOTICE 'user_updated: %', NEW.id;
+    PERFORM pg_notify('user_updated', json_build_object(
+        'id', NEW.id,
+        'ws_id', NEW.ws_id,
+        'fullname', NEW.fullname,
+        'email', NEW.email,
+        'display_name', NEW.display_name,
+        'title', NEW.title,
+        'timezone', NEW.timezone,
+        'locale', NEW.locale,
+        'avatar_url', NEW.avatar_url,
+        'updated_at', NEW.updated_at
+    )::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER user_updated_trigger
+    AFTER UPDATE ON users
+    FOR EACH ROW
+    WHEN (
+        (OLD.fullname, OLD.display_name, OLD.title, OLD.timezone, OLD.locale, OLD.avatar_url)
+        IS DISTINCT FROM
+        (NEW.fullname, NEW.display_name, NEW.title, NEW.timezone, NEW.locale, NEW.avatar_url)
+    )
+    EXECUTE PROCEDURE user_updated();
@@ -0,0 +1,47 @@
+-- Add migration script here
+-- custom status shown next to the user, cleared once it expires
+ALTER TABLE users ADD COLUMN IF NOT EXISTS status_emoji VARCHAR(32);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS status_text VARCHAR(128);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS status_expires_at TIMESTAMPTZ;
+
+-- do not disturb until a point in time, or daily between two local times
+ALTER TABLE users ADD COLUMN IF NOT EXISTS dnd_until TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS quiet_hours_start TIME;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS quiet_hours_end TIME;
+
+-- urgent messages reach users in do not disturb
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS urgent BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- whether notifications to a user are held at a point in time, quiet hours
+-- are in the user's timezone and may span midnight
+CREATE OR REPLACE FUNCTION user_dnd_active(uid BIGINT, at TIMESTAMPTZ)
+RETURNS BOOLEAN AS $$
+    SELECT COALESCE(bool_or(
+        COALESCE(dnd_until > at, FALSE)
+        OR CASE
+            WHEN quiet_hours_start IS NULL OR quiet_hours_end IS NULL THEN FALSE
+            WHEN quiet_hours_start <= quiet_hours_end THEN
+                (at AT TIME ZONE COALESCE(timezone, 'UTC'))::time >= quiet_hours_start
+                AND (at AT TIME ZONE COALESCE(timezone, 'UTC'))::time < quiet_hours_end
+            ELSE
+                (at AT TIME ZONE COALESCE(timezone, 'UTC'))::time >= quiet_hours_start
+                OR (at AT TIME ZONE COALESCE(timezone, 'UTC'))::time < quiet_hours_end
+        END
+    ), FALSE)
+    FROM users WHERE id = uid;
+$$ LANGUAGE sql STABLE;
+
+-- users as listed to members of their workspace, expired statuses hidden
+CREATE OR REPLACE VIEW chat_users AS
+SELECT
+    id,
+    ws_id,
+    fullname,
+    email,
+    CASE WHEN status_expires_at IS NULL OR status_expires_at > now() THEN status_emoji END
+        AS status_emoji,
+    CASE WHEN status_expires_at IS NULL OR status_expires_at > now() THEN status_text END
+        AS status_text,
+    CASE WHEN status_expires_at > now() THEN status_expires_at END AS status_expires_at,
+    user_dnd_active(id, now()) AS dnd
+FROM users;
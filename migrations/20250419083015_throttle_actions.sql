@@ -0,0 +1,5 @@
+-- Add migration script here
+-- keys are `<action>:email:<address>` or `<action>:ip:<address>` now, the
+-- counters of password sign ins are kept apart from those of mailed links
+ALTER TABLE signin_throttles ALTER COLUMN key TYPE VARCHAR(400);
+DELETE FROM signin_throttles WHERE key LIKE 'email:%' OR key LIKE 'ip:%';
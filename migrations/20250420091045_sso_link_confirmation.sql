@@ -0,0 +1,7 @@
+-- Add migration script here
+-- a provider account with the email of an existing user is only linked once
+-- the user confirmed it with a mailed link
+ALTER TYPE user_token_purpose ADD VALUE IF NOT EXISTS 'sso_link';
+
+ALTER TABLE user_identities ADD COLUMN IF NOT EXISTS confirmed_at TIMESTAMPTZ;
+UPDATE user_identities SET confirmed_at = created_at WHERE confirmed_at IS NULL;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- expired sign ins at a provider are purged when new ones start
+CREATE INDEX IF NOT EXISTS idx_sso_logins_expires_at ON sso_logins (expires_at);
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- quarantined files the scanner kept failing on, scanned again when uploaded again
+ALTER TYPE file_scan_status ADD VALUE IF NOT EXISTS 'failed';
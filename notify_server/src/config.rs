@@ -6,6 +6,12 @@ use serde::Deserialize;
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub dnd: DndConfig,
+    #[serde(default)]
+    pub presence: PresenceConfig,
+    #[serde(default)]
+    pub typing: TypingConfig,
 }
 
 #[derive(Debug, Deserialize)]
@@ -16,7 +22,85 @@ pub struct ServerConfig {
 
 #[derive(Debug, Deserialize)]
 pub struct AuthConfig {
-    pub pk: String,
+    // where chat_server publishes the keys tokens are verified with
+    pub jwks_url: String,
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+    // how long a session lookup is trusted, revocations announced by the
+    // database are applied right away
+    #[serde(default = "default_session_cache_secs")]
+    pub session_cache_secs: u64,
+    // how long a user looked up for a token is trusted
+    #[serde(default = "default_user_cache_secs")]
+    pub user_cache_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DndConfig {
+    // how often held events are checked for users whose do not disturb ended
+    pub flush_interval_secs: u64,
+    // held events per user, the oldest are dropped beyond that
+    pub max_held_events: usize,
+}
+
+impl Default for DndConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval_secs: 30,
+            max_held_events: 500,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    // connected users without a heartbeat for this long are away
+    pub away_after_secs: u64,
+    // users stay online this long after their last event stream closed
+    pub offline_grace_secs: u64,
+    pub sweep_interval_secs: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            away_after_secs: 5 * 60,
+            offline_grace_secs: 30,
+            sweep_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TypingConfig {
+    // how long clients show a typing indicator without another signal
+    pub ttl_secs: u64,
+    // signals of a user in a chat more frequent than this are dropped
+    pub throttle_secs: u64,
+}
+
+impl Default for TypingConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 6,
+            throttle_secs: 3,
+        }
+    }
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    60 * 5
+}
+
+fn default_session_cache_secs() -> u64 {
+    10
+}
+
+fn default_user_cache_secs() -> u64 {
+    30
 }
 
 impl AppConfig {
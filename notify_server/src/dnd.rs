@@ -0,0 +1,87 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use sqlx::PgPool;
+use tracing::{error, info, warn};
+
+use crate::{AppEvent, AppState};
+
+impl AppState {
+    /// Send the event to the user if connected. Events held back stay held,
+    /// only `flush_held` releases them once do not disturb ended, an urgent
+    /// message or a chat update mustn't let them through.
+    pub fn deliver(&self, user_id: u64, event: Arc<AppEvent>) {
+        self.send_all(user_id, [event]);
+    }
+
+    pub(crate) fn send_all(&self, user_id: u64, events: impl IntoIterator<Item = Arc<AppEvent>>) {
+        let Some(tx) = self.users.get(&user_id) else {
+            return;
+        };
+        for event in events {
+            info!("sending event to user {}: {:?}", user_id, event);
+            if let Err(e) = tx.send(event) {
+                error!("failed to send event to user {}: {:?}", user_id, e);
+            }
+        }
+    }
+
+    /// Keep the event until the user's do not disturb ends
+    pub fn hold(&self, user_id: u64, event: Arc<AppEvent>) {
+        info!("holding event for user {}: {:?}", user_id, event);
+        let mut held = self.held.entry(user_id).or_default();
+        if held.len() >= self.config.dnd.max_held_events {
+            warn!(
+                "too many held events for user {}, dropping the oldest",
+                user_id
+            );
+            held.pop_front();
+        }
+        held.push_back(event);
+    }
+
+    /// Deliver the held events of users whose do not disturb ended, those of
+    /// users who disconnected are dropped, they load messages on reconnect
+    pub async fn flush_held(&self) -> anyhow::Result<()> {
+        let user_ids: Vec<u64> = self.held.iter().map(|entry| *entry.key()).collect();
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+        let dnd = dnd_users(&self.pool, &user_ids).await?;
+        for user_id in user_ids {
+            if !self.users.contains_key(&user_id) {
+                self.held.remove(&user_id);
+            } else if !dnd.contains(&user_id) {
+                let held = self.held.remove(&user_id).map(|(_, held)| held);
+                let held = held.unwrap_or_default();
+                info!("delivering {} held events to user {}", held.len(), user_id);
+                self.send_all(user_id, held);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The users in do not disturb right now
+pub async fn dnd_users(pool: &PgPool, user_ids: &[u64]) -> anyhow::Result<HashSet<u64>> {
+    let user_ids: Vec<i64> = user_ids.iter().map(|id| *id as i64).collect();
+    let rows: Vec<(i64,)> = sqlx::query_as(
+        "SELECT id FROM unnest($1::bigint[]) AS id WHERE user_dnd_active(id, now())",
+    )
+    .bind(&user_ids)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(|(id,)| id as u64).collect())
+}
+
+pub fn dnd_flush_schedule(state: AppState) {
+    let interval = Duration::from_secs(state.config.dnd.flush_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = state.flush_held().await {
+                error!("failed to flush held events: {:?}", e);
+            }
+        }
+    });
+}
@@ -8,6 +8,18 @@ pub enum AppError {
     IoError(#[from] std::io::Error),
     #[error("jwt encoding key error: {0}")]
     JwtError(#[from] jwt_simple::Error),
+    #[error("http error: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("sql error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
 }
 
 impl IntoResponse for AppError {
@@ -15,6 +27,12 @@ impl IntoResponse for AppError {
         let status_code = match &self {
             AppError::JwtError(_) => axum::http::StatusCode::FORBIDDEN,
             AppError::IoError(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::HttpError(_) => axum::http::StatusCode::BAD_GATEWAY,
+            AppError::SqlxError(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::InvalidToken(_) => axum::http::StatusCode::UNAUTHORIZED,
+            AppError::InvalidInput(_) => axum::http::StatusCode::BAD_REQUEST,
+            AppError::NotFound(_) => axum::http::StatusCode::NOT_FOUND,
+            AppError::PermissionDenied(_) => axum::http::StatusCode::FORBIDDEN,
         };
 
         let body = Json(json!({
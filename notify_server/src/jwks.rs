@@ -0,0 +1,190 @@
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use chat_core::{DecodingKey, Jwks, TokenClaims, token_key_id};
+use tracing::{info, warn};
+
+use crate::AppError;
+
+// refetch at most this often when a token names a key we don't know
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The token verification keys published by chat_server, refreshed
+/// periodically and whenever a token is signed with an unknown key.
+#[derive(Clone)]
+pub struct KeyStore {
+    inner: Arc<KeyStoreInner>,
+}
+
+struct KeyStoreInner {
+    url: String,
+    client: reqwest::Client,
+    keys: RwLock<Option<Arc<DecodingKey>>>,
+    fetched_at: Mutex<Option<Instant>>,
+}
+
+impl KeyStore {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            inner: Arc::new(KeyStoreInner {
+                url: url.into(),
+                client: reqwest::Client::new(),
+                keys: RwLock::new(None),
+                fetched_at: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub async fn refresh(&self) -> Result<(), AppError> {
+        *self.inner.fetched_at.lock().expect("key store poisoned") = Some(Instant::now());
+        let jwks: Jwks = self
+            .inner
+            .client
+            .get(&self.inner.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let keys = DecodingKey::from_jwks(&jwks)?;
+        info!("loaded verification keys from {}", self.inner.url);
+        *self.inner.keys.write().expect("key store poisoned") = Some(Arc::new(keys));
+        Ok(())
+    }
+
+    /// Refresh the keys every `interval`, keys stay as they are if
+    /// chat_server can't be reached
+    pub fn refresh_schedule(&self, interval: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = store.refresh().await {
+                    warn!("failed to fetch verification keys: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Verify a token, a token signed with an unknown key is invalid even
+    /// when the keys can't be refetched
+    pub async fn verify(&self, token: &str) -> Result<TokenClaims, AppError> {
+        let kid = token_key_id(token);
+        let known = |keys: &DecodingKey| kid.as_deref().is_none_or(|kid| keys.has_key(kid));
+        if !self.keys().is_some_and(|keys| known(&keys))
+            && self.refetch_allowed()
+            && let Err(e) = self.refresh().await
+        {
+            warn!("failed to fetch verification keys: {}", e);
+        }
+        match self.keys() {
+            Some(keys) if known(&keys) => Ok(keys.verify(token)?),
+            Some(_) => Err(AppError::InvalidToken("unknown signing key".to_string())),
+            None => Err(AppError::InvalidToken(
+                "verification keys unavailable".to_string(),
+            )),
+        }
+    }
+
+    fn keys(&self) -> Option<Arc<DecodingKey>> {
+        self.inner.keys.read().expect("key store poisoned").clone()
+    }
+
+    fn refetch_allowed(&self) -> bool {
+        let fetched_at = self.inner.fetched_at.lock().expect("key store poisoned");
+        fetched_at.is_none_or(|at| at.elapsed() >= MIN_REFETCH_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+    use chat_core::{EncodingKey, KeyConfig};
+    use jwt_simple::prelude::*;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    type Published = Arc<Mutex<Option<Jwks>>>;
+
+    fn signing_key(kid: &str) -> Result<EncodingKey> {
+        let sk = Ed25519KeyPair::generate().to_pem();
+        EncodingKey::from_config(&[KeyConfig {
+            kid: kid.to_string(),
+            sk: Some(sk),
+            pk: None,
+            active_from: None,
+        }])
+    }
+
+    fn token(key: &EncodingKey) -> Result<String> {
+        key.sign(&TokenClaims::new(1, 1, None), 60)
+    }
+
+    // serves the published key set, or fails while there is none
+    async fn serve_jwks(published: Published) -> Result<String> {
+        async fn jwks(State(published): State<Published>) -> Result<Json<Jwks>, StatusCode> {
+            let jwks = published.lock().unwrap().clone();
+            jwks.map(Json).ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/.well-known/jwks.json", listener.local_addr()?);
+        let app = Router::new()
+            .route("/.well-known/jwks.json", get(jwks))
+            .with_state(published);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Ok(url)
+    }
+
+    fn allow_refetch(store: &KeyStore) {
+        *store.inner.fetched_at.lock().unwrap() = None;
+    }
+
+    #[tokio::test]
+    async fn key_store_should_learn_new_keys() -> Result<()> {
+        let (k1, k2) = (signing_key("k1")?, signing_key("k2")?);
+        let published = Published::default();
+        *published.lock().unwrap() = Some(k1.decoding_key().jwks());
+        let store = KeyStore::new(serve_jwks(published.clone()).await?);
+
+        // the keys are fetched on first use
+        assert_eq!(store.verify(&token(&k1)?).await?.user_id, 1);
+
+        // an unknown key is refetched at most every MIN_REFETCH_INTERVAL
+        let mut jwks = k1.decoding_key().jwks();
+        jwks.keys.extend(k2.decoding_key().jwks().keys);
+        *published.lock().unwrap() = Some(jwks);
+        let ret = store.verify(&token(&k2)?).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        allow_refetch(&store);
+        assert_eq!(store.verify(&token(&k2)?).await?.user_id, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn key_store_should_reject_unknown_keys_when_unreachable() -> Result<()> {
+        let (k1, k2) = (signing_key("k1")?, signing_key("k2")?);
+        let published = Published::default();
+        *published.lock().unwrap() = Some(k1.decoding_key().jwks());
+        let store = KeyStore::new(serve_jwks(published.clone()).await?);
+        store.refresh().await?;
+
+        // chat_server failing doesn't make the token a gateway error, and the
+        // keys already loaded keep working
+        *published.lock().unwrap() = None;
+        allow_refetch(&store);
+        let ret = store.verify(&token(&k2)?).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        assert_eq!(store.verify(&token(&k1)?).await?.user_id, 1);
+
+        // without any keys every token is invalid
+        let store = KeyStore::new("http://127.0.0.1:1/.well-known/jwks.json");
+        let ret = store.verify(&token(&k1)?).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        Ok(())
+    }
+}
@@ -1,39 +1,74 @@
-use std::{fmt, ops::Deref, sync::Arc};
+use std::{
+    collections::VecDeque,
+    fmt,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
     Router,
     http::Method,
     middleware::from_fn_with_state,
     response::{Html, IntoResponse},
-    routing::get,
+    routing::{get, post},
 };
 use tower_http::cors::{self, CorsLayer};
 
 mod config;
+mod dnd;
 mod error;
+mod jwks;
 mod notif;
+mod presence;
 mod sse;
+mod typing;
 
-use chat_core::{DecodingKey, TokenVerify, User, verify_token};
+use chat_core::{
+    Scope, SessionId, SessionStore, TokenClaims, TokenVerify, User, UserCache, require_scope,
+    verify_token,
+};
 use dashmap::DashMap;
+use sqlx::PgPool;
+use tracing::warn;
 
 pub use config::*;
+pub use dnd::*;
 pub use error::*;
+pub use jwks::*;
 pub use notif::AppEvent;
 pub use notif::*;
+pub use presence::*;
 pub use sse::*;
+pub use typing::*;
 
 use tokio::sync::broadcast;
 
+const REVOKED_CAPACITY: usize = 64;
+
 pub type UserMap = Arc<DashMap<u64, broadcast::Sender<Arc<AppEvent>>>>;
+pub type HeldEvents = Arc<DashMap<u64, VecDeque<Arc<AppEvent>>>>;
+pub type PresenceMap = Arc<DashMap<u64, Connections>>;
+pub type TypingMap = Arc<DashMap<(u64, u64), Instant>>;
 
 #[derive(Debug, Clone)]
 pub struct AppState(Arc<AppStateInner>);
 
 pub struct AppStateInner {
     pub config: AppConfig,
-    pub dk: DecodingKey,
+    pub keys: KeyStore,
     pub users: UserMap,
+    // events held back from users in do not disturb
+    pub held: HeldEvents,
+    // open event streams and heartbeats per user
+    pub presence: PresenceMap,
+    // when users last typed, by user and chat id
+    pub typing: TypingMap,
+    pub pool: PgPool,
+    pub sessions: SessionStore,
+    pub user_cache: UserCache,
+    // ids of revoked sessions, open event streams of those sessions close
+    pub revoked: broadcast::Sender<SessionId>,
 }
 
 impl fmt::Debug for AppStateInner {
@@ -45,16 +80,49 @@ impl fmt::Debug for AppStateInner {
 }
 
 impl AppState {
-    pub async fn new(config: AppConfig) -> Self {
-        let dk = DecodingKey::load(&config.auth.pk).unwrap();
+    pub async fn try_new(config: AppConfig) -> anyhow::Result<Self> {
+        let keys = KeyStore::new(&config.auth.jwks_url);
+        // chat_server may not be up yet, keys are fetched again on demand
+        if let Err(e) = keys.refresh().await {
+            warn!("failed to fetch verification keys: {}", e);
+        }
+        keys.refresh_schedule(Duration::from_secs(config.auth.jwks_refresh_secs));
+        let pool = PgPool::connect(&config.server.db_url).await?;
+        Ok(Self::new(config, keys, pool))
+    }
+
+    fn new(config: AppConfig, keys: KeyStore, pool: PgPool) -> Self {
         let users = Arc::new(DashMap::new());
-        Self(Arc::new(AppStateInner { config, dk, users }))
+        let sessions = SessionStore::new(
+            pool.clone(),
+            Duration::from_secs(config.auth.session_cache_secs),
+        );
+        let user_cache = UserCache::new(
+            pool.clone(),
+            Duration::from_secs(config.auth.user_cache_secs),
+        );
+        let (revoked, _) = broadcast::channel(REVOKED_CAPACITY);
+        Self(Arc::new(AppStateInner {
+            config,
+            keys,
+            users,
+            held: Arc::new(DashMap::new()),
+            presence: Arc::new(DashMap::new()),
+            typing: Arc::new(DashMap::new()),
+            pool,
+            sessions,
+            user_cache,
+            revoked,
+        }))
     }
 }
 
 pub async fn get_router(config: AppConfig) -> anyhow::Result<Router> {
-    let state = AppState::new(config).await;
+    let state = AppState::try_new(config).await?;
     setup_pg_listener(state.clone()).await?;
+    dnd_flush_schedule(state.clone());
+    presence_sweep_schedule(state.clone());
+    typing_cleanup_schedule(state.clone());
 
     let cors = CorsLayer::new()
         // allow `GET` and `POST` when accessing the resource
@@ -69,7 +137,23 @@ pub async fn get_router(config: AppConfig) -> anyhow::Result<Router> {
         .allow_headers(cors::Any);
 
     let app = Router::new()
-        .route("/events", get(sse::sse_handler))
+        .route(
+            "/events",
+            get(sse::sse_handler).route_layer(from_fn_with_state(Scope::ChatsRead, require_scope)),
+        )
+        .route(
+            "/presence",
+            post(presence_handler).route_layer(from_fn_with_state(Scope::ChatsRead, require_scope)),
+        )
+        .route(
+            "/chats/{id}/typing",
+            post(typing_handler).route_layer(from_fn_with_state(Scope::ChatsWrite, require_scope)),
+        )
+        .route(
+            "/presence/heartbeat",
+            post(heartbeat_handler)
+                .route_layer(from_fn_with_state(Scope::ChatsRead, require_scope)),
+        )
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .layer(cors)
         .route("/", get(index_handler))
@@ -86,9 +170,23 @@ async fn index_handler() -> impl IntoResponse {
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> Result<User, Self::Error> {
-        let user = self.dk.verify(token).map_err(AppError::from)?;
-        Ok(user)
+    async fn verify(&self, token: &str) -> Result<TokenClaims, Self::Error> {
+        let claims = self.keys.verify(token).await?;
+        let Some(session_id) = claims.session_id else {
+            return Err(AppError::InvalidToken("token has no session".to_string()));
+        };
+        if !self.sessions.is_active(session_id).await? {
+            return Err(AppError::InvalidToken("session revoked".to_string()));
+        }
+        Ok(claims)
+    }
+
+    async fn user(&self, claims: &TokenClaims) -> Result<User, Self::Error> {
+        match self.user_cache.get(claims.user_id).await? {
+            Some(user) if user.ws_id == claims.ws_id => Ok(user),
+            Some(_) => Err(AppError::InvalidToken("workspace changed".to_string())),
+            None => Err(AppError::InvalidToken("user no longer exists".to_string())),
+        }
     }
 }
 
@@ -99,3 +197,38 @@ impl Deref for AppState {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod test_utils {
+    use std::path::Path;
+
+    use sqlx::Executor;
+    use sqlx_db_tester::TestPg;
+
+    use super::*;
+
+    impl AppState {
+        /// A state on a fresh database with chat_server's fixtures, without
+        /// the background tasks
+        pub async fn new_for_test() -> anyhow::Result<(TestPg, Self)> {
+            let config = AppConfig::load()?;
+            let db_url_prefix = config.server.db_url.rfind('/').expect("db_url invalid");
+            let db_url = config.server.db_url[..db_url_prefix].to_string();
+            let tdb = TestPg::new(db_url, Path::new("../migrations"));
+            let pool = tdb.get_pool().await;
+
+            let sql = include_str!("../../chat_server/fixtures/test.sql").split(';');
+            let mut tx = pool.begin().await?;
+            for s in sql {
+                if s.trim().is_empty() {
+                    continue;
+                }
+                tx.execute(s).await?;
+            }
+            tx.commit().await?;
+
+            let keys = KeyStore::new(&config.auth.jwks_url);
+            Ok((tdb, Self::new(config, keys, pool)))
+        }
+    }
+}
@@ -1,12 +1,12 @@
 use std::{collections::HashSet, sync::Arc};
 
-use chat_core::{Chat, Message};
+use chat_core::{Chat, Message, SessionId, UserProfile};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgListener;
 use tracing::{error, info};
 
-use crate::AppState;
+use crate::{AppState, Presence, Typing, dnd_users};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "event")]
@@ -15,6 +15,9 @@ pub enum AppEvent {
     AddToChat(Chat),
     RemoveFromChat(Chat),
     NewMessage(Message),
+    UserUpdated(UserProfile),
+    PresenceChanged(Presence),
+    Typing(Typing),
 }
 
 #[derive(Debug)]
@@ -30,6 +33,12 @@ pub struct ChatUpdatedPayload {
     pub new: Option<Chat>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionRevokedPayload {
+    pub id: SessionId,
+    pub user_id: u64,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ChatMessageCreatedPayload {
     pub message: Message,
@@ -41,6 +50,8 @@ pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
 
     listener.listen("chat_updated").await?;
     listener.listen("chat_message_created").await?;
+    listener.listen("session_revoked").await?;
+    listener.listen("user_updated").await?;
 
     let mut stream = listener.into_stream();
 
@@ -48,9 +59,29 @@ pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
         while let Some(Ok(notif)) = stream.next().await {
             info!("received notification: {:?}", notif);
 
-            let user_map: &Arc<
-                dashmap::DashMap<u64, tokio::sync::broadcast::Sender<Arc<AppEvent>>>,
-            > = &state.users;
+            if notif.channel() == "session_revoked" {
+                match serde_json::from_str::<SessionRevokedPayload>(notif.payload()) {
+                    Ok(payload) => {
+                        info!(
+                            "session {:?} of user {} revoked",
+                            payload.id, payload.user_id
+                        );
+                        state.sessions.revoke(payload.id);
+                        // no open streams is fine
+                        let _ = state.revoked.send(payload.id);
+                    }
+                    Err(e) => error!("failed to load session revocation: {:?}", e),
+                }
+                continue;
+            }
+
+            if notif.channel() == "user_updated" {
+                match serde_json::from_str::<UserProfile>(notif.payload()) {
+                    Ok(profile) => notify_user_updated(&state, profile).await,
+                    Err(e) => error!("failed to load user update: {:?}", e),
+                }
+                continue;
+            }
 
             let notif = Notification::load(notif.channel(), notif.payload());
             let Ok(notif) = notif else {
@@ -58,21 +89,71 @@ pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
                 continue;
             };
 
-            notif.user_ids.iter().for_each(|user_id| {
-                if let Some(user_channel) = user_map.get(user_id) {
-                    let tx = user_channel.value();
-                    info!("sending event to user {}: {:?}", user_id, notif.event);
-                    if let Err(e) = tx.send(notif.event.clone()) {
-                        error!("failed to send event to user {}: {:?}", user_id, e);
-                    }
-                }
-            });
+            dispatch(&state, notif).await;
         }
     });
 
     Ok(())
 }
 
+// non-urgent messages to members in do not disturb are held back, senders
+// always get their own messages
+async fn dispatch(state: &AppState, notif: Notification) {
+    let held = match notif.event.as_ref() {
+        AppEvent::NewMessage(message) if !message.urgent => {
+            let recipients: Vec<u64> = notif
+                .user_ids
+                .iter()
+                .copied()
+                .filter(|id| *id as i64 != message.sender_id && state.users.contains_key(id))
+                .collect();
+            if recipients.is_empty() {
+                HashSet::new()
+            } else {
+                dnd_users(&state.pool, &recipients)
+                    .await
+                    .unwrap_or_else(|e| {
+                        error!("failed to check do not disturb: {:?}", e);
+                        HashSet::new()
+                    })
+            }
+        }
+        _ => HashSet::new(),
+    };
+
+    for user_id in notif.user_ids {
+        if held.contains(&user_id) {
+            state.hold(user_id, notif.event.clone());
+        } else {
+            state.deliver(user_id, notif.event.clone());
+        }
+    }
+}
+
+// every connected user of the workspace hears about the change
+async fn notify_user_updated(state: &AppState, profile: UserProfile) {
+    state.user_cache.invalidate(profile.id);
+    let ws_id = profile.ws_id;
+    let event = Arc::new(AppEvent::UserUpdated(profile));
+    let connected: Vec<u64> = state.users.iter().map(|entry| *entry.key()).collect();
+    for user_id in connected {
+        match state.user_cache.get(user_id as _).await {
+            Ok(Some(user)) if user.ws_id == ws_id => {}
+            Ok(_) => continue,
+            Err(e) => {
+                error!("failed to load user {}: {:?}", user_id, e);
+                continue;
+            }
+        }
+        if let Some(tx) = state.users.get(&user_id) {
+            info!("sending event to user {}: {:?}", user_id, event);
+            if let Err(e) = tx.send(event.clone()) {
+                error!("failed to send event to user {}: {:?}", user_id, e);
+            }
+        }
+    }
+}
+
 impl Notification {
     pub fn load(r#type: &str, payload: &str) -> anyhow::Result<Self> {
         let notif = match r#type {
@@ -0,0 +1,426 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{Extension, Json, extract::State, http::StatusCode, response::IntoResponse};
+use chat_core::User;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{AppError, AppEvent, AppState};
+
+// user ids a single presence query may ask for
+const MAX_QUERY_USERS: usize = 500;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    // connected, but the client stopped sending heartbeats
+    Away,
+    Offline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Presence {
+    pub user_id: u64,
+    pub status: PresenceStatus,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PresenceQuery {
+    pub user_ids: Vec<u64>,
+}
+
+/// Open event streams of a user, dropped once the user went offline
+#[derive(Debug)]
+pub struct Connections {
+    ws_id: u64,
+    count: usize,
+    last_heartbeat: Instant,
+    // when the last stream closed
+    disconnected_at: Option<Instant>,
+    status: PresenceStatus,
+}
+
+/// Counts the user as connected while the event stream is open
+pub struct ConnectionGuard {
+    state: AppState,
+    user_id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.state.presence_disconnect(self.user_id, Instant::now());
+    }
+}
+
+impl Connections {
+    fn status(&self, now: Instant, away_after: Duration, grace: Duration) -> PresenceStatus {
+        match self.disconnected_at {
+            // offline only once the grace period passed, so reconnecting
+            // clients don't flap
+            Some(at) if now.duration_since(at) >= grace => PresenceStatus::Offline,
+            _ if now.duration_since(self.last_heartbeat) >= away_after => PresenceStatus::Away,
+            _ => PresenceStatus::Online,
+        }
+    }
+}
+
+impl AppState {
+    pub fn presence_connected(&self, user: &User) -> ConnectionGuard {
+        self.presence_connect(user, Instant::now());
+        ConnectionGuard {
+            state: self.clone(),
+            user_id: user.id as _,
+        }
+    }
+
+    fn presence_connect(&self, user: &User, now: Instant) {
+        let user_id = user.id as u64;
+        self.presence
+            .entry(user_id)
+            .and_modify(|conns| {
+                conns.ws_id = user.ws_id as _;
+                conns.count += 1;
+                conns.last_heartbeat = now;
+                conns.disconnected_at = None;
+            })
+            .or_insert(Connections {
+                ws_id: user.ws_id as _,
+                count: 1,
+                last_heartbeat: now,
+                disconnected_at: None,
+                status: PresenceStatus::Offline,
+            });
+        self.presence_update(user_id, now);
+    }
+
+    fn presence_disconnect(&self, user_id: u64, now: Instant) {
+        if let Some(mut conns) = self.presence.get_mut(&user_id) {
+            conns.count = conns.count.saturating_sub(1);
+            if conns.count == 0 {
+                conns.disconnected_at = Some(now);
+            }
+        }
+    }
+
+    /// Heartbeats only count while the user has an open event stream
+    pub fn presence_heartbeat(&self, user_id: u64, now: Instant) {
+        let Some(mut conns) = self.presence.get_mut(&user_id) else {
+            return;
+        };
+        if conns.count == 0 {
+            return;
+        }
+        conns.last_heartbeat = now;
+        drop(conns);
+        self.presence_update(user_id, now);
+    }
+
+    /// Recompute the presence of every tracked user, those whose grace
+    /// period passed go offline
+    pub fn presence_sweep(&self, now: Instant) {
+        let user_ids: Vec<u64> = self.presence.iter().map(|entry| *entry.key()).collect();
+        user_ids
+            .into_iter()
+            .for_each(|user_id| self.presence_update(user_id, now));
+    }
+
+    /// Recompute the user's presence, the workspace hears about changes
+    fn presence_update(&self, user_id: u64, now: Instant) {
+        let config = &self.config.presence;
+        let away_after = Duration::from_secs(config.away_after_secs);
+        let grace = Duration::from_secs(config.offline_grace_secs);
+        let (ws_id, status) = {
+            let Some(mut conns) = self.presence.get_mut(&user_id) else {
+                return;
+            };
+            let status = conns.status(now, away_after, grace);
+            if status == conns.status {
+                return;
+            }
+            conns.status = status;
+            (conns.ws_id, status)
+        };
+        if status == PresenceStatus::Offline {
+            self.presence
+                .remove_if(&user_id, |_, conns| conns.count == 0);
+        }
+        info!("user {} is now {:?}", user_id, status);
+
+        let event = Arc::new(AppEvent::PresenceChanged(Presence { user_id, status }));
+        let members: Vec<u64> = self
+            .presence
+            .iter()
+            .filter(|entry| entry.ws_id == ws_id && entry.count > 0)
+            .map(|entry| *entry.key())
+            .collect();
+        for member in members {
+            self.send_all(member, [event.clone()]);
+        }
+    }
+
+    /// Presence of users of the workspace, users of other workspaces are
+    /// reported offline
+    pub fn presence(&self, ws_id: u64, user_ids: &[u64]) -> Vec<Presence> {
+        user_ids
+            .iter()
+            .map(|user_id| {
+                let status = self
+                    .presence
+                    .get(user_id)
+                    .filter(|conns| conns.ws_id == ws_id)
+                    .map_or(PresenceStatus::Offline, |conns| conns.status);
+                Presence {
+                    user_id: *user_id,
+                    status,
+                }
+            })
+            .collect()
+    }
+}
+
+pub fn presence_sweep_schedule(state: AppState) {
+    let interval = Duration::from_secs(state.config.presence.sweep_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            state.presence_sweep(Instant::now());
+        }
+    });
+}
+
+/// Keeps the user online, clients send it while the user is active
+pub async fn heartbeat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> impl IntoResponse {
+    state.presence_heartbeat(user.id as _, Instant::now());
+    StatusCode::NO_CONTENT
+}
+
+pub async fn presence_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(input): Json<PresenceQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    if input.user_ids.len() > MAX_QUERY_USERS {
+        return Err(AppError::InvalidInput(format!(
+            "at most {} users per query",
+            MAX_QUERY_USERS
+        )));
+    }
+    Ok(Json(state.presence(user.ws_id as _, &input.user_ids)))
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::types::chrono::Utc;
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    fn user(id: i64, ws_id: i64) -> User {
+        User {
+            id,
+            ws_id,
+            fullname: format!("User {}", id),
+            email: format!("user{}@acme.org", id),
+            password_hash: None,
+            verified_at: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn presence(user_id: u64, status: PresenceStatus) -> Presence {
+        Presence { user_id, status }
+    }
+
+    /// Connect the user with an event stream, returns what it receives
+    fn listen(state: &AppState, user: &User, now: Instant) -> broadcast::Receiver<Arc<AppEvent>> {
+        let (tx, rx) = broadcast::channel(16);
+        state.users.insert(user.id as _, tx);
+        state.presence_connect(user, now);
+        rx
+    }
+
+    fn received(rx: &mut broadcast::Receiver<Arc<AppEvent>>) -> Vec<Presence> {
+        let mut changes = vec![];
+        while let Ok(event) = rx.try_recv() {
+            if let AppEvent::PresenceChanged(change) = event.as_ref() {
+                changes.push(change.clone());
+            }
+        }
+        changes
+    }
+
+    fn durations(state: &AppState) -> (Duration, Duration) {
+        let config = &state.config.presence;
+        (
+            Duration::from_secs(config.away_after_secs),
+            Duration::from_secs(config.offline_grace_secs),
+        )
+    }
+
+    #[test]
+    fn connections_status_should_follow_heartbeats_and_grace_period() {
+        let (away, grace) = (300 * SEC, 30 * SEC);
+        let start = Instant::now();
+        let conns = Connections {
+            ws_id: 1,
+            count: 1,
+            last_heartbeat: start,
+            disconnected_at: None,
+            status: PresenceStatus::Online,
+        };
+        let status = |conns: &Connections, at| conns.status(start + at, away, grace);
+        assert_eq!(status(&conns, away - SEC), PresenceStatus::Online);
+        assert_eq!(status(&conns, away), PresenceStatus::Away);
+
+        let conns = Connections {
+            count: 0,
+            disconnected_at: Some(start + 10 * SEC),
+            ..conns
+        };
+        assert_eq!(
+            status(&conns, 10 * SEC + grace - SEC),
+            PresenceStatus::Online
+        );
+        assert_eq!(status(&conns, 10 * SEC + grace), PresenceStatus::Offline);
+        // offline wins over away
+        assert_eq!(status(&conns, away + grace), PresenceStatus::Offline);
+    }
+
+    #[tokio::test]
+    async fn presence_should_go_offline_after_the_grace_period() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (_, grace) = durations(&state);
+        let start = Instant::now();
+        let mut rx = listen(&state, &user(2, 1), start);
+        state.presence_connect(&user(1, 1), start);
+        assert_eq!(
+            received(&mut rx),
+            [
+                presence(2, PresenceStatus::Online),
+                presence(1, PresenceStatus::Online)
+            ]
+        );
+
+        let closed = start + SEC;
+        state.presence_disconnect(1, closed);
+        state.presence_sweep(closed + grace - SEC);
+        assert!(received(&mut rx).is_empty());
+        assert_eq!(
+            state.presence(1, &[1]),
+            [presence(1, PresenceStatus::Online)]
+        );
+        // heartbeats without an event stream don't count
+        state.presence_heartbeat(1, closed + SEC);
+
+        state.presence_sweep(closed + grace);
+        assert_eq!(received(&mut rx), [presence(1, PresenceStatus::Offline)]);
+        assert!(!state.presence.contains_key(&1));
+        assert_eq!(
+            state.presence(1, &[1]),
+            [presence(1, PresenceStatus::Offline)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn presence_should_not_flap_on_reconnects() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (_, grace) = durations(&state);
+        let start = Instant::now();
+        let mut rx = listen(&state, &user(2, 1), start);
+        state.presence_connect(&user(1, 1), start);
+        // a second tab
+        state.presence_connect(&user(1, 1), start);
+        received(&mut rx);
+
+        state.presence_disconnect(1, start + SEC);
+        state.presence_sweep(start + grace + 2 * SEC);
+        assert!(received(&mut rx).is_empty());
+
+        state.presence_disconnect(1, start + 2 * SEC);
+        state.presence_connect(&user(1, 1), start + 5 * SEC);
+        state.presence_sweep(start + grace + 2 * SEC);
+        assert!(received(&mut rx).is_empty());
+        assert_eq!(
+            state.presence(1, &[1]),
+            [presence(1, PresenceStatus::Online)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn presence_should_be_away_without_heartbeats() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (away, _) = durations(&state);
+        let start = Instant::now();
+        let mut rx = listen(&state, &user(2, 1), start);
+        state.presence_connect(&user(1, 1), start);
+        state.presence_heartbeat(2, start + away - SEC);
+        received(&mut rx);
+
+        state.presence_sweep(start + away);
+        assert_eq!(received(&mut rx), [presence(1, PresenceStatus::Away)]);
+        state.presence_heartbeat(1, start + away + SEC);
+        assert_eq!(received(&mut rx), [presence(1, PresenceStatus::Online)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn presence_should_stay_in_the_workspace() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let start = Instant::now();
+        let mut same = listen(&state, &user(2, 1), start);
+        let mut other = listen(&state, &user(3, 2), start);
+        received(&mut same);
+        received(&mut other);
+
+        state.presence_connect(&user(1, 1), start);
+        assert_eq!(received(&mut same), [presence(1, PresenceStatus::Online)]);
+        assert!(received(&mut other).is_empty());
+        assert_eq!(
+            state.presence(2, &[1, 3]),
+            [
+                presence(1, PresenceStatus::Offline),
+                presence(3, PresenceStatus::Online)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn presence_handler_should_cap_queried_users() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let query = |n: u64| {
+            Json(PresenceQuery {
+                user_ids: (1..=n).collect(),
+            })
+        };
+        let ret = presence_handler(
+            State(state.clone()),
+            Extension(user(1, 1)),
+            query(MAX_QUERY_USERS as u64 + 1),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        let res = presence_handler(
+            State(state),
+            Extension(user(1, 1)),
+            query(MAX_QUERY_USERS as u64),
+        )
+        .await?
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+}
@@ -5,11 +5,11 @@ use axum::{
     extract::State,
     response::sse::{Event, Sse},
 };
-use chat_core::User;
+use chat_core::{SessionId, User};
 use futures::Stream;
 use tokio::sync::broadcast;
 use tokio_stream::{StreamExt as _, wrappers::BroadcastStream};
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{AppEvent, AppState};
 
@@ -18,6 +18,7 @@ const CAPACITY: usize = 256;
 pub async fn sse_handler(
     State(state): State<AppState>,
     Extension(user): Extension<User>,
+    Extension(session_id): Extension<SessionId>,
     // TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
     let user_id = user.id as u64;
@@ -39,6 +40,9 @@ pub async fn sse_handler(
             AppEvent::AddToChat(_) => "add_to_chat",
             AppEvent::RemoveFromChat(_) => "remove_from_chat",
             AppEvent::NewMessage(_) => "new_message",
+            AppEvent::UserUpdated(_) => "user_updated",
+            AppEvent::PresenceChanged(_) => "presence_changed",
+            AppEvent::Typing(_) => "typing",
         };
 
         let v = serde_json::to_string(&e).expect("failed to serialize event");
@@ -46,9 +50,74 @@ pub async fn sse_handler(
         Ok(Event::default().data(v).event(name))
     });
 
+    // end the stream once the session is signed out
+    let revoked = state.revoked.subscribe();
+    // the user counts as connected until the stream ends
+    let connection = state.presence_connected(&user);
+    let signed_out = async move {
+        let _connection = connection;
+        signed_out(&state, session_id, revoked).await
+    };
+    let stream = futures::StreamExt::take_until(stream, signed_out);
+
     Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(1))
             .text("keep-alive-text"),
     )
 }
+
+/// Resolves once the session is signed out
+async fn signed_out(
+    state: &AppState,
+    session_id: SessionId,
+    mut revoked: broadcast::Receiver<SessionId>,
+) {
+    loop {
+        match revoked.recv().await {
+            Ok(id) if id == session_id => break,
+            // the missed ids may have been ours, the session store was told
+            // about them before they were sent
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                match state.sessions.is_active(session_id).await {
+                    Ok(false) => break,
+                    Ok(true) => {}
+                    Err(e) => warn!("failed to check session {:?}: {}", session_id, e),
+                }
+            }
+            Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::types::Uuid;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn signed_out_should_notice_revocations_it_lagged_behind() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let session_id = SessionId(Uuid::now_v7());
+        let mut done = Box::pin(signed_out(&state, session_id, state.revoked.subscribe()));
+
+        // other sessions don't end the stream
+        state.revoked.send(SessionId(Uuid::now_v7()))?;
+        let ret = timeout(Duration::from_millis(50), &mut done).await;
+        assert!(ret.is_err());
+
+        // our id is pushed out of the channel by many others
+        state.sessions.revoke(session_id);
+        state.revoked.send(session_id)?;
+        for _ in 0..1000 {
+            state.revoked.send(SessionId(Uuid::now_v7()))?;
+        }
+        timeout(Duration::from_secs(1), done).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Extension,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chat_core::{TokenClaims, User};
+use dashmap::Entry;
+use serde::{Deserialize, Serialize};
+
+use crate::{AppError, AppEvent, AppState};
+
+/// A member is typing in the chat, clients show it until `ttl_secs` passed
+/// without another one
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Typing {
+    pub chat_id: u64,
+    pub user_id: u64,
+    pub ttl_secs: u64,
+}
+
+impl AppState {
+    /// Tell the other members of the chat the user is typing. Signals within
+    /// the throttle interval of the last one are dropped before the database
+    /// is asked, nothing is stored.
+    pub async fn typing(
+        &self,
+        user_id: u64,
+        chat_id: u64,
+        claims: &TokenClaims,
+        now: Instant,
+    ) -> Result<(), AppError> {
+        if !claims.allows_chat(chat_id as _) {
+            return Err(AppError::PermissionDenied(
+                "The token is not allowed for this chat".to_string(),
+            ));
+        }
+        let config = &self.config.typing;
+        let throttle = Duration::from_secs(config.throttle_secs);
+        match self.typing.entry((user_id, chat_id)) {
+            Entry::Occupied(last) if now.duration_since(*last.get()) < throttle => return Ok(()),
+            Entry::Occupied(mut last) => {
+                last.insert(now);
+            }
+            Entry::Vacant(last) => {
+                last.insert(now);
+            }
+        }
+
+        // checked like chat_server's `verify_chat`
+        let members = match self.typing_members(user_id, chat_id).await {
+            Ok(members) => members,
+            Err(e) => {
+                // only members are throttled
+                self.typing
+                    .remove_if(&(user_id, chat_id), |_, last| *last == now);
+                return Err(e);
+            }
+        };
+
+        let event = Arc::new(AppEvent::Typing(Typing {
+            chat_id,
+            user_id,
+            ttl_secs: config.ttl_secs,
+        }));
+        members
+            .into_iter()
+            .map(|member| member as u64)
+            .filter(|member| *member != user_id)
+            .for_each(|member| self.send_all(member, [event.clone()]));
+        Ok(())
+    }
+
+    async fn typing_members(&self, user_id: u64, chat_id: u64) -> Result<Vec<i64>, AppError> {
+        let members: Option<(Vec<i64>,)> =
+            sqlx::query_as("SELECT members FROM chats WHERE id = $1")
+                .bind(chat_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+        let Some((members,)) = members else {
+            return Err(AppError::NotFound(format!(
+                "Chat not found with id: {}",
+                chat_id
+            )));
+        };
+        if !members.contains(&(user_id as i64)) {
+            return Err(AppError::PermissionDenied(
+                "You are not a member of this chat".to_string(),
+            ));
+        }
+        Ok(members)
+    }
+}
+
+// forget when users last typed once it no longer throttles them
+pub fn typing_cleanup_schedule(state: AppState) {
+    let throttle = Duration::from_secs(state.config.typing.throttle_secs);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(throttle.max(Duration::from_secs(1)) * 10);
+        loop {
+            ticker.tick().await;
+            state.typing.retain(|_, last| last.elapsed() < throttle);
+        }
+    });
+}
+
+pub async fn typing_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Extension(claims): Extension<TokenClaims>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .typing(user.id as _, chat_id, &claims, Instant::now())
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    fn listen(state: &AppState, user_id: u64) -> broadcast::Receiver<Arc<AppEvent>> {
+        let (tx, rx) = broadcast::channel(16);
+        state.users.insert(user_id, tx);
+        rx
+    }
+
+    fn received(rx: &mut broadcast::Receiver<Arc<AppEvent>>) -> Vec<Typing> {
+        let mut typing = vec![];
+        while let Ok(event) = rx.try_recv() {
+            if let AppEvent::Typing(t) = event.as_ref() {
+                typing.push(t.clone());
+            }
+        }
+        typing
+    }
+
+    #[tokio::test]
+    async fn typing_should_reach_the_other_members() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (mut sender, mut member) = (listen(&state, 1), listen(&state, 2));
+        let claims = TokenClaims::new(1, 1, None);
+        state.typing(1, 1, &claims, Instant::now()).await?;
+        let expected = Typing {
+            chat_id: 1,
+            user_id: 1,
+            ttl_secs: state.config.typing.ttl_secs,
+        };
+        assert_eq!(received(&mut member), [expected]);
+        assert!(received(&mut sender).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn typing_should_require_membership_and_an_allowed_chat() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut member = listen(&state, 2);
+        let now = Instant::now();
+
+        // user 3 is in another workspace
+        let ret = state.typing(3, 1, &TokenClaims::new(3, 2, None), now).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state
+            .typing(1, 999, &TokenClaims::new(1, 1, None), now)
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        // rejected signals aren't throttled
+        assert!(state.typing.is_empty());
+
+        let mut claims = TokenClaims::new(1, 1, None);
+        claims.chat_ids = Some(vec![3]);
+        let ret = state.typing(1, 1, &claims, now).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        state.typing(1, 3, &claims, now).await?;
+        assert_eq!(received(&mut member).len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn typing_should_be_throttled() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut member = listen(&state, 2);
+        let claims = TokenClaims::new(1, 1, None);
+        let throttle = Duration::from_secs(state.config.typing.throttle_secs);
+        let start = Instant::now();
+
+        state.typing(1, 1, &claims, start).await?;
+        state.typing(1, 1, &claims, start + throttle - SEC).await?;
+        assert_eq!(received(&mut member).len(), 1);
+        // other chats have their own interval
+        state.typing(1, 3, &claims, start + SEC).await?;
+        assert_eq!(received(&mut member).len(), 1);
+
+        state.typing(1, 1, &claims, start + throttle).await?;
+        assert_eq!(received(&mut member).len(), 1);
+        Ok(())
+    }
+}